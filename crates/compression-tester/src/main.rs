@@ -11,7 +11,7 @@ use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use world::Position;
 use world::ServerMessage;
-use world::{ClientMessage, Rect, ServerMessageBundle};
+use world::{ClientMessage, Rect, ServerMessageBundle, PROTOCOL_VERSION, SUPPORTED_FEATURES};
 
 #[tokio::main]
 async fn main() {
@@ -60,6 +60,11 @@ impl Client {
     async fn sender(client: Arc<Mutex<Client>>, mut write: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>) {
         {
             let mut client = client.lock().await;
+            let hello = ClientMessage::Hello {
+                version: PROTOCOL_VERSION,
+                features: SUPPORTED_FEATURES.iter().map(|feature| feature.to_string()).collect(),
+            };
+            client.send_message(hello, &mut write).await;
             client.send_message(ClientMessage::Connected, &mut write).await
         }
         let mut client = client.lock().await;
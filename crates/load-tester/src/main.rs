@@ -1,3 +1,4 @@
+use clap::Parser;
 use futures_util::future::join_all;
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
@@ -8,24 +9,81 @@ use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 use tokio::time::sleep;
 use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
-use world::{ClientMessage, ServerMessageBundle};
+use tokio_tungstenite::{connect_async_with_config, MaybeTlsStream, WebSocketStream};
+use world::{ClientMessage, ServerMessageBundle, PROTOCOL_VERSION, SUPPORTED_FEATURES};
 use world::Event;
 use world::ServerMessage;
 use world::Position;
 
-static THREADS: usize = 200;
-static TEST_DURATION: Duration = Duration::from_secs(600);
-static TIME_BETWEEN_MESSAGES: Duration = Duration::from_millis(1000);
+/// Connects lots of players to a server, has them click around, and reports how quickly the
+/// server answered.
+#[derive(Parser)]
+struct Options {
+    /// The server's WebSocket URL
+    #[arg(short, long, default_value = "ws://infinitesweeper.online/ws")]
+    url: String,
+    /// How many players to connect
+    #[arg(short, long, default_value_t = 200)]
+    clients: u32,
+    /// How long each player waits between clicks
+    #[arg(short, long, default_value_t = 1000)]
+    interval_ms: u64,
+    /// How long to run the test for
+    #[arg(short, long, default_value_t = 600)]
+    duration_secs: u64,
+    /// Players click anywhere up to this many tiles away from the origin
+    #[arg(short, long, default_value_t = 5000)]
+    spread: u32,
+    /// Print every message sent and received
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+impl Options {
+    fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration_secs)
+    }
+
+    fn random_position(&self) -> Position {
+        let random = || (thread_rng().next_u32() % (self.spread * 2 + 1)) as i32 - self.spread as i32;
+        Position(random(), random())
+    }
+}
 
 #[tokio::main]
 async fn main() {
+    let options = Arc::new(Options::parse());
     let mut handles = vec![];
-    for _ in 0..THREADS {
-        handles.push(tokio::spawn(Client::spawn()));
-        sleep(TIME_BETWEEN_MESSAGES/THREADS as u32).await
+    for _ in 0..options.clients {
+        handles.push(tokio::spawn(Client::spawn(options.clone())));
+        sleep(options.interval() / options.clients).await
+    }
+    let results = join_all(handles).await;
+
+    let mut sent = 0;
+    let mut throttled = 0;
+    let mut latencies = vec![];
+    for result in results.into_iter().flatten() {
+        sent += result.sent;
+        throttled += result.throttled;
+        latencies.extend(result.latencies);
+    }
+    latencies.sort();
+    let percentile = |p: usize| latencies.get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1)));
+    println!("Clicks sent:      {}", sent);
+    println!("Clicks answered:  {}", latencies.len());
+    println!("Times throttled:  {}", throttled);
+    println!("Answers/second:   {:.1}", latencies.len() as f64 / options.duration().as_secs_f64());
+    if !latencies.is_empty() {
+        println!("Latency p50:      {:?}", percentile(50).unwrap());
+        println!("Latency p90:      {:?}", percentile(90).unwrap());
+        println!("Latency p99:      {:?}", percentile(99).unwrap());
+        println!("Latency max:      {:?}", latencies.last().unwrap());
     }
-    join_all(handles).await;
 }
 
 struct Request {
@@ -46,11 +104,21 @@ struct SentMessage {
 struct Client {
     sent_messages: Vec<SentMessage>,
     player_id: Option<String>,
+    throttled: usize,
+    verbose: bool,
+}
+
+/// What one player saw during the test
+struct ClientResult {
+    sent: usize,
+    throttled: usize,
+    latencies: Vec<Duration>,
 }
 
 impl Client {
-    pub async fn spawn() {
-        let (stream, _response) = connect_async("ws://infinitesweeper.online/ws")
+    pub async fn spawn(options: Arc<Options>) -> ClientResult {
+        // Without disabling Nagle's algorithm, small messages can sit in the buffer for 40ms
+        let (stream, _response) = connect_async_with_config(options.url.as_str(), None, true)
             .await.expect("couldn't connect");
 
         let (write, read) = stream.split();
@@ -58,65 +126,86 @@ impl Client {
         let client = Arc::new(Mutex::new(Client {
             sent_messages: vec![],
             player_id: None,
+            throttled: 0,
+            verbose: options.verbose,
         }));
 
-        let sender = Client::sender(client.clone(), write);
+        let sender = Client::sender(client.clone(), write, options.clone());
         let receiver = Client::receiver(client.clone(), read);
         let sender = tokio::spawn(sender);
         let receiver = tokio::spawn(receiver);
 
-        tokio::time::sleep(TEST_DURATION).await;
+        tokio::time::sleep(options.duration()).await;
         sender.abort();
         receiver.abort();
 
         let client = client.lock().await;
+        let mut latencies = vec![];
         for sent in &client.sent_messages {
-            match &sent.response {
-                None => {
-                    // println!("{:?}: no matched _response", sent.request.message);
-                }
-                Some(response) => {
+            if let Some(response) = &sent.response {
+                if client.verbose {
                     println!("{:?}: {:?}", response.message, response.received_at - sent.request.sent_at)
                 }
+                latencies.push(response.received_at - sent.request.sent_at);
             }
         }
+        ClientResult {
+            sent: client.sent_messages.len(),
+            throttled: client.throttled,
+            latencies,
+        }
     }
 
-    async fn sender(client: Arc<Mutex<Client>>, mut write: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>) {
+    async fn sender(client: Arc<Mutex<Client>>, mut write: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>, options: Arc<Options>) {
         {
             let mut client = client.lock().await;
+            let hello = ClientMessage::Hello {
+                version: PROTOCOL_VERSION,
+                features: SUPPORTED_FEATURES.iter().map(|feature| feature.to_string()).collect(),
+            };
+            client.send_message(hello, &mut write).await;
             client.send_message(ClientMessage::Connected, &mut write).await
         }
         loop {
-            let mut client = client.lock().await;
-            let message = ClientMessage::Click(Position(thread_rng().next_u32() as i32%5000, thread_rng().next_u32() as i32%5000));
-            client.send_message(message, &mut write).await;
-            tokio::time::sleep(TIME_BETWEEN_MESSAGES).await;
+            {
+                let mut client = client.lock().await;
+                let message = ClientMessage::Click(options.random_position());
+                client.send_message(message, &mut write).await;
+            }
+            tokio::time::sleep(options.interval()).await;
         }
     }
 
     async fn send_message(&mut self, message: ClientMessage, write: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>) {
-        println!("sending {:?}", message);
-        self.sent_messages.push(SentMessage {
-            request: Request {
-                message,
-                sent_at: Instant::now()
-            },
-            response: None
-        });
-        let message = &self.sent_messages.last().unwrap().request.message;
-        write.send(Message::Text(Utf8Bytes::from(serde_json::to_string(message).expect("couldn't serialize message")))).await.expect("TODO: panic message");
+        if self.verbose {
+            println!("sending {:?}", message);
+        }
+        let text = serde_json::to_string(&message).expect("couldn't serialize message");
+        if !matches!(message, ClientMessage::Connected | ClientMessage::Hello { .. }) {
+            self.sent_messages.push(SentMessage {
+                request: Request {
+                    message,
+                    sent_at: Instant::now()
+                },
+                response: None
+            });
+        }
+        write.send(Message::Text(Utf8Bytes::from(text))).await.expect("couldn't send message");
     }
 
     async fn receiver(client: Arc<Mutex<Client>>, read: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>) {
         read.for_each(|message| async {
             match message {
                 Ok(data) => {
-                    println!("got data {:?}", data);
-                    if let Ok(ServerMessageBundle(messages)) = ServerMessageBundle::from_compressed(&*data.into_data()) {
+                    let mut client = client.lock().await;
+                    if client.verbose {
+                        println!("got data {:?}", data);
+                    }
+                    if let Ok(ServerMessageBundle(messages)) = ServerMessageBundle::from_compressed(&data.into_data()) {
                         for message in messages {
-                            println!("got message {:?}", message);
-                            let mut client = client.lock().await;
+                            if client.verbose {
+                                println!("got message {:?}", message);
+                            }
                             client.match_server_message(message);
                         }
                     }
@@ -128,8 +217,18 @@ impl Client {
         }).await
     }
 
+    fn respond_to(&mut self, request: &ClientMessage, message: &ServerMessage) {
+        for sent in &mut self.sent_messages {
+            if sent.response.is_none() && &sent.request.message == request {
+                sent.response = Some(Response {
+                    message: message.clone(),
+                    received_at: Instant::now(),
+                })
+            }
+        }
+    }
+
     fn match_server_message(&mut self, server_message: ServerMessage) {
-        let sm_clone = server_message.clone();
         match &server_message {
             ServerMessage::Event(event) => {
                 let player = event.player();
@@ -141,24 +240,28 @@ impl Client {
                         Event::Flag { at, .. } |
                         Event::Unflag { at, .. } => ClientMessage::Flag(*at),
                     };
-                    for sent in &mut self.sent_messages {
-                        if sent.response.is_none() && sent.request.message == corresponding_client_message {
-                            sent.response = Some(Response {
-                                message: sm_clone.clone(),
-                                received_at: Instant::now(),
-                            })
-                        }
-                    }
+                    self.respond_to(&corresponding_client_message, &server_message);
                 }
             }
             ServerMessage::Chunk(_) => {}
-            ServerMessage::Rect(_) => {}
-            ServerMessage::Player(_) => {}
-            ServerMessage::Welcome(player) => {
+            ServerMessage::Rect(rect) => {
+                // Clicks that don't change anything get the clicked tile sent back
+                self.respond_to(&ClientMessage::Click(rect.top_left), &server_message);
+            }
+            ServerMessage::Player(_) | ServerMessage::Cursors(_) | ServerMessage::Chat(_) => {}
+            ServerMessage::Ping { .. } | ServerMessage::Marker(_) | ServerMessage::MarkerRemoved(_) => {}
+            ServerMessage::Welcome { player, .. } => {
                 self.player_id = Some(player.player_id.clone());
             }
             ServerMessage::Disconnected(_) => {}
+            ServerMessage::Restarting(_) => {}
+            ServerMessage::Throttled(_) => {
+                self.throttled += 1;
+            }
+            ServerMessage::Error(_) => {}
+            ServerMessage::Stale => {}
+            ServerMessage::ResumeKey(_) | ServerMessage::Hello { .. } => {}
             ServerMessage::Connected => {}
         }
     }
-}
\ No newline at end of file
+}
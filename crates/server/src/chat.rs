@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use world::{ChatChannel, ChatMessage};
+use crate::json_file::{JsonFile, Unsaved};
+
+/// How many of the latest global chat messages are kept to send to players when they join
+pub const CHAT_HISTORY: usize = 50;
+
+/// The latest global chat messages, kept in a JSON file in the data directory so that players
+/// joining after a restart can still see what was being said.
+pub struct ChatHistory {
+    pub file: JsonFile<ChatMessage>,
+    /// Oldest first
+    recent: VecDeque<ChatMessage>,
+}
+
+impl ChatHistory {
+    pub fn new(path: PathBuf) -> Self {
+        Self { file: JsonFile::new(path), recent: VecDeque::new() }
+    }
+
+    /// Reads the messages from the file, or starts with none if there isn't one yet
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let mut history = Self::new(path);
+        history.recent = history.file.load()?.into();
+        history.recent.drain(..history.recent.len().saturating_sub(CHAT_HISTORY));
+        Ok(history)
+    }
+
+    pub fn len(&self) -> usize {
+        self.recent.len()
+    }
+
+    /// Keeps the message if it's global, forgetting the oldest one if there are too many
+    pub fn add(&mut self, message: ChatMessage) {
+        if message.channel != ChatChannel::Global {
+            return;
+        }
+        if self.recent.len() == CHAT_HISTORY {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(message);
+        self.file.mark_changed();
+    }
+
+    /// The latest global chat messages, oldest first
+    pub fn recent(&self) -> impl Iterator<Item = &ChatMessage> {
+        self.recent.iter()
+    }
+
+    pub fn unsaved(&self) -> Option<Unsaved<ChatMessage>> {
+        self.file.unsaved(|| self.recent.iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use world::{ChatChannel, ChatMessage, Position};
+    use crate::chat::{ChatHistory, CHAT_HISTORY};
+    use crate::testing::TempDir;
+
+    fn chat(channel: ChatChannel, text: &str) -> ChatMessage {
+        ChatMessage {
+            channel,
+            player_id: "talker".to_string(),
+            name: "Talker".to_string(),
+            position: Position(10, 10),
+            time: 0,
+            text: text.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn the_latest_global_messages_survive_a_restart() {
+        let dir = TempDir::new("chat");
+        let path = dir.join("chat.json");
+
+        let mut history = ChatHistory::load(path.clone()).unwrap();
+        history.add(chat(ChatChannel::Nearby, "Over here"));
+        assert!(history.unsaved().is_none(), "only global messages are kept");
+        for i in 0..CHAT_HISTORY + 5 {
+            history.add(chat(ChatChannel::Global, &i.to_string()));
+        }
+        history.unsaved().unwrap().write().await.unwrap();
+
+        let history = ChatHistory::load(path.clone()).unwrap();
+        let recent: Vec<_> = history.recent().map(|message| message.text.as_str()).collect();
+        assert_eq!(recent.len(), CHAT_HISTORY);
+        assert_eq!((recent[0], recent[CHAT_HISTORY - 1]), ("5", "54"));
+    }
+}
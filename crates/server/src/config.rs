@@ -0,0 +1,291 @@
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use world::World;
+use crate::eventlog::Durability;
+use crate::rate_limit::Rate;
+
+/// The config file that is used if none is given on the command line.
+const DEFAULT_CONFIG_FILE: &str = "sweeper.toml";
+
+/// Everything that can be set in the TOML config file. Anything left out of the file gets its
+/// default value, and anything given on the command line or in an environment variable takes
+/// priority over the file.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub server: NetworkConfig,
+    pub data: DataConfig,
+    pub world: WorldConfig,
+    pub limits: LimitsConfig,
+    pub players: PlayersConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub port: u16,
+    pub bind: IpAddr,
+    /// Serve the web client from this directory instead of the files built into the binary
+    pub static_dir: Option<PathBuf>,
+    /// How long clients are told to wait before reconnecting when the server shuts down
+    pub reconnect_delay_secs: u16,
+    /// How many messages can be waiting to be sent to a client before it's considered to have
+    /// fallen behind, and gets sent its whole viewport again
+    pub client_queue_capacity: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            port: 80,
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            static_dir: None,
+            reconnect_delay_secs: 5,
+            client_queue_capacity: 1024,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DataConfig {
+    /// The directory that all the server's files are kept in
+    pub dir: Option<PathBuf>,
+    /// Path to the event log, relative to the data directory
+    pub event_log: Option<PathBuf>,
+    /// Path to the snapshot directory, relative to the data directory
+    pub snapshots: Option<PathBuf>,
+    /// Path to the file that players' identities are kept in, relative to the data directory
+    pub players: Option<PathBuf>,
+    /// Path to the file that the markers on the map are kept in, relative to the data directory
+    pub markers: Option<PathBuf>,
+    /// Path to the file that the latest chat messages are kept in, relative to the data directory
+    pub chat: Option<PathBuf>,
+    /// When the event log gets synced to disk
+    pub durability: Durability,
+}
+
+impl Default for DataConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            event_log: None,
+            snapshots: None,
+            players: None,
+            markers: None,
+            chat: None,
+            durability: Durability::IntervalMs(1000),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorldConfig {
+    pub seed: u64,
+    pub mines_per_chunk: u8,
+}
+
+impl Default for WorldConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            mines_per_chunk: 40,
+        }
+    }
+}
+
+impl WorldConfig {
+    /// With fewer mines than this, openings get so big that a single click could try to reveal
+    /// most of the infinite world.
+    const MIN_MINES_PER_CHUNK: u8 = 20;
+    /// [world::ChunkMines::random] picks from 255 of the 256 tiles in a chunk.
+    const MAX_MINES_PER_CHUNK: u8 = 255;
+
+    pub fn new_world(&self) -> World {
+        World::with_parameters(self.seed, self.mines_per_chunk)
+    }
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlayersConfig {
+    /// A file of words that aren't allowed in names, one per line
+    pub blocked_words: Option<PathBuf>,
+}
+
+/// How quickly clients can send messages. Anything over the limit is ignored, and the client
+/// is told to slow down.
+#[derive(Deserialize, Debug, Copy, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Clicks, flags, double clicks and profile changes from each connection
+    pub actions: Rate,
+    /// Queries and viewport changes from each connection
+    pub queries: Rate,
+    /// Actions from all the connections from one IP address together
+    pub ip_actions: Rate,
+    /// Queries from all the connections from one IP address together
+    pub ip_queries: Rate,
+    /// Cursor movements from each connection. Ones over the limit are dropped without telling
+    /// the client.
+    pub cursors: Rate,
+    /// Cursor movements from all the connections from one IP address together
+    pub ip_cursors: Rate,
+    /// Chat messages, pings and markers from each connection
+    pub chat: Rate,
+    /// Chat messages, pings and markers from all the connections from one IP address together
+    pub ip_chat: Rate,
+    /// Each message that gets ignored uses up one of these, and the connection is closed once
+    /// they've run out
+    pub throttled: Rate,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            actions: Rate { per_sec: 20.0, burst: 40.0 },
+            queries: Rate { per_sec: 30.0, burst: 60.0 },
+            ip_actions: Rate { per_sec: 100.0, burst: 200.0 },
+            ip_queries: Rate { per_sec: 150.0, burst: 300.0 },
+            cursors: Rate { per_sec: 20.0, burst: 20.0 },
+            ip_cursors: Rate { per_sec: 100.0, burst: 100.0 },
+            chat: Rate { per_sec: 1.0, burst: 5.0 },
+            ip_chat: Rate { per_sec: 5.0, burst: 20.0 },
+            throttled: Rate { per_sec: 2.0, burst: 100.0 },
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the config file at the path, or `sweeper.toml` if no path is given. It's only an
+    /// error for the file to be missing if the path was given explicitly.
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let path = match path {
+            Some(path) => path,
+            None => {
+                let default = Path::new(DEFAULT_CONFIG_FILE);
+                if !default.exists() {
+                    return Ok(Self::default());
+                }
+                default
+            }
+        };
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Unable to read config file {:?}: {}", path, err))?;
+        Self::parse(&text)
+            .map_err(|err| format!("Invalid config file {:?}: {}", path, err))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|err| err.to_string())
+    }
+
+    /// Checks the settings that the types alone can't. This should be called after the command
+    /// line overrides have been applied.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.server.client_queue_capacity == 0 {
+            return Err("server.client_queue_capacity must be at least 1".to_string());
+        }
+        if let Some(static_dir) = &self.server.static_dir {
+            if !static_dir.is_dir() {
+                return Err(format!("server.static_dir {:?} is not a directory", static_dir));
+            }
+        }
+        if let Some(blocked_words) = &self.players.blocked_words {
+            if !blocked_words.is_file() {
+                return Err(format!("players.blocked_words {:?} is not a file", blocked_words));
+            }
+        }
+        if let Durability::IntervalMs(ms) = self.data.durability {
+            if ms == 0 || ms > 60_000 {
+                return Err("data.durability interval_ms must be between 1 and 60000".to_string());
+            }
+        }
+        let limits = &self.limits;
+        limits.actions.validate("limits.actions")?;
+        limits.queries.validate("limits.queries")?;
+        limits.ip_actions.validate("limits.ip_actions")?;
+        limits.ip_queries.validate("limits.ip_queries")?;
+        limits.cursors.validate("limits.cursors")?;
+        limits.ip_cursors.validate("limits.ip_cursors")?;
+        limits.chat.validate("limits.chat")?;
+        limits.ip_chat.validate("limits.ip_chat")?;
+        limits.throttled.validate("limits.throttled")?;
+        let mines = self.world.mines_per_chunk;
+        if !(WorldConfig::MIN_MINES_PER_CHUNK..=WorldConfig::MAX_MINES_PER_CHUNK).contains(&mines) {
+            return Err(format!(
+                "world.mines_per_chunk must be between {} and {}",
+                WorldConfig::MIN_MINES_PER_CHUNK, WorldConfig::MAX_MINES_PER_CHUNK
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{LimitsConfig, ServerConfig};
+    use crate::eventlog::Durability;
+    use crate::rate_limit::Rate;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn empty_file_gives_defaults() {
+        let config = ServerConfig::parse("").unwrap();
+        assert_eq!(config.server.port, 80);
+        assert_eq!(config.server.bind, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(config.data.durability, Durability::IntervalMs(1000));
+        assert_eq!(config.world.mines_per_chunk, 40);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn partial_sections_keep_other_defaults() {
+        let config = ServerConfig::parse("[server]\nport = 8080\n[world]\nseed = 7").unwrap();
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.world.seed, 7);
+        assert_eq!(config.world.mines_per_chunk, 40);
+
+        let config = ServerConfig::parse("[limits]\nactions = { per_sec = 5, burst = 10 }").unwrap();
+        assert_eq!(config.limits.actions, Rate { per_sec: 5.0, burst: 10.0 });
+        assert_eq!(config.limits.queries, LimitsConfig::default().queries);
+    }
+
+    #[test]
+    fn durability_policies() {
+        let config = ServerConfig::parse("[data]\ndurability = \"always\"").unwrap();
+        assert_eq!(config.data.durability, Durability::Always);
+        let config = ServerConfig::parse("[data]\ndurability = \"os\"").unwrap();
+        assert_eq!(config.data.durability, Durability::Os);
+        let config = ServerConfig::parse("[data]\ndurability = { interval_ms = 250 }").unwrap();
+        assert_eq!(config.data.durability, Durability::IntervalMs(250));
+        let config = ServerConfig::parse("[data]\ndurability = { interval_ms = 0 }").unwrap();
+        assert!(config.validate().is_err());
+        assert!(ServerConfig::parse("[data]\ndurability = \"sometimes\"").is_err());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(ServerConfig::parse("[server]\nprot = 8080").is_err());
+        assert!(ServerConfig::parse("[sever]\nport = 8080").is_err());
+    }
+
+    #[test]
+    fn bad_values_fail_validation() {
+        let config = ServerConfig::parse("[world]\nmines_per_chunk = 2").unwrap();
+        assert!(config.validate().is_err());
+        let config = ServerConfig::parse("[server]\nclient_queue_capacity = 0").unwrap();
+        assert!(config.validate().is_err());
+        let config = ServerConfig::parse("[limits]\nactions = { per_sec = 0, burst = 10 }").unwrap();
+        assert!(config.validate().is_err());
+        assert!(ServerConfig::parse("[server]\nbind = \"not an address\"").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use crate::config::DataConfig;
+
+/// Where the server keeps the files that make up the world.
+#[derive(Debug, Clone)]
+pub struct DataPaths {
+    pub dir: PathBuf,
+    pub event_log: PathBuf,
+    pub snapshots: PathBuf,
+    pub players: PathBuf,
+    pub markers: PathBuf,
+    pub chat: PathBuf,
+}
+
+/// The paths given on the command line or in environment variables, which take priority over
+/// the config file
+#[derive(Debug, Default)]
+pub struct DataArgs {
+    pub dir: Option<PathBuf>,
+    pub event_log: Option<PathBuf>,
+    pub snapshots: Option<PathBuf>,
+    pub players: Option<PathBuf>,
+    pub markers: Option<PathBuf>,
+    pub chat: Option<PathBuf>,
+}
+
+impl DataPaths {
+    /// Works out the paths from the command line (which includes environment variables) and the
+    /// config file, in that order of priority. Relative paths for the files and the snapshot
+    /// directory are relative to the data directory.
+    pub fn resolve(args: DataArgs, config: &DataConfig) -> Self {
+        let dir = args.dir.or(config.dir.clone())
+            .unwrap_or_else(|| PathBuf::from("."));
+        let event_log = args.event_log.or(config.event_log.clone())
+            .unwrap_or_else(|| PathBuf::from("eventlog"));
+        let snapshots = args.snapshots.or(config.snapshots.clone())
+            .unwrap_or_else(|| PathBuf::from("snapshots"));
+        let players = args.players.or(config.players.clone())
+            .unwrap_or_else(|| PathBuf::from("players.json"));
+        let markers = args.markers.or(config.markers.clone())
+            .unwrap_or_else(|| PathBuf::from("markers.json"));
+        let chat = args.chat.or(config.chat.clone())
+            .unwrap_or_else(|| PathBuf::from("chat.json"));
+        Self {
+            event_log: dir.join(event_log),
+            snapshots: dir.join(snapshots),
+            players: dir.join(players),
+            markers: dir.join(markers),
+            chat: dir.join(chat),
+            dir,
+        }
+    }
+
+    fn lock_file(&self) -> PathBuf {
+        self.dir.join("lock")
+    }
+
+    /// Creates the directory layout if it's not there yet, then takes the lock on the data
+    /// directory so that no other server can use it while we're running.
+    pub fn open(self) -> io::Result<DataDir> {
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::create_dir_all(&self.snapshots)?;
+        for file in [&self.event_log, &self.players, &self.markers, &self.chat] {
+            if let Some(parent) = file.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+
+        let mut lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.lock_file())?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let holder = std::fs::read_to_string(self.lock_file()).unwrap_or_default();
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("{:?} is being used by another server (pid {})", self.dir, holder.trim())
+                ));
+            }
+            Err(TryLockError::Error(err)) => return Err(err),
+        }
+        lock.set_len(0)?;
+        writeln!(lock, "{}", std::process::id())?;
+
+        Ok(DataDir { paths: self, _lock: lock })
+    }
+}
+
+/// The data directory, locked for as long as this is alive.
+pub struct DataDir {
+    pub paths: DataPaths,
+    _lock: File,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::PathBuf;
+    use crate::config::ServerConfig;
+    use crate::data_dir::{DataArgs, DataPaths};
+    use crate::testing::TempDir;
+
+    #[test]
+    fn command_line_beats_config_file_beats_defaults() {
+        let config = ServerConfig::parse("[data]\ndir = \"/srv/sweeper\"\nevent_log = \"log\"\nplayers = \"people.json\"").unwrap();
+        let paths = DataPaths::resolve(DataArgs::default(), &config.data);
+        assert_eq!(paths.dir, PathBuf::from("/srv/sweeper"));
+        assert_eq!(paths.event_log, PathBuf::from("/srv/sweeper/log"));
+        assert_eq!(paths.players, PathBuf::from("/srv/sweeper/people.json"));
+        assert_eq!(paths.snapshots, PathBuf::from("/srv/sweeper/snapshots"));
+        assert_eq!(paths.markers, PathBuf::from("/srv/sweeper/markers.json"));
+        assert_eq!(paths.chat, PathBuf::from("/srv/sweeper/chat.json"));
+
+        let args = DataArgs {
+            dir: Some(PathBuf::from("data")),
+            snapshots: Some(PathBuf::from("/backups")),
+            players: Some(PathBuf::from("players/all.json")),
+            ..Default::default()
+        };
+        let paths = DataPaths::resolve(args, &config.data);
+        assert_eq!(paths.dir, PathBuf::from("data"));
+        assert_eq!(paths.event_log, PathBuf::from("data/log"));
+        // Absolute paths aren't put in the data directory
+        assert_eq!(paths.snapshots, PathBuf::from("/backups"));
+        assert_eq!(paths.players, PathBuf::from("data/players/all.json"));
+
+        let paths = DataPaths::resolve(DataArgs::default(), &ServerConfig::parse("").unwrap().data);
+        assert_eq!(paths.dir, PathBuf::from("."));
+        assert_eq!(paths.event_log, PathBuf::from("./eventlog"));
+    }
+
+    #[test]
+    fn only_one_server_can_use_a_data_directory() {
+        let dir = TempDir::new("data-dir");
+        let args = || DataArgs { dir: Some(dir.join("data")), ..Default::default() };
+        let config = ServerConfig::parse("").unwrap();
+
+        let first = DataPaths::resolve(args(), &config.data).open().unwrap();
+        assert!(first.paths.snapshots.is_dir());
+        let second = DataPaths::resolve(args(), &config.data).open();
+        let err = second.err().expect("the directory is locked");
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(err.to_string().contains(&std::process::id().to_string()), "{err}");
+
+        drop(first);
+        assert!(DataPaths::resolve(args(), &config.data).open().is_ok(), "the lock goes with the server");
+    }
+}
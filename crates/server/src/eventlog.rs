@@ -4,14 +4,23 @@ use serde_with::formats::Unpadded;
 use serde_with::serde_as;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
-use futures_util::{Stream, StreamExt};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use futures_util::{stream, Stream, StreamExt};
 use log::*;
 use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncWriteExt, BufWriter};
+use std::io::Write;
 use tokio::io;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::{Interval, MissedTickBehavior};
 use tokio_util::codec::{FramedRead, LinesCodec};
-use world::{ChunkMines, ChunkPosition, Position};
+use world::{ChunkMines, ChunkPosition, Position, World};
 use world::Event;
+use crate::config::WorldConfig;
+use crate::feed::{EventFeed, LoggedEvent};
 
 #[serde_as]
 #[derive(Serialize, Deserialize)]
@@ -44,10 +53,65 @@ impl SourcedEvent {
             }
         }
     }
+
+    /// Does the same to the world as when the event was logged, and gives what happened. The log
+    /// doesn't say who did it, so the event's player ID is empty.
+    pub fn apply(self, world: &mut World) -> Option<Event> {
+        match self {
+            SourcedEvent::Click(position) => {
+                world.click(position, "")
+            }
+            SourcedEvent::DoubleClick(position) => {
+                world.double_click(position, "")
+            }
+            SourcedEvent::Flag(position) |
+            SourcedEvent::Unflag(position) => {
+                // TODO: should probably handle this properly
+                world.flag(position, "")
+            }
+            SourcedEvent::ChunkGenerated(position, mines) => {
+                if world.get_chunk(position.position()).is_none() {
+                    let chunk = mines.to_chunk(position);
+                    world.insert_chunk(chunk);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SourcedEvent::Click(_) => "Click",
+            SourcedEvent::DoubleClick(_) => "DoubleClick",
+            SourcedEvent::Flag(_) => "Flag",
+            SourcedEvent::Unflag(_) => "Unflag",
+            SourcedEvent::ChunkGenerated(_, _) => "ChunkGenerated",
+        }
+    }
+
+    pub fn position(&self) -> Position {
+        match self {
+            SourcedEvent::Click(position) |
+            SourcedEvent::DoubleClick(position) |
+            SourcedEvent::Flag(position) |
+            SourcedEvent::Unflag(position) => *position,
+            SourcedEvent::ChunkGenerated(position, _) => position.position(),
+        }
+    }
 }
 
+/// Appends events to the log. Events are queued up by [EventLogWriter::write], then written in
+/// one go by [EventLogWriter::flush] or [EventLogWriter::sync].
 pub struct EventLogWriter {
-    file: BufWriter<File>,
+    /// Where the log is, so that the feed can read old events back from it
+    path: PathBuf,
+    /// Only taken while it's being written to on a blocking thread
+    file: Option<std::fs::File>,
+    /// Lines that haven't been written to the file yet. When writing fails, whatever didn't make
+    /// it is kept here to try again.
+    unwritten: Vec<u8>,
+    /// How long the log is, including what hasn't been written yet
+    length: u64,
 }
 
 impl EventLogWriter {
@@ -55,19 +119,239 @@ impl EventLogWriter {
         let file = OpenOptions::new()
             .append(true)
             .create(true)
-            .open(file_path).await?;
-        Ok(Self { file: BufWriter::new(file) })
+            .open(&file_path).await?;
+        let length = file.metadata().await?.len();
+        Ok(Self { path: file_path, file: Some(file.into_std().await), unwritten: vec![], length })
     }
     
-    pub async fn write(&mut self, event: SourcedEvent) -> io::Result<()> {
-        let mut json = serde_json::to_string(&event)?;
+    pub fn write(&mut self, event: &SourcedEvent) -> io::Result<()> {
+        let mut json = serde_json::to_string(event)?;
         json.push('\n');
-        self.file.write_all(json.as_bytes()).await?;
+        self.unwritten.extend_from_slice(json.as_bytes());
+        self.length += json.len() as u64;
         Ok(())
     }
     
     pub async fn flush(&mut self) -> io::Result<()> {
-        self.file.flush().await
+        self.write_out(false).await
+    }
+
+    /// Flushes, then waits until the OS has actually put everything on the disk.
+    pub async fn sync(&mut self) -> io::Result<()> {
+        self.write_out(true).await
+    }
+
+    /// Writes the queued lines to the file on a blocking thread, keeping track of how much was
+    /// written so that nothing is lost or written twice if it fails part of the way through.
+    async fn write_out(&mut self, sync: bool) -> io::Result<()> {
+        let mut file = self.file.take().expect("Only one write at a time");
+        let unwritten = std::mem::take(&mut self.unwritten);
+        let (file, unwritten, result) = tokio::task::spawn_blocking(move || {
+            let mut written = 0;
+            let mut result = Ok(());
+            while written < unwritten.len() {
+                match file.write(&unwritten[written..]) {
+                    Ok(0) => {
+                        result = Err(io::ErrorKind::WriteZero.into());
+                        break;
+                    }
+                    Ok(n) => written += n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => {
+                        result = Err(err);
+                        break;
+                    }
+                }
+            }
+            if result.is_ok() && sync {
+                result = file.sync_data();
+            }
+            let unwritten = unwritten[written..].to_vec();
+            (file, unwritten, result)
+        }).await.expect("Writing to the event log panicked");
+        self.file = Some(file);
+        self.unwritten = unwritten;
+        result
+    }
+}
+
+/// How hard the event log writer tries to make sure that events survive a crash.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// Sync every batch of events before anyone is told about them
+    Always,
+    /// Write events as they come in, and sync at most this many milliseconds later
+    IntervalMs(u64),
+    /// Write events as they come in, and leave it to the OS to decide when to sync
+    Os,
+}
+
+impl Durability {
+    fn sync_interval(&self) -> Option<Interval> {
+        match self {
+            Durability::IntervalMs(ms) => {
+                let mut interval = tokio::time::interval(Duration::from_millis(*ms));
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                Some(interval)
+            }
+            Durability::Always | Durability::Os => None,
+        }
+    }
+}
+
+/// How long to wait before trying again when writing to the event log fails
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Something to do once some events have been written, like sending them to players
+pub type Then = Box<dyn FnOnce() + Send>;
+
+enum LogRequest {
+    /// An event to write, and the world event it came from if it's one to pass on to the feed
+    Event(SourcedEvent, Option<Event>),
+    /// Run this once everything before it has been written, and synced if the durability
+    /// needs that
+    Then(Then),
+    /// Sync everything before this, reply, then stop
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Handle to the task that writes to the event log. Events from every connection go through one
+/// channel, and the task writes whatever has built up since its last write in one go, so when
+/// lots of players are clicking at once, they share the cost of syncing.
+#[derive(Clone)]
+pub struct EventLog {
+    tx: UnboundedSender<LogRequest>,
+    /// The events that have been written, for the `/api/events` feed
+    pub feed: Arc<EventFeed>,
+}
+
+impl EventLog {
+    /// Starts writing. Events are passed on to the feed once they're written, and synced if
+    /// they need to be. The feed replays the log into a world like the one described to find
+    /// events that it doesn't keep any more.
+    pub fn spawn(writer: EventLogWriter, durability: Durability, world: &WorldConfig) -> Self {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let feed = Arc::new(EventFeed::new(writer.path.clone(), writer.length, world.clone()));
+        tokio::spawn(writer.run(rx, durability, feed.clone()));
+        Self { tx, feed }
+    }
+
+    /// Queues the events to be written, then runs `then` once they have been, and have been
+    /// synced with [Durability::Always]. This should be called while the regions that the
+    /// events happened in are still locked, so that the log has events in the same order that
+    /// they happened in, and `then` runs in that order too. Each event can come with the world
+    /// event it was made from, for the feed.
+    pub fn append(&self, events: Vec<(SourcedEvent, Option<Event>)>, then: impl FnOnce() + Send + 'static) {
+        for (event, published) in events {
+            if self.tx.send(LogRequest::Event(event, published)).is_err() {
+                error!("Event log writer has stopped, event not written");
+                break;
+            }
+        }
+        if let Err(SendError(LogRequest::Then(then))) = self.tx.send(LogRequest::Then(Box::new(then))) {
+            then();
+        }
+    }
+
+    /// Writes and syncs everything that has been appended so far, then stops the writer.
+    pub async fn shutdown(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.tx.send(LogRequest::Shutdown(done_tx)).is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+}
+
+impl EventLogWriter {
+    async fn run(mut self, mut rx: UnboundedReceiver<LogRequest>, durability: Durability, feed: Arc<EventFeed>) {
+        let mut sync_interval = durability.sync_interval();
+        let mut retry_interval = tokio::time::interval(RETRY_INTERVAL);
+        retry_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut unsynced = false;
+        // Whether the last write failed, so that everything since is waiting to be tried again
+        let mut failing = false;
+        let mut requests = vec![];
+        // Only run once everything before them has been written
+        let mut waiting = vec![];
+        // Events for the feed, waiting to be written
+        let mut written = vec![];
+        let sync = durability == Durability::Always;
+        loop {
+            tokio::select! {
+                received = rx.recv_many(&mut requests, 1024) => {
+                    if received == 0 {
+                        break;
+                    }
+                    let mut shutdown = None;
+                    for request in requests.drain(..) {
+                        match request {
+                            LogRequest::Event(event, published) => match self.write(&event) {
+                                Ok(()) => written.extend(published.map(|event| LoggedEvent { id: self.length, event })),
+                                Err(err) => error!("Unable to write {} event: {}", event.name(), err),
+                            }
+                            LogRequest::Then(then) => waiting.push(then),
+                            LogRequest::Shutdown(reply) => shutdown = Some(reply),
+                        }
+                    }
+
+                    let sync = sync || shutdown.is_some();
+                    failing = !self.write_batch(sync, durability, &mut waiting, &mut written, &feed).await;
+                    unsynced = failing || !sync;
+                    if let Some(reply) = shutdown {
+                        if failing {
+                            error!("{} bytes of events were never written to the event log", self.unwritten.len());
+                        } else {
+                            info!("Event log synced");
+                        }
+                        for then in waiting.drain(..) {
+                            then();
+                        }
+                        let _ = reply.send(());
+                        return;
+                    }
+                }
+                _ = retry_interval.tick(), if failing => {
+                    failing = !self.write_batch(sync, durability, &mut waiting, &mut written, &feed).await;
+                    unsynced = failing || !sync;
+                }
+                _ = async { sync_interval.as_mut().unwrap().tick().await }, if unsynced && !failing && sync_interval.is_some() => {
+                    unsynced = false;
+                    if let Err(err) = self.sync().await {
+                        error!("Unable to sync event log: {}", err);
+                    }
+                }
+            }
+        }
+        if let Err(err) = self.sync().await {
+            error!("Unable to sync event log: {}", err);
+        }
+    }
+
+    /// Writes everything queued up, and syncs if asked to. Once that's worked, the feed is sent
+    /// the events and everything waiting is run. If it fails, this returns false, and the
+    /// events are kept for the next try. So is everything waiting with [Durability::Always],
+    /// but otherwise it runs anyway, so that players aren't kept waiting for the disk.
+    async fn write_batch(
+        &mut self,
+        sync: bool,
+        durability: Durability,
+        waiting: &mut Vec<Then>,
+        written: &mut Vec<LoggedEvent>,
+        feed: &EventFeed,
+    ) -> bool {
+        let result = if sync { self.sync().await } else { self.flush().await };
+        if let Err(err) = &result {
+            error!("Unable to write to event log, trying again in {:?}: {}", RETRY_INTERVAL, err);
+        } else {
+            feed.publish(written.drain(..));
+        }
+        if result.is_ok() || durability != Durability::Always {
+            for then in waiting.drain(..) {
+                then();
+            }
+        }
+        result.is_ok()
     }
 }
 
@@ -87,6 +371,63 @@ impl EventLogReader {
     pub fn events(self) -> impl Stream<Item = EventReadResult> {
         self.reader.map(|line| EventReadResult::parse(line.ok()))
     }
+
+    /// Replays the log into the world, giving each event that changed something along with where
+    /// its line ends, like the feed's IDs. Lines that can't be read are skipped.
+    pub fn logged_events(self, world: World) -> impl Stream<Item = LoggedEvent> {
+        // Read as bytes, because the lines codec stops at the first line that isn't UTF-8
+        let reader = BufReader::new(self.reader.into_inner());
+        stream::unfold((reader, world, 0, vec![]), |(mut reader, mut world, mut offset, mut line)| async move {
+            loop {
+                line.clear();
+                let read = reader.read_until(b'\n', &mut line).await.ok()?;
+                if read == 0 {
+                    return None;
+                }
+                offset += read as u64;
+                let Ok(event) = serde_json::from_slice::<SourcedEvent>(&line) else {
+                    error!("Skipping invalid event at {offset}: {}", String::from_utf8_lossy(&line).trim_end());
+                    continue;
+                };
+                if let Some(event) = event.apply(&mut world) {
+                    return Some((LoggedEvent { id: offset, event }, (reader, world, offset, line)));
+                }
+            }
+        })
+    }
+
+    /// Applies every event in the log to the world, skipping any lines that can't be parsed.
+    pub async fn replay(self, world: &mut World) -> ReplaySummary {
+        let start_time = Instant::now();
+        let mut summary = ReplaySummary::default();
+        let mut events = self.events();
+        while let Some(event) = events.next().await {
+            match event {
+                EventReadResult::Ok(event) => {
+                    summary.events_read += 1;
+                    if summary.events_read % 1000 == 0 {
+                        info!("Read {} events", summary.events_read);
+                    }
+                    trace!("read");
+                    event.apply(world);
+                }
+                EventReadResult::Invalid(text) => {
+                    summary.invalid += 1;
+                    error!("Skipping invalid event: {}", text)
+                }
+                EventReadResult::Eof => {}
+            }
+        }
+        summary.elapsed = start_time.elapsed();
+        summary
+    }
+}
+
+#[derive(Default)]
+pub struct ReplaySummary {
+    pub events_read: usize,
+    pub invalid: usize,
+    pub elapsed: Duration,
 }
 
 pub enum EventReadResult {
@@ -110,4 +451,102 @@ impl EventReadResult {
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::oneshot;
+    use world::{Event, Position};
+    use crate::config::WorldConfig;
+    use futures_util::StreamExt;
+    use crate::eventlog::{Durability, EventLog, EventLogReader, EventLogWriter, SourcedEvent};
+    use crate::testing::TempDir;
+
+    async fn new_log(name: &str) -> (TempDir, PathBuf, EventLogWriter) {
+        let dir = TempDir::new(&format!("eventlog-{name}"));
+        let path = dir.join("eventlog");
+        let writer = EventLogWriter::new(path.clone()).await.unwrap();
+        (dir, path, writer)
+    }
+
+    fn flag(x: i32) -> (SourcedEvent, Option<Event>) {
+        let event = Event::Flag { player_id: "alice".to_string(), at: Position(x, 0) };
+        (SourcedEvent::from_event(&event), Some(event))
+    }
+
+    fn lines(path: &PathBuf) -> usize {
+        std::fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    #[tokio::test]
+    async fn always_runs_then_once_the_events_are_synced() {
+        let (_dir, path, writer) = new_log("always").await;
+        let log = EventLog::spawn(writer, Durability::Always, &WorldConfig::default());
+        let (tx, rx) = oneshot::channel();
+        let lines_path = path.clone();
+        log.append(vec![flag(1), flag(2)], move || tx.send(lines(&lines_path)).unwrap());
+        assert_eq!(rx.await.unwrap(), 2);
+        // By now the feed has them too, with where they end in the log as their IDs
+        let feed: Vec<_> = log.feed.join(Some(0)).unwrap().missed.iter().map(|logged| logged.id).collect();
+        let length = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(feed.len(), 2);
+        assert_eq!(feed[1], length);
+        log.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn shutting_down_writes_everything_appended() {
+        for durability in [Durability::IntervalMs(60_000), Durability::Os] {
+            let (_dir, path, writer) = new_log(&format!("{durability:?}")).await;
+            let log = EventLog::spawn(writer, durability, &WorldConfig::default());
+            let order = Arc::new(Mutex::new(vec![]));
+            for x in 0..100 {
+                let order = order.clone();
+                log.append(vec![flag(x)], move || order.lock().unwrap().push(x));
+            }
+            log.shutdown().await;
+            assert_eq!(lines(&path), 100);
+            assert_eq!(*order.lock().unwrap(), (0..100).collect::<Vec<_>>(), "Run in the order they were appended");
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_writes_are_kept_to_try_again() {
+        let (_dir, path, mut writer) = new_log("failing").await;
+        let good = writer.file.replace(std::fs::File::open(&path).unwrap());
+        writer.write(&flag(1).0).unwrap();
+        assert!(writer.flush().await.is_err());
+        assert!(!writer.unwritten.is_empty());
+        assert_eq!(lines(&path), 0);
+
+        writer.file = good;
+        writer.write(&flag(2).0).unwrap();
+        writer.sync().await.unwrap();
+        assert!(writer.unwritten.is_empty());
+        assert_eq!(lines(&path), 2);
+        assert_eq!(writer.length, std::fs::metadata(&path).unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn logged_events_skip_lines_that_cannot_be_read() {
+        let (_dir, path, mut writer) = new_log("corrupt").await;
+        writer.write(&flag(1).0).unwrap();
+        writer.flush().await.unwrap();
+        let first = std::fs::metadata(&path).unwrap().len();
+        {
+            use std::io::Write;
+            let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(b"{\"Flag\":\xff\xfe}\nnot an event\n").unwrap();
+        }
+        let mut writer = EventLogWriter::new(path.clone()).await.unwrap();
+        writer.write(&flag(2).0).unwrap();
+        writer.flush().await.unwrap();
+
+        let reader = EventLogReader::open(path.clone()).await.unwrap();
+        let read: Vec<_> = reader.logged_events(WorldConfig::default().new_world()).collect().await;
+        let ids: Vec<_> = read.iter().map(|logged| logged.id).collect();
+        assert_eq!(ids, vec![first, std::fs::metadata(&path).unwrap().len()]);
+        assert_eq!(read[1].event, Event::Flag { player_id: String::new(), at: Position(2, 0) });
+    }
+}
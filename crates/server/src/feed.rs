@@ -0,0 +1,349 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::future::ready;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use axum::response::sse;
+use futures_util::{stream, Stream, StreamExt};
+use serde::Deserialize;
+use tokio::sync::{broadcast, Semaphore};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use world::{Event, Rect};
+use crate::config::WorldConfig;
+use crate::eventlog::EventLogReader;
+
+/// How many of the latest events are kept for feed clients that reconnect
+const RECENT_EVENTS: usize = 4096;
+/// How far a feed client can fall behind before it's cut off. It can then reconnect and carry
+/// on from the events that are kept.
+const FEED_CAPACITY: usize = 1024;
+/// The names that can be given in `types`
+const EVENT_TYPES: [&str; 4] = ["Clicked", "DoubleClicked", "Flag", "Unflag"];
+
+/// An event that has been written to the log. Its ID is where its line in the log ends, which is
+/// where the next one starts, so IDs keep going up even when the server restarts.
+#[derive(Debug)]
+pub struct LoggedEvent {
+    pub id: u64,
+    pub event: Event,
+}
+
+/// The events written to the log, for clients of `/api/events`. The latest ones are kept so
+/// that a client that reconnects can carry on from the last one it was sent, and older ones are
+/// found by replaying the log.
+pub struct EventFeed {
+    recent: Mutex<Recent>,
+    tx: broadcast::Sender<Arc<LoggedEvent>>,
+    history: Arc<History>,
+}
+
+struct Recent {
+    /// Oldest first
+    events: VecDeque<Arc<LoggedEvent>>,
+    /// Every event after this ID is kept
+    complete_after: u64,
+    /// The ID of the latest event, or where the log ended when the server started
+    latest: u64,
+}
+
+/// Where to find the events that aren't kept any more
+struct History {
+    log: PathBuf,
+    world: WorldConfig,
+    /// Replaying the log takes a while, so only one client at a time does it
+    replaying: Arc<Semaphore>,
+}
+
+/// What a feed client is sent when it connects
+pub struct Joined {
+    /// The IDs between which the client missed events that have to be read from the log
+    from_log: Option<(u64, u64)>,
+    history: Arc<History>,
+    /// The kept events that came after the one the client was last sent, oldest first
+    pub(crate) missed: Vec<Arc<LoggedEvent>>,
+    /// The ID of the latest event when the client joined, which it has everything up to once
+    /// it's been sent the missed events
+    up_to: u64,
+    rx: broadcast::Receiver<Arc<LoggedEvent>>,
+}
+
+impl EventFeed {
+    /// A feed for the log at the path, which is this many bytes long so far, and whose events
+    /// happened in a world like the one described
+    pub fn new(log: PathBuf, log_length: u64, world: WorldConfig) -> Self {
+        let (tx, _) = broadcast::channel(FEED_CAPACITY);
+        Self {
+            recent: Mutex::new(Recent { events: VecDeque::new(), complete_after: log_length, latest: log_length }),
+            tx,
+            history: Arc::new(History { log, world, replaying: Arc::new(Semaphore::new(1)) }),
+        }
+    }
+
+    /// Sends events to every feed client, in the order they were written
+    pub fn publish(&self, events: impl IntoIterator<Item = LoggedEvent>) {
+        let mut recent = self.recent.lock().unwrap();
+        for event in events {
+            let event = Arc::new(event);
+            recent.latest = event.id;
+            recent.events.push_back(event.clone());
+            if recent.events.len() > RECENT_EVENTS {
+                if let Some(dropped) = recent.events.pop_front() {
+                    recent.complete_after = dropped.id;
+                }
+            }
+            // Nobody might be listening
+            let _ = self.tx.send(event);
+        }
+    }
+
+    /// Starts sending events to a client, carrying on after the last one it was sent if it's
+    /// reconnecting, or says why it can't
+    pub fn join(&self, last_id: Option<u64>) -> Result<Joined, String> {
+        // Subscribing with the lock held means that nothing is missed or sent twice
+        let recent = self.recent.lock().unwrap();
+        let rx = self.tx.subscribe();
+        let history = self.history.clone();
+        let Some(last_id) = last_id else {
+            return Ok(Joined { from_log: None, history, missed: vec![], up_to: recent.latest, rx });
+        };
+        if last_id > recent.latest {
+            return Err(format!("There's no event {last_id} yet, the latest is {}", recent.latest));
+        }
+        Ok(Joined {
+            from_log: (last_id < recent.complete_after).then_some((last_id, recent.complete_after)),
+            history,
+            missed: recent.events.iter().filter(|event| event.id > last_id).cloned().collect(),
+            up_to: recent.latest,
+            rx,
+        })
+    }
+}
+
+/// The query string of `/api/events`
+#[derive(Deserialize, Debug, Default)]
+pub struct FeedQuery {
+    left: Option<i32>,
+    top: Option<i32>,
+    right: Option<i32>,
+    bottom: Option<i32>,
+    /// Event names separated by commas, like `Clicked,Flag`
+    types: Option<String>,
+    /// For clients that can't send a `Last-Event-ID` header
+    pub last_event_id: Option<u64>,
+}
+
+/// Which events a feed client wants
+#[derive(Debug, Clone, Default)]
+pub struct FeedFilter {
+    area: Option<Rect>,
+    types: Option<Vec<&'static str>>,
+}
+
+impl FeedFilter {
+    /// The filter the query asks for, or why it doesn't make sense
+    pub fn from_query(query: &FeedQuery) -> Result<Self, String> {
+        let area = match (query.left, query.top, query.right, query.bottom) {
+            (None, None, None, None) => None,
+            // Filtering is cheap, so the area can be as big as the client likes
+            (Some(left), Some(top), Some(right), Some(bottom)) if left <= right && top <= bottom => {
+                Some(Rect { left, top, right, bottom })
+            }
+            (Some(_), Some(_), Some(_), Some(_)) => return Err("The area has a negative size".to_string()),
+            _ => return Err("Give all of left, top, right and bottom, or none of them".to_string()),
+        };
+        let types = match &query.types {
+            None => None,
+            Some(types) => Some(types.split(',')
+                .map(|name| EVENT_TYPES.into_iter().find(|&known| known == name.trim())
+                    .ok_or_else(|| format!("{name:?} isn't a type of event")))
+                .collect::<Result<_, _>>()?),
+        };
+        Ok(Self { area, types })
+    }
+
+    /// Whether the event is the right type, and changed something in the area
+    pub fn matches(&self, event: &Event) -> bool {
+        self.types.as_ref().is_none_or(|types| types.contains(&event.name()))
+            && self.area.is_none_or(|area| area.intersection(&event.area()).is_some())
+    }
+}
+
+impl Joined {
+    /// The events for the client, as server-sent events, until it falls too far behind or the
+    /// server shuts down. Each one is the event as JSON, with the event's ID. Events it missed
+    /// that aren't kept any more are read from the log first. If it falls behind, it's sent a
+    /// `lagged` event whose data is the ID to carry on from, before the stream ends.
+    pub fn stream(self, filter: FeedFilter, shutdown: CancellationToken) -> impl Stream<Item = Result<sse::Event, Infallible>> {
+        let Joined { from_log, history, missed, up_to, rx } = self;
+        let old = {
+            let filter = filter.clone();
+            let shutdown = shutdown.clone();
+            stream::iter(from_log)
+                .flat_map(move |(after, until)| history.clone().replay(after, until, filter.clone()))
+                .take_until(shutdown.cancelled_owned())
+        };
+        let missed: Vec<_> = missed.iter()
+            .filter(|logged| filter.matches(&logged.event))
+            .map(|logged| to_sse(logged))
+            .collect();
+        let live = stream::unfold(Some((rx, filter, up_to)), move |state| {
+            let shutdown = shutdown.clone();
+            async move {
+                let (mut rx, filter, mut up_to) = state?;
+                loop {
+                    let received = tokio::select! {
+                        received = rx.recv() => received,
+                        _ = shutdown.cancelled() => return None,
+                    };
+                    match received {
+                        Ok(logged) => {
+                            up_to = logged.id;
+                            if filter.matches(&logged.event) {
+                                return Some((to_sse(&logged), Some((rx, filter, up_to))));
+                            }
+                        }
+                        // Reconnecting from here gets it going again, without starting from
+                        // where it was before
+                        Err(RecvError::Lagged(_)) => {
+                            let lagged = sse::Event::default().event("lagged").data(up_to.to_string());
+                            return Some((lagged, None));
+                        }
+                        // The log has stopped
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+        old.chain(stream::iter(missed)).chain(live).map(Ok)
+    }
+}
+
+impl History {
+    /// The events after `after` up to and including `until`, found by replaying the log from
+    /// the start. They don't say who did them, because the log doesn't. If the log can't be
+    /// read, this is a `stale` event whose data is the ID after which the client will have
+    /// every event.
+    fn replay(self: Arc<Self>, after: u64, until: u64, filter: FeedFilter) -> impl Stream<Item = sse::Event> {
+        stream::once(async move {
+            let permit = self.replaying.clone().acquire_owned().await;
+            match EventLogReader::open(self.log.clone()).await {
+                Ok(reader) => reader.logged_events(self.world.new_world())
+                    .take_while(move |logged| ready(logged.id <= until))
+                    .filter(move |logged| ready(logged.id > after && filter.matches(&logged.event)))
+                    // Letting the next client replay once this one has finished
+                    .map(move |logged| { let _replaying = &permit; to_sse(&logged) })
+                    .left_stream(),
+                Err(_) => stream::iter([sse::Event::default().event("stale").data(until.to_string())]).right_stream(),
+            }
+        }).flatten()
+    }
+}
+
+fn to_sse(logged: &LoggedEvent) -> sse::Event {
+    sse::Event::default()
+        .id(logged.id.to_string())
+        .data(serde_json::to_string(&logged.event).expect("Events can always be written as JSON"))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use world::{Event, Position};
+    use crate::config::WorldConfig;
+    use crate::eventlog::{EventLogWriter, SourcedEvent};
+    use tokio_util::sync::CancellationToken;
+    use crate::feed::{EventFeed, FeedFilter, FeedQuery, LoggedEvent, FEED_CAPACITY, RECENT_EVENTS};
+    use crate::testing::TempDir;
+
+    fn flag(id: u64, at: Position) -> LoggedEvent {
+        LoggedEvent { id, event: Event::Flag { player_id: "alice".to_string(), at } }
+    }
+
+    fn ids(events: &[std::sync::Arc<LoggedEvent>]) -> Vec<u64> {
+        events.iter().map(|logged| logged.id).collect()
+    }
+
+    #[test]
+    fn reconnecting_carries_on_from_the_last_event() {
+        let feed = EventFeed::new("eventlog".into(), 100, WorldConfig::default());
+        let mut live = feed.join(None).unwrap();
+        feed.publish([flag(150, Position(0, 0)), flag(200, Position(1, 0))]);
+        assert_eq!(live.rx.try_recv().unwrap().id, 150);
+
+        let joined = feed.join(Some(150)).unwrap();
+        assert_eq!(ids(&joined.missed), vec![200]);
+        assert_eq!(joined.from_log, None);
+        // Events from before the server started aren't kept, so they're read from the log
+        let joined = feed.join(Some(50)).unwrap();
+        assert_eq!(ids(&joined.missed), vec![150, 200]);
+        assert_eq!(joined.from_log, Some((50, 100)));
+        assert!(feed.join(Some(201)).is_err(), "there's no such event yet");
+
+        feed.publish((0..RECENT_EVENTS as u64).map(|i| flag(300 + i, Position(0, 0))));
+        let joined = feed.join(Some(200)).unwrap();
+        assert_eq!(joined.missed.len(), RECENT_EVENTS);
+        assert_eq!(joined.from_log, None, "150 and 200 have gone, but the client had them");
+        assert_eq!(feed.join(Some(150)).unwrap().from_log, Some((150, 200)));
+    }
+
+    #[tokio::test]
+    async fn clients_that_fall_behind_are_told_where_to_carry_on_from() {
+        let feed = EventFeed::new("eventlog".into(), 100, WorldConfig::default());
+        let joined = feed.join(Some(100)).unwrap();
+        feed.publish((1..=FEED_CAPACITY as u64 + 10).map(|i| flag(100 + i, Position(0, 0))));
+        let sent: Vec<_> = joined.stream(FeedFilter::default(), CancellationToken::new())
+            .map(|event| format!("{:?}", event.unwrap()))
+            .collect().await;
+        // Nothing was read before it fell behind, so it carries on from where it started. The
+        // event's debug output has the bytes that would be sent.
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].contains(r"event: lagged\ndata: 100\n"), "{}", sent[0]);
+    }
+
+    #[tokio::test]
+    async fn events_that_are_not_kept_are_read_from_the_log() {
+        let dir = TempDir::new("feed");
+        let path = dir.join("eventlog");
+        let mut writer = EventLogWriter::new(path.clone()).await.unwrap();
+        let mut ids = vec![];
+        for event in [SourcedEvent::Flag(Position(1, 1)), SourcedEvent::Unflag(Position(1, 1)), SourcedEvent::Flag(Position(9, 9))] {
+            writer.write(&event).unwrap();
+            writer.flush().await.unwrap();
+            ids.push(std::fs::metadata(&path).unwrap().len());
+        }
+
+        // As if the server had restarted since
+        let feed = EventFeed::new(path.clone(), ids[2], WorldConfig::default());
+        let joined = feed.join(Some(ids[0])).unwrap();
+        assert_eq!(joined.from_log, Some((ids[0], ids[2])));
+        let everything = FeedFilter::default();
+        let read: Vec<_> = feed.history.clone().replay(ids[0], ids[2], everything).collect().await;
+        assert_eq!(read.len(), 2);
+        let near = FeedFilter::from_query(&FeedQuery {
+            left: Some(0), top: Some(0), right: Some(3), bottom: Some(3), ..Default::default()
+        }).unwrap();
+        let read: Vec<_> = feed.history.clone().replay(0, ids[2], near).collect().await;
+        assert_eq!(read.len(), 2, "the flag and unflag at (1, 1)");
+    }
+
+    #[test]
+    fn filtering_by_area_and_type() {
+        let area = |left, top, right, bottom| FeedQuery {
+            left: Some(left), top: Some(top), right: Some(right), bottom: Some(bottom), ..Default::default()
+        };
+        let types = |types: &str| FeedQuery { types: Some(types.to_string()), ..Default::default() };
+        let filter = FeedFilter::from_query(&FeedQuery { types: Some("Flag, Unflag".to_string()), ..area(0, 0, 10, 10) }).unwrap();
+        assert!(filter.matches(&flag(1, Position(5, 5)).event));
+        assert!(!filter.matches(&flag(1, Position(50, 5)).event));
+        let unflag = Event::Unflag { player_id: "bob".to_string(), at: Position(1, 1) };
+        assert!(filter.matches(&unflag));
+        let everything = FeedFilter::from_query(&FeedQuery::default()).unwrap();
+        assert!(everything.matches(&flag(1, Position(50, 5)).event));
+
+        assert!(FeedFilter::from_query(&FeedQuery { left: Some(0), ..Default::default() }).is_err());
+        assert!(FeedFilter::from_query(&area(10, 0, 0, 10)).is_err());
+        assert!(FeedFilter::from_query(&types("Clicked,Exploded")).is_err());
+        assert!(FeedFilter::from_query(&types("DoubleClicked")).is_ok());
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use world::player::{Player, PlayerStats};
+use world::{Event, Position};
+use crate::json_file::{JsonFile, Unsaved};
+
+/// Players who haven't been back for this long are forgotten, and their tokens stop working.
+pub const IDENTITY_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// The most players remembered at once. Past this, the ones seen longest ago are forgotten first.
+pub const MAX_IDENTITIES: usize = 100_000;
+
+/// Who a player is, which carries on between connections. The token is the secret that the
+/// client sends back to be this player again.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub player_id: String,
+    pub token: String,
+    pub position: Position,
+    pub name: String,
+    pub colour: u8,
+    pub stats: PlayerStats,
+    /// Seconds since the Unix epoch
+    pub last_seen: u64,
+}
+
+/// Every player's identity, kept in a JSON file in the data directory so that they survive
+/// restarts.
+pub struct Identities {
+    pub file: JsonFile<Identity>,
+    by_player: HashMap<String, Identity>,
+    /// Player ID for each token
+    by_token: HashMap<String, String>,
+    /// Players who haven't clicked or set a profile yet, so aren't worth writing to the file, and
+    /// are forgotten if they leave like that
+    idle: HashSet<String>,
+    /// Players who are only remembered until they leave, and whose tokens aren't given out
+    guests: HashSet<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl Identities {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            file: JsonFile::new(path),
+            by_player: HashMap::new(),
+            by_token: HashMap::new(),
+            idle: HashSet::new(),
+            guests: HashSet::new(),
+        }
+    }
+
+    /// Reads the identities from the file, or starts with none if there isn't one yet
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let mut identities = Self::new(path);
+        for identity in identities.file.load()? {
+            identities.insert(identity);
+        }
+        identities.forget_older_than(now().saturating_sub(IDENTITY_LIFETIME.as_secs()));
+        Ok(identities)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_player.len()
+    }
+
+    fn insert(&mut self, identity: Identity) {
+        self.by_token.insert(identity.token.clone(), identity.player_id.clone());
+        self.by_player.insert(identity.player_id.clone(), identity);
+    }
+
+    /// A player that hasn't been seen before. They're only written to the file once they've
+    /// done something.
+    pub fn create(&mut self) -> Identity {
+        let identity = self.new_identity();
+        self.idle.insert(identity.player_id.clone());
+        identity
+    }
+
+    /// A player that's never written to the file, for when someone is already playing as the
+    /// player they asked to be
+    pub fn create_guest(&mut self) -> Identity {
+        let identity = self.new_identity();
+        self.guests.insert(identity.player_id.clone());
+        identity
+    }
+
+    pub fn knows(&self, player_id: &str) -> bool {
+        self.by_player.contains_key(player_id)
+    }
+
+    pub fn is_guest(&self, player_id: &str) -> bool {
+        self.guests.contains(player_id)
+    }
+
+    fn new_identity(&mut self) -> Identity {
+        let mut player_id = Player::random_id();
+        while self.by_player.contains_key(&player_id) {
+            player_id = Player::random_id();
+        }
+        let Player { player_id, position, name, colour } = Player::new(player_id);
+        let identity = Identity {
+            player_id,
+            token: Player::random_token(),
+            position,
+            name,
+            colour,
+            stats: PlayerStats::default(),
+            last_seen: now(),
+        };
+        self.insert(identity.clone());
+        self.file.mark_changed();
+        identity
+    }
+
+    /// The player that was given this token
+    pub fn find(&mut self, token: &str) -> Option<Identity> {
+        let player_id = self.by_token.get(token)?;
+        let identity = self.by_player.get_mut(player_id)?;
+        identity.last_seen = now();
+        self.file.mark_changed();
+        Some(identity.clone())
+    }
+
+    pub fn stats(&self, player_id: &str) -> PlayerStats {
+        self.by_player.get(player_id).map(|identity| identity.stats).unwrap_or_default()
+    }
+
+    /// Adds what the player did to their stats, and remembers where they did it
+    pub fn record(&mut self, player_id: &str, event: &Event) {
+        if let Some(identity) = self.by_player.get_mut(player_id) {
+            identity.stats.record(event);
+            let mut player = Player { position: identity.position, ..Player::new(player_id.to_string()) };
+            player.update(event);
+            identity.position = player.position;
+            identity.last_seen = now();
+            self.idle.remove(player_id);
+            self.file.mark_changed();
+        }
+    }
+
+    pub fn moved(&mut self, player_id: &str, position: Position) {
+        if let Some(identity) = self.by_player.get_mut(player_id) {
+            identity.position = position;
+            identity.last_seen = now();
+            self.file.mark_changed();
+        }
+    }
+
+    pub fn set_profile(&mut self, player_id: &str, name: &str, colour: u8) {
+        if let Some(identity) = self.by_player.get_mut(player_id) {
+            identity.name = name.to_string();
+            identity.colour = colour;
+            self.idle.remove(player_id);
+            self.file.mark_changed();
+        }
+    }
+
+    /// Saves the player from now on, like when they put a marker down that should stay after a
+    /// restart
+    pub fn keep(&mut self, player_id: &str) {
+        if self.idle.remove(player_id) {
+            self.file.mark_changed();
+        }
+    }
+
+    /// Forgets the players last seen before `cutoff`, in seconds since the Unix epoch
+    pub fn forget_older_than(&mut self, cutoff: u64) {
+        let before = self.by_player.len();
+        self.by_player.retain(|_, identity| identity.last_seen >= cutoff);
+        if self.by_player.len() != before {
+            self.forgotten();
+        }
+    }
+
+    /// Forgets the players seen longest ago until at most `max` are saved. Idle players and
+    /// guests don't count, because they're forgotten when they leave anyway.
+    pub fn forget_all_but(&mut self, max: usize) {
+        let Self { by_player, idle, guests, .. } = self;
+        let saved = |identity: &Identity| !idle.contains(&identity.player_id) && !guests.contains(&identity.player_id);
+        let mut last_seen: Vec<_> = by_player.values()
+            .filter(|identity| saved(identity))
+            .map(|identity| identity.last_seen)
+            .collect();
+        if last_seen.len() <= max {
+            return;
+        }
+        let nth = last_seen.len() - max;
+        let (_, cutoff, _) = last_seen.select_nth_unstable(nth);
+        let cutoff = *cutoff;
+        // Players seen at the same second as the cutoff might still be too many
+        let mut excess = last_seen.iter().filter(|&&seen| seen >= cutoff).count() - max;
+        by_player.retain(|_, identity| {
+            if !saved(identity) || identity.last_seen > cutoff {
+                return true;
+            }
+            let forget = identity.last_seen < cutoff || excess > 0;
+            excess -= (identity.last_seen == cutoff && forget) as usize;
+            !forget
+        });
+        self.forgotten();
+    }
+
+    /// Forgets the player if they were never going to be saved, now that they've gone
+    pub fn left(&mut self, player_id: &str) {
+        if !self.idle.remove(player_id) && !self.guests.remove(player_id) {
+            return;
+        }
+        if let Some(identity) = self.by_player.remove(player_id) {
+            self.by_token.remove(&identity.token);
+        }
+    }
+
+    fn forgotten(&mut self) {
+        let by_player = &self.by_player;
+        self.by_token.retain(|_, player_id| by_player.contains_key(player_id));
+        self.idle.retain(|player_id| by_player.contains_key(player_id));
+        self.guests.retain(|player_id| by_player.contains_key(player_id));
+        self.file.mark_changed();
+    }
+
+    /// Forgets players who haven't been back for too long first. Players who are idle or guests
+    /// aren't written.
+    pub fn unsaved(&mut self) -> Option<Unsaved<Identity>> {
+        self.forget_older_than(now().saturating_sub(IDENTITY_LIFETIME.as_secs()));
+        self.forget_all_but(MAX_IDENTITIES);
+        let Self { file, by_player, idle, guests, .. } = self;
+        file.unsaved(|| by_player.values()
+            .filter(|identity| !idle.contains(&identity.player_id) && !guests.contains(&identity.player_id))
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use world::{Event, Position, Tile, UpdatedRect, UpdatedTile};
+    use crate::identities::Identities;
+    use crate::testing::TempDir;
+
+    #[tokio::test]
+    async fn identities_survive_a_restart() {
+        let dir = TempDir::new("identities");
+        let path = dir.join("players.json");
+
+        let mut identities = Identities::load(path.clone()).unwrap();
+        let alice = identities.create();
+        let bob = identities.create();
+        let updated = UpdatedRect::new(vec![
+            UpdatedTile { position: Position(3, 4), tile: Tile::empty().with_revealed() },
+        ]);
+        identities.record(&alice.player_id, &Event::Clicked {
+            player_id: alice.player_id.clone(),
+            at: Position(3, 4),
+            updated,
+        });
+        identities.moved(&bob.player_id, Position(-10, 20));
+        identities.set_profile(&bob.player_id, "Bob", 17);
+        identities.unsaved().unwrap().write().await.unwrap();
+
+        let mut identities = Identities::load(path.clone()).unwrap();
+        let found = identities.find(&alice.token).unwrap();
+        assert_eq!(found.player_id, alice.player_id);
+        assert_eq!(found.position, Position(3, 4));
+        assert_eq!((found.stats.clicks, found.stats.tiles_revealed), (1, 1));
+        let found = identities.find(&bob.token).unwrap();
+        assert_eq!((found.position, found.name.as_str(), found.colour), (Position(-10, 20), "Bob", 17));
+        assert!(identities.find("not a token").is_none());
+
+        identities.forget_older_than(u64::MAX);
+        assert!(identities.find(&alice.token).is_none());
+    }
+
+    #[test]
+    fn players_are_only_saved_once_they_have_done_something() {
+        let mut identities = Identities::new("players.json".into());
+        let idle = identities.create();
+        let clicker = identities.create();
+        let named = identities.create();
+        let marker = identities.create();
+        let guest = identities.create_guest();
+        let event = Event::Flag { player_id: clicker.player_id.clone(), at: Position(0, 0) };
+        identities.record(&clicker.player_id, &event);
+        identities.set_profile(&named.player_id, "Named", 3);
+        identities.keep(&marker.player_id);
+        identities.record(&guest.player_id, &event);
+        identities.set_profile(&guest.player_id, "Guest", 4);
+
+        let mut saved: Vec<_> = identities.unsaved().unwrap().items.into_iter().map(|identity| identity.player_id).collect();
+        saved.sort();
+        let mut expected = vec![clicker.player_id, named.player_id, marker.player_id.clone()];
+        expected.sort();
+        assert_eq!(saved, expected);
+        // They're still known until they leave
+        assert!(identities.find(&idle.token).is_some());
+        assert!(identities.is_guest(&guest.player_id));
+        for player in [&idle, &guest, &marker] {
+            identities.left(&player.player_id);
+        }
+        assert!(identities.find(&idle.token).is_none());
+        assert!(!identities.knows(&guest.player_id));
+        assert!(identities.find(&marker.token).is_some());
+    }
+
+    #[test]
+    fn the_players_seen_longest_ago_are_forgotten_first() {
+        let mut identities = Identities::new("players.json".into());
+        let players: Vec<_> = (0..10).map(|_| identities.create()).collect();
+        for (last_seen, player) in players.iter().enumerate() {
+            identities.keep(&player.player_id);
+            // Some seen at the same time, so that the cutoff has to split them
+            identities.by_player.get_mut(&player.player_id).unwrap().last_seen = last_seen as u64 / 2;
+        }
+        // Seen longer ago than anyone, but they aren't saved so they don't count
+        let idle = identities.create();
+        let guest = identities.create_guest();
+        for player in [&idle, &guest] {
+            identities.by_player.get_mut(&player.player_id).unwrap().last_seen = 0;
+        }
+        identities.forget_all_but(5);
+        assert_eq!(identities.len(), 7);
+        for player in &players[..4] {
+            assert!(identities.find(&player.token).is_none());
+        }
+        for player in players[6..].iter().chain([&idle, &guest]) {
+            assert!(identities.find(&player.token).is_some());
+        }
+        assert_eq!(identities.by_token.len(), 7);
+    }
+}
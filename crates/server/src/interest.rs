@@ -0,0 +1,693 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use axum::extract::ws::Message;
+use log::info;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+use world::player::Player;
+use world::{ChatChannel, ChatMessage, ChunkPosition, Event, Position, Rect, ServerMessage, ServerMessageBundle};
+use crate::regions::CompressedChunk;
+
+/// The index splits the world into square cells this many tiles across, and remembers which
+/// clients are interested in each cell.
+const CELL_SIZE: i32 = 1024;
+
+/// The biggest viewport a client can have along each axis. The web client never has more than
+/// 8192 tiles loaded along each axis.
+const MAX_VIEWPORT: i32 = 8192;
+
+/// Once the server is remembering this many chunk versions for one client, it starts again
+/// rather than using more memory. The worst that happens is that chunks get sent again.
+const MAX_KNOWN_CHUNKS: usize = 1 << 16;
+
+/// Pings are sent to the clients looking at anywhere this many tiles or fewer from them, so
+/// that ones just off the screen can be pointed to
+const PING_RANGE: i32 = 512;
+
+/// Checks that a client isn't asking about more of the world than it could be looking at
+pub fn check_area(rect: &Rect) -> Result<(), String> {
+    let width = rect.right as i64 - rect.left as i64;
+    let height = rect.bottom as i64 - rect.top as i64;
+    if width < 0 || height < 0 {
+        Err(format!("{:?} has a negative size", rect))
+    } else if width > MAX_VIEWPORT as i64 || height > MAX_VIEWPORT as i64 {
+        Err(format!("An area of {width}x{height} tiles is too big, the most is {MAX_VIEWPORT}x{MAX_VIEWPORT}"))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct Cell(i32, i32);
+
+impl Cell {
+    fn of(Position(x, y): Position) -> Self {
+        Self(x.div_euclid(CELL_SIZE), y.div_euclid(CELL_SIZE))
+    }
+
+    /// Every cell that has some part of the rect in it
+    fn covering(rect: &Rect) -> impl Iterator<Item = Cell> {
+        let Cell(left, top) = Self::of(rect.top_left());
+        let Cell(right, bottom) = Self::of(rect.bottom_right());
+        (left..=right).flat_map(move |x| (top..=bottom).map(move |y| Cell(x, y)))
+    }
+}
+
+struct Subscriber {
+    tx: Sender<Message>,
+    /// Set when the client's queue filled up. Nothing more is sent until it has caught up.
+    lagged: bool,
+    /// Tells the connection that the client has fallen behind and needs to be resynced
+    resync: Arc<Notify>,
+    /// The part of the world that the client is looking at
+    area: Option<Rect>,
+    /// Players that the client has been told about, and so needs to hear about disconnecting
+    known_players: HashSet<String>,
+    /// The version of each chunk that the client has, so that chunks that haven't changed
+    /// aren't sent again
+    known_chunks: HashMap<ChunkPosition, u32>,
+    /// The client can be sent where other players' cursors are
+    wants_cursors: bool,
+    /// The client can be sent chat messages
+    wants_chat: bool,
+    /// The client can be sent pings and markers
+    wants_markers: bool,
+}
+
+/// Works out which clients need to hear about what, so that each client only gets the events
+/// in the part of the world that it's looking at, rather than everything that happens.
+#[derive(Default)]
+pub struct Subscriptions {
+    subscribers: HashMap<String, Subscriber>,
+    cells: HashMap<Cell, HashSet<String>>,
+    /// Where each player's cursor has moved to since the cursors were last sent out. Only the
+    /// latest position counts, however often a client sends.
+    moved_cursors: HashMap<String, Position>,
+}
+
+impl Subscriptions {
+    /// Starts sending to the client. The connection is notified if the client falls behind.
+    pub fn join(&mut self, player_id: &str, tx: Sender<Message>) -> Arc<Notify> {
+        let resync = Arc::new(Notify::new());
+        let old = self.subscribers.insert(player_id.to_string(), Subscriber {
+            tx,
+            lagged: false,
+            resync: resync.clone(),
+            area: None,
+            known_players: HashSet::new(),
+            known_chunks: HashMap::new(),
+            wants_cursors: false,
+            wants_chat: false,
+            wants_markers: false,
+        });
+        // A player coming back on a new connection starts again from nothing
+        if let Some(area) = old.and_then(|old| old.area) {
+            self.remove_from_cells(player_id, &area);
+        }
+        resync
+    }
+
+    /// Whether to send the client where other players' cursors are
+    pub fn want_cursors(&mut self, player_id: &str, wants_cursors: bool) {
+        if let Some(subscriber) = self.subscribers.get_mut(player_id) {
+            subscriber.wants_cursors = wants_cursors;
+        }
+    }
+
+    /// Whether to send the client chat messages
+    pub fn want_chat(&mut self, player_id: &str, wants_chat: bool) {
+        if let Some(subscriber) = self.subscribers.get_mut(player_id) {
+            subscriber.wants_chat = wants_chat;
+        }
+    }
+
+    /// Whether to send the client pings and markers
+    pub fn want_markers(&mut self, player_id: &str, wants_markers: bool) {
+        if let Some(subscriber) = self.subscribers.get_mut(player_id) {
+            subscriber.wants_markers = wants_markers;
+        }
+    }
+
+    pub fn wants_chat(&self, player_id: &str) -> bool {
+        self.subscribers.get(player_id).is_some_and(|subscriber| subscriber.wants_chat)
+    }
+
+    pub fn wants_markers(&self, player_id: &str) -> bool {
+        self.subscribers.get(player_id).is_some_and(|subscriber| subscriber.wants_markers)
+    }
+
+    /// Starts sending to a client that fell behind again. It has missed some messages, so it's
+    /// treated as if it knows nothing, and this gives its viewport and the players in it to
+    /// send again.
+    pub fn resync(&mut self, player_id: &str, players: &HashMap<String, Player>) -> (Option<Rect>, Vec<Player>) {
+        let Some(subscriber) = self.subscribers.get_mut(player_id) else { return (None, vec![]) };
+        subscriber.lagged = false;
+        subscriber.known_players.clear();
+        // Some of the events it missed might have been in chunks it has
+        subscriber.known_chunks.clear();
+        let Some(viewport) = subscriber.area else { return (None, vec![]) };
+        (Some(viewport), self.unknown_players_in(player_id, &viewport, players))
+    }
+
+    /// Forgets about the client, and tells everyone who knew about the player that they've gone.
+    pub fn leave(&mut self, player_id: &str) {
+        self.moved_cursors.remove(player_id);
+        if let Some(subscriber) = self.subscribers.remove(player_id) {
+            if let Some(area) = subscriber.area {
+                self.remove_from_cells(player_id, &area);
+            }
+        }
+        let recipients: Vec<_> = self.subscribers.iter_mut()
+            .filter_map(|(id, subscriber)| subscriber.known_players.remove(player_id).then_some(id.clone()))
+            .collect();
+        self.send_to(&recipients, ServerMessage::Disconnected(player_id.to_string()));
+    }
+
+    /// Replaces the area the client is looking at, and returns the parts of the new area that
+    /// weren't in the old one, which the client needs to be sent. Viewports that are too big
+    /// are cut down to size from the bottom right.
+    pub fn set_viewport(&mut self, player_id: &str, mut viewport: Rect) -> Vec<Rect> {
+        let Some(subscriber) = self.subscribers.get_mut(player_id) else { return vec![] };
+        viewport.right = viewport.right.min(viewport.left.saturating_add(MAX_VIEWPORT));
+        viewport.bottom = viewport.bottom.min(viewport.top.saturating_add(MAX_VIEWPORT));
+        let old_viewport = subscriber.area.replace(viewport);
+
+        if let Some(old_viewport) = old_viewport {
+            self.remove_from_cells(player_id, &old_viewport);
+        }
+        for cell in Cell::covering(&viewport) {
+            self.cells.entry(cell).or_default().insert(player_id.to_string());
+        }
+        match old_viewport {
+            Some(old_viewport) => viewport.subtract(&old_viewport),
+            None => vec![viewport],
+        }
+    }
+
+    /// Remembers which versions of chunks the client says it already has
+    pub fn hold(&mut self, player_id: &str, versions: Vec<(ChunkPosition, u32)>) {
+        let Some(subscriber) = self.subscribers.get_mut(player_id) else { return };
+        for (position, version) in versions {
+            Self::remember_chunk(subscriber, position, version);
+        }
+    }
+
+    /// Leaves out the chunks that the client already has, and remembers that it's about to be
+    /// sent the rest
+    pub fn changed_chunks(&mut self, player_id: &str, chunks: Vec<CompressedChunk>) -> Vec<CompressedChunk> {
+        let Some(subscriber) = self.subscribers.get_mut(player_id) else { return chunks };
+        chunks.into_iter()
+            .filter(|chunk| Self::remember_chunk(subscriber, chunk.position, chunk.version))
+            .collect()
+    }
+
+    /// Returns false if the client already has this version of the chunk
+    fn remember_chunk(subscriber: &mut Subscriber, position: ChunkPosition, version: u32) -> bool {
+        if subscriber.known_chunks.get(&position) == Some(&version) {
+            return false;
+        }
+        if subscriber.known_chunks.len() >= MAX_KNOWN_CHUNKS {
+            subscriber.known_chunks.clear();
+        }
+        subscriber.known_chunks.insert(position, version);
+        true
+    }
+
+    fn remove_from_cells(&mut self, player_id: &str, area: &Rect) {
+        for cell in Cell::covering(area) {
+            if let Some(subscribers) = self.cells.get_mut(&cell) {
+                subscribers.remove(player_id);
+                if subscribers.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    /// The clients whose area overlaps the rect
+    fn interested_in(&self, rect: &Rect) -> Vec<String> {
+        let mut result = HashSet::new();
+        for cell in Cell::covering(rect) {
+            for player_id in self.cells.get(&cell).into_iter().flatten() {
+                let overlaps = self.subscribers[player_id].area
+                    .is_some_and(|area| area.intersection(rect).is_some());
+                if overlaps {
+                    result.insert(player_id.clone());
+                }
+            }
+        }
+        result.into_iter().collect()
+    }
+
+    /// Sends the event to everyone looking at where it happened, and always to the player who
+    /// caused it. Clients that didn't know about the player are told who they are first.
+    pub fn send_event(&mut self, event: Event, players: &HashMap<String, Player>) {
+        let player_id = event.player().player_id;
+        let mut recipients = self.interested_in(&event.area());
+        if !recipients.contains(&player_id) {
+            recipients.push(player_id.clone());
+        }
+        let introduced = self.learn_about(&recipients, &player_id);
+        if let Some(player) = players.get(&player_id) {
+            self.send_to(&introduced, ServerMessage::Player(player.clone()));
+        }
+        self.send_to(&recipients, ServerMessage::Event(event));
+    }
+
+    /// Tells everyone looking at where the player is about them.
+    pub fn send_player(&mut self, player: Player) {
+        let recipients = self.interested_in(&Rect::from_center_and_size(player.position, 1, 1));
+        self.learn_about(&recipients, &player.player_id);
+        self.send_to(&recipients, ServerMessage::Player(player));
+    }
+
+    /// Remembers where the player's cursor is, to send out with everyone else's
+    pub fn move_cursor(&mut self, player_id: &str, position: Position) {
+        if self.subscribers.contains_key(player_id) {
+            self.moved_cursors.insert(player_id.to_string(), position);
+        }
+    }
+
+    /// Sends each client one message with every cursor that has moved in its area since last
+    /// time, other than its own, after the players it didn't know about yet.
+    pub fn send_cursors(&mut self, players: &HashMap<String, Player>) {
+        let mut to_send: HashMap<String, Vec<Player>> = HashMap::new();
+        for (player_id, position) in std::mem::take(&mut self.moved_cursors) {
+            for recipient in self.interested_in(&Rect::from_center_and_size(position, 1, 1)) {
+                if recipient != player_id && self.subscribers[&recipient].wants_cursors {
+                    to_send.entry(recipient).or_default().push(Player { position, ..Player::new(player_id.clone()) });
+                }
+            }
+        }
+        for (recipient, moved) in to_send {
+            let Some(subscriber) = self.subscribers.get_mut(&recipient) else { continue };
+            if subscriber.lagged {
+                continue;
+            }
+            let mut messages: Vec<_> = moved.iter()
+                .filter(|player| subscriber.known_players.insert(player.player_id.clone()))
+                .filter_map(|player| players.get(&player.player_id).cloned())
+                .map(ServerMessage::Player)
+                .collect();
+            messages.push(ServerMessage::Cursors(moved));
+            let message = ServerMessageBundle(messages).to_bytes();
+            Self::try_send(&recipient, subscriber, message);
+        }
+    }
+
+    /// Sends the message to every client that can read chat, or for the nearby channel only to
+    /// the ones looking at where the sender is, and always to the sender.
+    pub fn send_chat(&mut self, message: ChatMessage) {
+        let mut recipients = match message.channel {
+            ChatChannel::Global => self.subscribers.keys().cloned().collect(),
+            ChatChannel::Nearby => self.interested_in(&Rect::from_center_and_size(message.position, 1, 1)),
+        };
+        if !recipients.contains(&message.player_id) {
+            recipients.push(message.player_id.clone());
+        }
+        recipients.retain(|recipient| self.subscribers.get(recipient).is_some_and(|subscriber| subscriber.wants_chat));
+        self.send_to(&recipients, ServerMessage::Chat(message));
+    }
+
+    /// Sends the ping to the clients looking near it that can read it, and always to the player
+    /// who sent it.
+    pub fn send_ping(&mut self, player_id: &str, position: Position) {
+        let mut recipients = self.interested_in(&Rect::from_center_and_size(position, 2 * PING_RANGE, 2 * PING_RANGE));
+        if !recipients.iter().any(|recipient| recipient == player_id) {
+            recipients.push(player_id.to_string());
+        }
+        recipients.retain(|recipient| self.wants_markers(recipient));
+        self.send_to(&recipients, ServerMessage::Ping { player_id: player_id.to_string(), position });
+    }
+
+    /// Sends a marker being added or removed to every client that can read it
+    pub fn send_marker_change(&mut self, message: ServerMessage) {
+        let recipients: Vec<_> = self.subscribers.iter()
+            .filter(|(_, subscriber)| subscriber.wants_markers)
+            .map(|(player_id, _)| player_id.clone())
+            .collect();
+        self.send_to(&recipients, message);
+    }
+
+    /// The players in the rect that the client hasn't been told about yet. Calling this counts
+    /// as telling them.
+    pub fn unknown_players_in(&mut self, player_id: &str, rect: &Rect, players: &HashMap<String, Player>) -> Vec<Player> {
+        let Some(subscriber) = self.subscribers.get_mut(player_id) else { return vec![] };
+        players.values()
+            .filter(|player| rect.intersection(&Rect::from_center_and_size(player.position, 1, 1)).is_some())
+            .filter(|player| subscriber.known_players.insert(player.player_id.clone()))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the recipients that didn't know about the player before
+    fn learn_about(&mut self, recipients: &[String], player_id: &str) -> Vec<String> {
+        recipients.iter()
+            .filter(|&recipient| self.subscribers.get_mut(recipient)
+                .is_some_and(|subscriber| subscriber.known_players.insert(player_id.to_string())))
+            .cloned()
+            .collect()
+    }
+
+    /// Sends chunks that have just become ready to everyone looking at them who doesn't have
+    /// them yet.
+    pub fn send_chunks(&mut self, chunks: &[CompressedChunk]) {
+        let mut to_send: HashMap<String, Vec<&CompressedChunk>> = HashMap::new();
+        for chunk in chunks {
+            for recipient in self.interested_in(&chunk.rect()) {
+                to_send.entry(recipient).or_default().push(chunk);
+            }
+        }
+        for (recipient, chunks) in to_send {
+            let Some(subscriber) = self.subscribers.get_mut(&recipient) else { continue };
+            if subscriber.lagged {
+                continue;
+            }
+            let chunks: Vec<_> = chunks.into_iter()
+                .filter(|chunk| Self::remember_chunk(subscriber, chunk.position, chunk.version))
+                .map(|chunk| &chunk.message)
+                .collect();
+            if !chunks.is_empty() {
+                Self::try_send(&recipient, subscriber, ServerMessageBundle::from_message_bytes(chunks));
+            }
+        }
+    }
+
+    fn send_to(&mut self, recipients: &[String], message: ServerMessage) {
+        if recipients.is_empty() {
+            return;
+        }
+        let message = ServerMessageBundle(vec![message]).to_bytes();
+        for recipient in recipients {
+            let Some(subscriber) = self.subscribers.get_mut(recipient) else { continue };
+            if subscriber.lagged {
+                continue;
+            }
+            Self::try_send(recipient, subscriber, message.clone());
+        }
+    }
+
+    fn try_send(recipient: &str, subscriber: &mut Subscriber, message: Vec<u8>) {
+        if let Err(TrySendError::Full(_)) = subscriber.tx.try_send(Message::Binary(message)) {
+            info!("{recipient} has fallen behind");
+            subscriber.lagged = true;
+            subscriber.resync.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::ws::Message;
+    use std::collections::HashMap;
+    use tokio::sync::mpsc::{channel, Receiver};
+    use world::player::Player;
+    use world::{ChatChannel, ChatMessage, Chunk, ChunkPosition, ChunkTiles, Event, Position, Rect, ServerMessage, ServerMessageBundle, Tile};
+    use crate::interest::{check_area, Subscriptions, MAX_VIEWPORT};
+    use crate::regions::CompressedChunk;
+
+    fn received(rx: &mut Receiver<Message>) -> Vec<ServerMessage> {
+        let mut result = vec![];
+        while let Ok(Message::Binary(bytes)) = rx.try_recv() {
+            result.extend(ServerMessageBundle::from_compressed(&bytes).unwrap().0);
+        }
+        result
+    }
+
+    fn flag(player_id: &str, at: Position) -> Event {
+        Event::Flag { player_id: player_id.to_string(), at }
+    }
+
+    #[test]
+    fn events_only_go_to_clients_looking_at_them() {
+        let mut subscriptions = Subscriptions::default();
+        let (near_tx, mut near_rx) = channel(16);
+        let (far_tx, mut far_rx) = channel(16);
+        subscriptions.join("near", near_tx);
+        subscriptions.join("far", far_tx);
+        subscriptions.set_viewport("near", Rect::from_center_and_size(Position(0, 0), 100, 100));
+        subscriptions.set_viewport("far", Rect::from_center_and_size(Position(100_000, 0), 100, 100));
+
+        subscriptions.send_event(flag("near", Position(10, 10)), &HashMap::new());
+        assert_eq!(received(&mut near_rx).len(), 1);
+        assert!(received(&mut far_rx).is_empty());
+
+        // The player who acted always hears about it
+        subscriptions.send_event(flag("far", Position(-10, -10)), &HashMap::new());
+        assert_eq!(received(&mut near_rx).len(), 1);
+        assert_eq!(received(&mut far_rx).len(), 1);
+    }
+
+    #[test]
+    fn filled_chunks_go_to_clients_looking_at_them() {
+        let mut subscriptions = Subscriptions::default();
+        let (near_tx, mut near_rx) = channel(16);
+        let (far_tx, mut far_rx) = channel(16);
+        subscriptions.join("near", near_tx);
+        subscriptions.join("far", far_tx);
+        subscriptions.set_viewport("near", Rect::from_center_and_size(Position(0, 0), 100, 100));
+        subscriptions.set_viewport("far", Rect::from_center_and_size(Position(100_000, 0), 100, 100));
+        let chunk = |x: i32| CompressedChunk::new(&Chunk::from_position_and_tiles(ChunkPosition::new(x, 0), ChunkTiles::default()));
+
+        subscriptions.send_chunks(&[chunk(0), chunk(16), chunk(1000)]);
+        assert_eq!(received(&mut near_rx).len(), 2);
+        assert!(received(&mut far_rx).is_empty());
+        // Nothing has changed since they were sent
+        subscriptions.send_chunks(&[chunk(0)]);
+        assert!(received(&mut near_rx).is_empty());
+    }
+
+    #[test]
+    fn cursors_are_batched_and_only_go_to_clients_that_want_them() {
+        let mut subscriptions = Subscriptions::default();
+        let (watcher_tx, mut watcher_rx) = channel(16);
+        let (old_tx, mut old_rx) = channel(16);
+        let (mover_tx, mut mover_rx) = channel(16);
+        subscriptions.join("watcher", watcher_tx);
+        subscriptions.join("old", old_tx);
+        subscriptions.join("mover", mover_tx);
+        for player_id in ["watcher", "old", "mover"] {
+            subscriptions.set_viewport(player_id, Rect::from_center_and_size(Position(0, 0), 100, 100));
+        }
+        subscriptions.want_cursors("watcher", true);
+        subscriptions.want_cursors("mover", true);
+
+        for x in 0..10 {
+            subscriptions.move_cursor("mover", Position(x, 0));
+        }
+        subscriptions.move_cursor("old", Position(1000, 0));
+        subscriptions.send_cursors(&HashMap::new());
+        let mover = Player { position: Position(9, 0), ..Player::new("mover".to_string()) };
+        assert_eq!(received(&mut watcher_rx), vec![ServerMessage::Cursors(vec![mover])]);
+        assert!(received(&mut old_rx).is_empty());
+        assert!(received(&mut mover_rx).is_empty());
+        // Nothing has moved since
+        subscriptions.send_cursors(&HashMap::new());
+        assert!(received(&mut watcher_rx).is_empty());
+
+        // The watcher was told about the mover, so it hears when they go
+        subscriptions.leave("mover");
+        assert_eq!(received(&mut watcher_rx), vec![ServerMessage::Disconnected("mover".to_string())]);
+    }
+
+    #[test]
+    fn players_are_introduced_before_their_events_and_cursors() {
+        let mut subscriptions = Subscriptions::default();
+        let (watcher_tx, mut watcher_rx) = channel(16);
+        let (named_tx, _named_rx) = channel(16);
+        subscriptions.join("watcher", watcher_tx);
+        subscriptions.join("named", named_tx);
+        subscriptions.set_viewport("watcher", Rect::from_center_and_size(Position(0, 0), 100, 100));
+        subscriptions.want_cursors("watcher", true);
+        let named = Player { name: "Named".to_string(), colour: 7, ..Player::new("named".to_string()) };
+        let players = HashMap::from([("named".to_string(), named.clone())]);
+
+        subscriptions.move_cursor("named", Position(1, 1));
+        subscriptions.send_cursors(&players);
+        let moved = Player { position: Position(1, 1), ..Player::new("named".to_string()) };
+        assert_eq!(received(&mut watcher_rx), vec![ServerMessage::Player(named.clone()), ServerMessage::Cursors(vec![moved])]);
+
+        // Only the first time
+        subscriptions.send_event(flag("named", Position(2, 2)), &players);
+        assert!(matches!(received(&mut watcher_rx)[..], [ServerMessage::Event(_)]));
+
+        let (late_tx, mut late_rx) = channel(16);
+        subscriptions.join("late", late_tx);
+        subscriptions.set_viewport("late", Rect::from_center_and_size(Position(0, 0), 100, 100));
+        subscriptions.send_event(flag("named", Position(3, 3)), &players);
+        assert!(matches!(&received(&mut late_rx)[..], [ServerMessage::Player(player), ServerMessage::Event(_)] if *player == named));
+    }
+
+    #[test]
+    fn nearby_chat_only_goes_to_clients_looking_at_the_sender() {
+        let mut subscriptions = Subscriptions::default();
+        let (near_tx, mut near_rx) = channel(16);
+        let (far_tx, mut far_rx) = channel(16);
+        let (old_tx, mut old_rx) = channel(16);
+        subscriptions.join("near", near_tx);
+        subscriptions.join("far", far_tx);
+        subscriptions.join("old", old_tx);
+        subscriptions.set_viewport("near", Rect::from_center_and_size(Position(0, 0), 100, 100));
+        subscriptions.set_viewport("far", Rect::from_center_and_size(Position(100_000, 0), 100, 100));
+        subscriptions.set_viewport("old", Rect::from_center_and_size(Position(0, 0), 100, 100));
+        subscriptions.want_chat("near", true);
+        subscriptions.want_chat("far", true);
+        let chat = |channel, text: &str| ChatMessage {
+            channel,
+            player_id: "far".to_string(),
+            name: "Far".to_string(),
+            position: Position(10, 10),
+            time: 0,
+            text: text.to_string(),
+        };
+
+        // The sender's cursor is near, even though they're looking somewhere else
+        let nearby = chat(ChatChannel::Nearby, "Over here");
+        subscriptions.send_chat(nearby.clone());
+        assert_eq!(received(&mut near_rx), vec![ServerMessage::Chat(nearby.clone())]);
+        assert_eq!(received(&mut far_rx), vec![ServerMessage::Chat(nearby)]);
+        assert!(received(&mut old_rx).is_empty());
+
+        let global = chat(ChatChannel::Global, "Hello everyone");
+        subscriptions.send_chat(global.clone());
+        assert_eq!(received(&mut near_rx).len(), 1);
+        assert!(received(&mut old_rx).is_empty());
+
+        let (new_tx, _new_rx) = channel(16);
+        subscriptions.join("new", new_tx);
+        assert!(!subscriptions.wants_chat("new"), "it can't read chat");
+        subscriptions.want_chat("new", true);
+        assert!(subscriptions.wants_chat("new"));
+    }
+
+    #[test]
+    fn pings_go_to_clients_looking_near_them() {
+        let mut subscriptions = Subscriptions::default();
+        let (near_tx, mut near_rx) = channel(16);
+        let (far_tx, mut far_rx) = channel(16);
+        let (old_tx, mut old_rx) = channel(16);
+        subscriptions.join("near", near_tx);
+        subscriptions.join("far", far_tx);
+        subscriptions.join("old", old_tx);
+        // Just off the edge of what near can see
+        subscriptions.set_viewport("near", Rect::from_center_and_size(Position(200, 0), 100, 100));
+        subscriptions.set_viewport("far", Rect::from_center_and_size(Position(100_000, 0), 100, 100));
+        subscriptions.set_viewport("old", Rect::from_center_and_size(Position(0, 0), 100, 100));
+        for player_id in ["near", "far"] {
+            subscriptions.want_markers(player_id, true);
+        }
+
+        subscriptions.send_ping("far", Position(0, 0));
+        let ping = ServerMessage::Ping { player_id: "far".to_string(), position: Position(0, 0) };
+        assert_eq!(received(&mut near_rx), vec![ping.clone()]);
+        assert_eq!(received(&mut far_rx), vec![ping]);
+        assert!(received(&mut old_rx).is_empty());
+
+        subscriptions.send_marker_change(ServerMessage::MarkerRemoved(3));
+        assert_eq!(received(&mut near_rx), vec![ServerMessage::MarkerRemoved(3)]);
+        assert_eq!(received(&mut far_rx), vec![ServerMessage::MarkerRemoved(3)]);
+        assert!(received(&mut old_rx).is_empty());
+    }
+
+    #[test]
+    fn disconnects_go_to_clients_that_knew_the_player() {
+        let mut subscriptions = Subscriptions::default();
+        let (watcher_tx, mut watcher_rx) = channel(16);
+        let (stranger_tx, mut stranger_rx) = channel(16);
+        let (leaver_tx, _leaver_rx) = channel(16);
+        subscriptions.join("watcher", watcher_tx);
+        subscriptions.join("stranger", stranger_tx);
+        subscriptions.join("leaver", leaver_tx);
+        subscriptions.set_viewport("watcher", Rect::from_center_and_size(Position(0, 0), 100, 100));
+        subscriptions.set_viewport("stranger", Rect::from_center_and_size(Position(50_000, 0), 100, 100));
+
+        subscriptions.send_player(Player::new("leaver".to_string()));
+        // The leaver moves away from everyone before leaving
+        subscriptions.send_event(flag("leaver", Position(-50_000, 0)), &HashMap::new());
+        received(&mut watcher_rx);
+
+        subscriptions.leave("leaver");
+        assert_eq!(received(&mut watcher_rx), vec![ServerMessage::Disconnected("leaver".to_string())]);
+        assert!(received(&mut stranger_rx).is_empty());
+    }
+
+    #[test]
+    fn checking_areas() {
+        assert!(check_area(&Rect::from_top_left_and_size(Position(-100, -100), 8192, 10)).is_ok());
+        assert!(check_area(&Rect::from_top_left_and_size(Position(-100, -100), 8193, 10)).is_err());
+        assert!(check_area(&Rect { left: 10, top: 0, right: 0, bottom: 10 }).is_err());
+        assert!(check_area(&Rect { left: i32::MIN, top: 0, right: i32::MAX, bottom: 10 }).is_err());
+    }
+
+    #[test]
+    fn moving_the_viewport() {
+        let mut subscriptions = Subscriptions::default();
+        let (tx, mut rx) = channel(16);
+        subscriptions.join("player", tx);
+        let first = Rect::from_top_left_and_size(Position(0, 0), 100, 100);
+        assert_eq!(subscriptions.set_viewport("player", first), vec![first]);
+        // Moving right only needs the new columns
+        let second = Rect::from_top_left_and_size(Position(50, 0), 100, 100);
+        assert_eq!(subscriptions.set_viewport("player", second), vec![Rect::from_top_left_and_size(Position(100, 0), 50, 100)]);
+        assert!(subscriptions.set_viewport("player", second).is_empty());
+
+        subscriptions.send_event(flag("other", Position(10, 10)), &HashMap::new());
+        assert!(received(&mut rx).is_empty());
+        subscriptions.send_event(flag("other", Position(140, 10)), &HashMap::new());
+        assert_eq!(received(&mut rx).len(), 1);
+
+        let huge = Rect::from_top_left_and_size(Position(0, 0), 100_000, 10);
+        subscriptions.set_viewport("player", huge);
+        assert_eq!(subscriptions.subscribers["player"].area.unwrap().width(), MAX_VIEWPORT);
+    }
+
+    #[tokio::test]
+    async fn lagging_clients_are_resynced() {
+        let mut subscriptions = Subscriptions::default();
+        let (tx, mut rx) = channel(2);
+        let resync = subscriptions.join("slow", tx);
+        let viewport = Rect::from_center_and_size(Position(0, 0), 100, 100);
+        subscriptions.set_viewport("slow", viewport);
+        for i in 0..5 {
+            subscriptions.send_event(flag("other", Position(i, 0)), &HashMap::new());
+        }
+        // Only what fit in the queue arrives, and the connection is told to resync
+        assert_eq!(received(&mut rx).len(), 2);
+        resync.notified().await;
+        subscriptions.send_event(flag("other", Position(10, 0)), &HashMap::new());
+        assert!(received(&mut rx).is_empty());
+
+        let mut players = HashMap::new();
+        let mut other = Player::new("other".to_string());
+        other.position = Position(10, 0);
+        players.insert("other".to_string(), other.clone());
+        assert_eq!(subscriptions.resync("slow", &players), (Some(viewport), vec![other]));
+        subscriptions.send_event(flag("other", Position(11, 0)), &HashMap::new());
+        assert_eq!(received(&mut rx).len(), 1);
+    }
+
+    #[test]
+    fn only_changed_chunks_are_sent() {
+        let mut subscriptions = Subscriptions::default();
+        let (tx, _rx) = channel(16);
+        subscriptions.join("player", tx);
+        let chunk = |x: i32, changes: usize| {
+            let mut chunk = Chunk::from_position_and_tiles(ChunkPosition::new(x, 0), ChunkTiles::default());
+            for _ in 0..changes {
+                chunk.set_tile(Position(x, 0), Tile::empty().with_flag());
+            }
+            CompressedChunk::new(&chunk)
+        };
+        let positions = |chunks: Vec<CompressedChunk>| chunks.iter().map(|chunk| chunk.position.0).collect::<Vec<_>>();
+
+        subscriptions.hold("player", vec![(ChunkPosition::new(0, 0), 1), (ChunkPosition::new(16, 0), 1)]);
+        let sent = subscriptions.changed_chunks("player", vec![chunk(0, 1), chunk(16, 2), chunk(32, 0)]);
+        assert_eq!(positions(sent), vec![16, 32]);
+        // It's been sent them now
+        let sent = subscriptions.changed_chunks("player", vec![chunk(0, 1), chunk(16, 2), chunk(32, 0)]);
+        assert!(sent.is_empty());
+        let sent = subscriptions.changed_chunks("player", vec![chunk(0, 1), chunk(16, 3)]);
+        assert_eq!(positions(sent), vec![16]);
+    }
+}
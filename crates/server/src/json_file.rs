@@ -0,0 +1,115 @@
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+/// A list of things kept in a JSON file in the data directory so that they survive restarts,
+/// like the players' identities or the markers. Whatever holds them says when they've changed,
+/// and the file is written again the next time the server saves, until a write succeeds.
+pub struct JsonFile<T> {
+    pub path: PathBuf,
+    /// How many times things have changed
+    changes: u64,
+    /// How many of those changes the file is known to have
+    saved: u64,
+    items: PhantomData<T>,
+}
+
+/// What to write to a file, taken while holding whatever the things are kept in and turned
+/// into JSON after letting go of it
+pub struct Unsaved<T> {
+    pub path: PathBuf,
+    pub items: Vec<T>,
+    /// What to pass to [JsonFile::saved] once it's been written
+    pub changes: u64,
+}
+
+impl<T: Serialize + DeserializeOwned> JsonFile<T> {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, changes: 0, saved: 0, items: PhantomData }
+    }
+
+    /// Reads what was saved, or nothing if the file isn't there yet
+    pub fn load(&self) -> io::Result<Vec<T>> {
+        let json = match std::fs::read(&self.path) {
+            Ok(json) => json,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        serde_json::from_slice(&json).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn mark_changed(&mut self) {
+        self.changes += 1;
+    }
+
+    /// What to write, if anything has changed since the file was last written. `items` is only
+    /// called if it has.
+    pub fn unsaved(&self, items: impl FnOnce() -> Vec<T>) -> Option<Unsaved<T>> {
+        if self.saved == self.changes {
+            return None;
+        }
+        Some(Unsaved { path: self.path.clone(), items: items(), changes: self.changes })
+    }
+
+    /// Notes that what [JsonFile::unsaved] gave has been written. Anything that changed since
+    /// is still unsaved.
+    pub fn saved(&mut self, changes: u64) {
+        self.saved = self.saved.max(changes);
+    }
+}
+
+impl<T: Serialize> Unsaved<T> {
+    /// Gives what to pass to [JsonFile::saved]
+    pub async fn write(self) -> io::Result<u64> {
+        let json = serde_json::to_vec(&self.items).map_err(io::Error::other)?;
+        write_atomically(&self.path, json).await?;
+        Ok(self.changes)
+    }
+}
+
+/// Writes the file next to where it's going first, so that a crash part of the way through
+/// never leaves it half written.
+pub async fn write_atomically(path: &Path, contents: Vec<u8>) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut file = tokio::fs::File::create(&temporary).await?;
+    file.write_all(&contents).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json_file::{JsonFile, Unsaved};
+    use crate::testing::TempDir;
+
+    #[tokio::test]
+    async fn written_until_the_changes_have_been_saved() {
+        let dir = TempDir::new("json-file");
+        let mut file = JsonFile::<u32>::new(dir.join("numbers.json"));
+        assert_eq!(file.load().unwrap(), Vec::<u32>::new(), "there's no file yet");
+        assert!(file.unsaved(|| unreachable!()).is_none());
+
+        file.mark_changed();
+        let failing = JsonFile::<u32>::new(dir.join("missing").join("numbers.json"));
+        let unsaved = file.unsaved(|| vec![1, 2]).unwrap();
+        assert!(Unsaved { path: failing.path, ..unsaved }.write().await.is_err());
+        assert!(file.unsaved(|| vec![1, 2]).is_some(), "it's tried again after failing");
+
+        let unsaved = file.unsaved(|| vec![1, 2]).unwrap();
+        // Something changes while it's being written
+        file.mark_changed();
+        file.saved(unsaved.write().await.unwrap());
+        let changes = file.unsaved(|| vec![1, 2, 3]).unwrap().write().await.unwrap();
+        file.saved(changes);
+        assert!(file.unsaved(|| unreachable!()).is_none(), "nothing has changed since");
+        assert_eq!(JsonFile::<u32>::new(dir.join("numbers.json")).load().unwrap(), vec![1, 2, 3]);
+
+        std::fs::write(dir.join("numbers.json"), "not JSON").unwrap();
+        assert!(file.load().is_err());
+    }
+}
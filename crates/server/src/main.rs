@@ -1,300 +1,1207 @@
+mod chat;
+mod config;
+mod data_dir;
 mod eventlog;
+mod feed;
+mod identities;
+mod interest;
+mod json_file;
+mod names;
+mod markers;
+mod rate_limit;
+mod session;
+mod regions;
+#[cfg(test)]
+mod testing;
+mod tools;
 
-use std::collections::VecDeque;
-use axum::extract::{Path, State};
+use std::collections::{HashMap, HashSet};
+use axum::extract::{self, ConnectInfo, Path, State};
 use axum::extract::{ws::WebSocket, WebSocketUpgrade};
-use axum::http::{header, HeaderValue, StatusCode};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
+use axum::response::sse::{KeepAlive, Sse};
 use axum::routing::get;
 use axum::{body, Router};
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use futures_util::{stream::{SplitSink, SplitStream}, SinkExt, StreamExt};
 use include_dir::{include_dir, Dir};
+use serde::Serialize;
 use serde_json::Value;
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
-use axum::extract::ws::Message;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum::extract::ws::{close_code, CloseFrame, Message};
 use mime_guess::mime::TEXT_HTML;
+use mime_guess::Mime;
 use tokio::net::TcpListener;
-use log::{error, info, trace};
-use tokio::sync::broadcast::{Receiver, Sender};
-use tokio::sync::{broadcast, Mutex};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use log::{debug, error, info, trace};
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use world::ClientMessage::{self, *};
 use world::player::Player;
-use world::{ServerMessage, ServerMessageBundle};
-use world::World;
+use world::rules;
+use world::{negotiate, ChatMessage, Event, Position, ServerMessage, ServerMessageBundle, CHAT, CURSORS, MARKERS, CURSOR_INTERVAL_MS, MAX_HOLDING, PROTOCOL_VERSION, TILES_BPE_1, UNVERSIONED_PROTOCOL_VERSION};
 use world::Rect;
-use crate::eventlog::{EventLogReader, EventLogWriter, EventReadResult, SourcedEvent};
+use crate::chat::ChatHistory;
+use crate::config::ServerConfig;
+use crate::data_dir::{DataArgs, DataDir, DataPaths};
+use crate::eventlog::{EventLog, EventLogReader, EventLogWriter, SourcedEvent};
+use crate::feed::{FeedFilter, FeedQuery};
+use crate::identities::{Identities, Identity};
+use crate::interest::{check_area, Subscriptions};
+use crate::json_file::Unsaved;
+use crate::markers::Markers;
+use crate::names::NameRules;
+use crate::rate_limit::{Budget, ConnectionLimiter, IpLimits, Verdict};
+use crate::session::{Session, Sessions};
+use crate::regions::{split_by_region, LockedRegions, NotLocked, Regions};
 
 #[derive(Parser)]
 struct Cli {
-    #[arg(short, long, value_name = "PORT NUMBER")]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short, long, value_name = "PORT NUMBER", global = true)]
     port: Option<u16>,
 
-    #[arg(short, long, value_name = "PATH")]
-    world_file: Option<PathBuf>
+    /// Address to listen on
+    #[arg(short, long, value_name = "IP ADDRESS", global = true)]
+    bind: Option<IpAddr>,
+
+    /// Serve the web client from this directory instead of the files built into the binary
+    #[arg(long, value_name = "PATH", global = true)]
+    static_dir: Option<PathBuf>,
+
+    /// Path to the event log. If it's relative, it's relative to the data directory.
+    #[arg(short, long, value_name = "PATH", global = true, env = "SWEEPER_WORLD_FILE")]
+    world_file: Option<PathBuf>,
+
+    /// Directory to keep the event log and other data files in
+    #[arg(short, long, value_name = "PATH", global = true, env = "SWEEPER_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Directory to keep snapshots in. If it's relative, it's relative to the data directory.
+    #[arg(long, value_name = "PATH", global = true, env = "SWEEPER_SNAPSHOT_DIR")]
+    snapshot_dir: Option<PathBuf>,
+
+    /// File to keep players' IDs, names and stats in. If it's relative, it's relative to the data
+    /// directory.
+    #[arg(long, value_name = "PATH", global = true, env = "SWEEPER_PLAYERS_FILE")]
+    players_file: Option<PathBuf>,
+
+    /// File to keep the named markers on the map in. If it's relative, it's relative to the data
+    /// directory.
+    #[arg(long, value_name = "PATH", global = true, env = "SWEEPER_MARKERS_FILE")]
+    markers_file: Option<PathBuf>,
+
+    /// File to keep the latest global chat messages in. If it's relative, it's relative to the
+    /// data directory.
+    #[arg(long, value_name = "PATH", global = true, env = "SWEEPER_CHAT_FILE")]
+    chat_file: Option<PathBuf>,
+
+    /// TOML file to read settings from. Defaults to sweeper.toml if it exists.
+    #[arg(short, long, value_name = "PATH", global = true, env = "SWEEPER_CONFIG")]
+    config: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Host the game. This is what happens if no command is given.
+    Serve,
+    /// Load the event log and report how long it took
+    Replay(LogArgs),
+    /// Check that the event log can be read, and that its chunks match the world generator
+    Verify(LogArgs),
+    /// Count the events in the event log by type and by region
+    Stats {
+        /// Width and height of each region, in tiles
+        #[arg(short, long, default_value_t = 1024)]
+        region_size: i32,
+        /// How many of the busiest regions to list
+        #[arg(short, long, default_value_t = 20)]
+        top: usize,
+        #[command(flatten)]
+        log: LogArgs,
+    },
+    /// Load the event log and print the tiles in a rect as text
+    Render {
+        #[arg(allow_negative_numbers = true)]
+        left: i32,
+        #[arg(allow_negative_numbers = true)]
+        top: i32,
+        #[arg(allow_negative_numbers = true)]
+        right: i32,
+        #[arg(allow_negative_numbers = true)]
+        bottom: i32,
+        #[command(flatten)]
+        log: LogArgs,
+    },
+    /// Time sending the chunks around spawn to lots of players at once
+    Bench {
+        /// How many players are looking at spawn
+        #[arg(long, default_value_t = 1000)]
+        players: usize,
+        /// Width and height of the area around spawn that's been played in, in tiles
+        #[arg(short, long, default_value_t = 256)]
+        size: i32,
+    },
+}
+
+#[derive(Args)]
+struct LogArgs {
+    /// Path to the event log. Defaults to the event log in the data directory.
+    log: Option<PathBuf>,
 }
 
 #[derive(Clone)]
 struct AppState {
-    world: Arc<Mutex<World>>,
-    broadcast_tx: Arc<Sender<Message>>,
-    event_log_writer: Arc<UnboundedSender<SourcedEvent>>,
+    regions: Arc<Regions>,
+    players: Arc<Mutex<HashMap<String, Player>>>,
+    /// Everyone who has ever played, and hasn't been gone too long
+    identities: Arc<Mutex<Identities>>,
+    names: Arc<NameRules>,
+    /// The named markers on the map
+    markers: Arc<Mutex<Markers>>,
+    /// The latest global chat messages, for players who join later
+    chat: Arc<Mutex<ChatHistory>>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    ip_limits: Arc<IpLimits>,
+    /// Players' sessions, which can be resumed for a while after their connection drops
+    sessions: Arc<Sessions>,
+    event_log: EventLog,
+    static_dir: Option<Arc<PathBuf>>,
+    /// Cancelled when the server starts shutting down
+    shutdown: CancellationToken,
+    /// Every open WebSocket connection
+    connections: TaskTracker,
+    reconnect_delay_secs: u16,
+    client_queue_capacity: usize,
+    /// Different every time the server starts, because chunk versions start again from zero
+    epoch: u64,
 }
 
+/// How long to wait for clients to be told that the server is shutting down before giving up
+/// on them.
+const CLIENT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client whose connection closed or dropped has to come back and carry on where it
+/// left off, before everyone else is told it has gone.
+const RESUME_WINDOW: Duration = Duration::from_secs(15);
+
+/// How often clients are pinged, so that a connection that has quietly died is noticed...
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// ...once nothing at all has been heard from the client for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// How often players' identities, the markers and the chat are written to disk, if they've changed
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
     
     env_logger::init();
 
-    let mut world = World::new();
-    let start_time = Instant::now();
-    if let Ok(reader) = EventLogReader::open("eventlog".into()).await {
-        let mut events = reader.events();
-        let mut events_read = 0;
-        while let Some(event) = events.next().await {
-            events_read += 1;
-            if events_read % 1000 == 0 {
-                info!("Read {} events", events_read);
-            }
-            trace!("read");
-            match event {
-                EventReadResult::Ok(event) => {
-                    match event {
-                        SourcedEvent::Click(position) => {
-                            world.click(position, "");
-                        }
-                        SourcedEvent::DoubleClick(position) => {
-                            world.double_click(position, "");
-                        }
-                        SourcedEvent::Flag(position) |
-                        SourcedEvent::Unflag(position) => {
-                            // TODO: should probably handle this properly
-                            world.flag(position, "");
-                        }
-                        SourcedEvent::ChunkGenerated(position, mines) => {
-                            if world.get_chunk(position.position()).is_none() {
-                                let chunk = mines.to_chunk(position);
-                                world.insert_chunk(chunk);
-                            }
-                        }
-                    }
-                }
-                EventReadResult::Invalid(text) => {
-                    error!("Skipping invalid event: {}", text)
+    let mut config = ServerConfig::load(cli.config.as_deref()).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1);
+    });
+    if let Some(port) = cli.port {
+        config.server.port = port;
+    }
+    if let Some(bind) = cli.bind {
+        config.server.bind = bind;
+    }
+    if let Some(static_dir) = cli.static_dir {
+        config.server.static_dir = Some(static_dir);
+    }
+    if let Err(err) = config.validate() {
+        eprintln!("Invalid configuration: {err}");
+        std::process::exit(1);
+    }
+    let paths = DataPaths::resolve(DataArgs {
+        dir: cli.data_dir,
+        event_log: cli.world_file,
+        snapshots: cli.snapshot_dir,
+        players: cli.players_file,
+        markers: cli.markers_file,
+        chat: cli.chat_file,
+    }, &config.data);
+    let log_path = |LogArgs { log }| log.unwrap_or_else(|| paths.event_log.clone());
+
+    let succeeded = match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            match paths.clone().open() {
+                Ok(data_dir) => serve(config, data_dir).await,
+                Err(err) => {
+                    eprintln!("Unable to open data directory: {err}");
+                    false
                 }
-                EventReadResult::Eof => {}
             }
         }
-        info!("{} events read in {:?}", events_read, Instant::now() - start_time);
+        Command::Replay(log) => tools::replay(log_path(log), &config.world).await,
+        Command::Verify(log) => tools::verify(log_path(log), &config.world).await,
+        Command::Stats { region_size, top, log } => {
+            tools::stats(log_path(log), region_size.max(1), top).await
+        }
+        Command::Render { left, top, right, bottom, log } => {
+            tools::render(log_path(log), &config.world, Rect { left, top, right, bottom }).await
+        }
+        Command::Bench { players, size } => tools::bench(&config.world, players, size.max(16)).await,
+    };
+    if !succeeded {
+        std::process::exit(1);
+    }
+}
+
+/// Returns whether the server started
+async fn serve(config: ServerConfig, data_dir: DataDir) -> bool {
+    info!("Using data directory {:?}", data_dir.paths.dir);
+    let identities = match Identities::load(data_dir.paths.players.clone()) {
+        Ok(identities) => identities,
+        Err(err) => {
+            error!("Unable to read {:?}: {err}", data_dir.paths.players);
+            return false;
+        }
+    };
+    info!("{} players known", identities.len());
+    let mut markers = match Markers::load(data_dir.paths.markers.clone()) {
+        Ok(markers) => markers,
+        Err(err) => {
+            error!("Unable to read {:?}: {err}", data_dir.paths.markers);
+            return false;
+        }
+    };
+    // Markers go with the players who put them there
+    markers.forget_unless(|player_id| identities.knows(player_id));
+    info!("{} markers on the map", markers.len());
+    let chat = match ChatHistory::load(data_dir.paths.chat.clone()) {
+        Ok(chat) => chat,
+        Err(err) => {
+            error!("Unable to read {:?}: {err}", data_dir.paths.chat);
+            return false;
+        }
+    };
+    info!("{} chat messages kept", chat.len());
+    let names = match &config.players.blocked_words {
+        Some(path) => match NameRules::load(path) {
+            Ok(names) => names,
+            Err(err) => {
+                error!("Unable to read {path:?}: {err}");
+                return false;
+            }
+        },
+        None => NameRules::default(),
+    };
+    let mut world = config.world.new_world();
+    if let Ok(reader) = EventLogReader::open(data_dir.paths.event_log.clone()).await {
+        let summary = reader.replay(&mut world).await;
+        info!("{} events read in {:?}", summary.events_read, summary.elapsed);
     } else {
         info!("No event log found, starting a new world.");
     }
-    world.players.clear();
     world.generated_chunks.clear();
+    world.filled_chunks.clear();
+    let regions = Regions::from_world(world);
+    info!("World split into {} regions", regions.len());
 
-    let mut event_log_writer = EventLogWriter::new("eventlog".into()).await
+    let event_log_writer = EventLogWriter::new(data_dir.paths.event_log.clone()).await
         .expect("Unable to create event log writer");
+    let event_log = EventLog::spawn(event_log_writer, config.data.durability, &config.world);
 
-    let (event_tx, mut event_rx) = tokio::sync::mpsc::unbounded_channel();
-    tokio::spawn(async move {
-        let mut events = vec![];
-        while event_rx.recv_many(&mut events, 1024).await != 0 {
-            let events = std::mem::take(&mut events);
-            for event in events {
-                event_log_writer.write(event).await.unwrap()
-            }
-            event_log_writer.flush().await.unwrap();
-            tokio::time::sleep(Duration::from_secs(5)).await;
-        }
-    });
-    
-    let (broadcast_tx, _) = broadcast::channel(1024);
-    
     let app = AppState {
-        world: Arc::new(Mutex::new(world)),
-        broadcast_tx: Arc::new(broadcast_tx),
-        event_log_writer: Arc::new(event_tx),
+        regions: Arc::new(regions),
+        players: Default::default(),
+        identities: Arc::new(Mutex::new(identities)),
+        names: Arc::new(names),
+        markers: Arc::new(Mutex::new(markers)),
+        chat: Arc::new(Mutex::new(chat)),
+        subscriptions: Default::default(),
+        ip_limits: Arc::new(IpLimits::new(config.limits)),
+        sessions: Default::default(),
+        event_log: event_log.clone(),
+        static_dir: config.server.static_dir.clone().map(Arc::new),
+        shutdown: CancellationToken::new(),
+        connections: TaskTracker::new(),
+        reconnect_delay_secs: config.server.reconnect_delay_secs,
+        client_queue_capacity: config.server.client_queue_capacity,
+        epoch: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64,
     };
+    let shutdown = app.shutdown.clone();
+    let connections = app.connections.clone();
+    let to_save = app.clone();
+
+    tokio::spawn(send_cursors(app.clone()));
+    tokio::spawn(save_periodically(app.clone()));
 
     let router: Router<> = Router::new()
         .route("/", get(root))
         .route("/ws", get(ws_upgrade_handler))
-        .with_state(app)
+        .route("/ws/spectate", get(spectate_upgrade_handler))
+        .route("/api/events", get(events_feed))
         .route("/static/*path", get(static_path))
+        .with_state(app)
         ;
-    let port = cli.port.unwrap_or(80);
-    info!("Hosting on port {port}");
-    let addr = SocketAddr::from(([0,0,0,0], port));
+    let addr = config.server.address();
+    info!("Hosting on {addr}");
     let tcp = TcpListener::bind(&addr).await.unwrap();
 
-    axum::serve(tcp, router).await.unwrap();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown.cancel();
+        }
+    });
+
+    // This stops accepting new connections, then every open WebSocket sees the shutdown token
+    // and says goodbye to its client.
+    axum::serve(tcp, router.into_make_service_with_connect_info::<SocketAddr>())
+        .tcp_nodelay(true)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await.unwrap();
+
+    connections.close();
+    if !connections.is_empty() {
+        info!("Waiting for {} clients to disconnect", connections.len());
+        if tokio::time::timeout(CLIENT_DRAIN_TIMEOUT, connections.wait()).await.is_err() {
+            error!("{} clients didn't disconnect in time", connections.len());
+        }
+    }
+
+    event_log.shutdown().await;
+    save(&to_save).await;
+    info!("Shut down");
+    true
+}
+
+/// Sends out where everyone's cursors have moved to, a batch at a time
+async fn send_cursors(app: AppState) {
+    let mut interval = tokio::time::interval(Duration::from_millis(CURSOR_INTERVAL_MS));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => app.subscriptions.lock().unwrap().send_cursors(&app.players.lock().unwrap()),
+            _ = app.shutdown.cancelled() => return,
+        }
+    }
+}
+
+async fn save_periodically(app: AppState) {
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => save(&app).await,
+            _ = app.shutdown.cancelled() => return,
+        }
+    }
+}
+
+/// Writes the players' identities, the markers and the chat, if they've changed
+async fn save(app: &AppState) {
+    // Each is turned into JSON after letting go of its lock, which actions wait for
+    let players = app.identities.lock().unwrap().unsaved();
+    if let Some(changes) = write(players).await {
+        app.identities.lock().unwrap().file.saved(changes);
+    }
+    let (markers, forgotten) = {
+        let identities = app.identities.lock().unwrap();
+        let mut markers = app.markers.lock().unwrap();
+        // Markers go with the players who put them there
+        let forgotten = markers.forget_unless(|player_id| identities.knows(player_id));
+        (markers.unsaved(), forgotten)
+    };
+    if !forgotten.is_empty() {
+        let mut subscriptions = app.subscriptions.lock().unwrap();
+        for id in forgotten {
+            subscriptions.send_marker_change(ServerMessage::MarkerRemoved(id));
+        }
+    }
+    if let Some(changes) = write(markers).await {
+        app.markers.lock().unwrap().file.saved(changes);
+    }
+    let chat = app.chat.lock().unwrap().unsaved();
+    if let Some(changes) = write(chat).await {
+        app.chat.lock().unwrap().file.saved(changes);
+    }
 }
 
-async fn ws_upgrade_handler(ws: WebSocketUpgrade, State(app): State<AppState>) -> Response {
-    ws.on_upgrade(|socket| handle_socket(socket, app))
+/// Gives which changes were saved, if it was written. If it couldn't be, it's tried again the
+/// next time.
+async fn write<T: Serialize>(unsaved: Option<Unsaved<T>>) -> Option<u64> {
+    let unsaved = unsaved?;
+    let path = unsaved.path.clone();
+    match unsaved.write().await {
+        Ok(changes) => Some(changes),
+        Err(err) => {
+            error!("Unable to save {path:?}: {err}");
+            None
+        }
+    }
 }
 
-async fn handle_socket(ws: WebSocket, app: AppState) {
-    let (ws_tx, ws_rx) = ws.split();
+/// Waits for Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await
+            .expect("Unable to listen for Ctrl+C");
+    };
 
-    let player_id = app.world.lock().await.new_player_id();
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Unable to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    let (client_tx, client_rx) = tokio::sync::mpsc::unbounded_channel();
-    let client_tx_clone = client_tx.clone();
-    {
-        let broadcast_rx = app.broadcast_tx.subscribe();
-        tokio::spawn(async move {
-            recv_broadcast(broadcast_rx, client_tx_clone).await;
-        });
-        tokio::spawn(async move {
-            send_client_messages(ws_tx, client_rx).await;
-        });
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
-    
-    recv_from_client(ws_rx, client_tx, app.broadcast_tx.clone(), app.event_log_writer, app.world.clone(), &player_id).await;
+    info!("Shutting down...");
+}
+
+async fn ws_upgrade_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    State(app): State<AppState>,
+) -> Response {
+    let connections = app.connections.clone();
+    ws.on_upgrade(move |socket| connections.track_future(handle_socket(socket, address.ip(), app)))
+}
+
+/// Why a connection stopped
+enum Ended {
+    /// The connection was lost without the client saying goodbye, so it might come back
+    Dropped,
+    /// The client closed the connection
+    Closed,
+    /// The client was disconnected for misbehaving
+    Kicked,
+    /// The client resumed its session on a new connection
+    TakenOver,
+    ShuttingDown,
+}
+
+async fn handle_socket(ws: WebSocket, ip: IpAddr, app: AppState) {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let mut limiter = ConnectionLimiter::new(ip, app.ip_limits.clone());
+    let Some((features, first_message)) = greet(&mut ws_tx, &mut ws_rx, &app).await else { return };
+
+    // A client that lost its connection says which session it had in its first message
+    let mut first_message = decode_client_message(first_message);
+    let mut resumed_after = None;
+    let (session, taken_over) = match &first_message {
+        Some(Resume { key, last }) => match app.sessions.resume(key) {
+            Some(resumed) => {
+                info!("{} resumed their session", resumed.0.player_id);
+                resumed_after = Some(*last);
+                first_message = None;
+                resumed
+            }
+            None => {
+                // It's been too long, so start again as the same player. The resume key is
+                // the player's token.
+                let session = start_session(&app, identify(&app, key));
+                first_message = Some(Connected);
+                session
+            }
+        },
+        Some(Identify(token)) => start_session(&app, identify(&app, token)),
+        _ => {
+            let identity = app.identities.lock().unwrap().create();
+            start_session(&app, identity)
+        }
+    };
+    let Some(client_rx) = session.take_receiver().await else {
+        error!("{}'s old connection didn't stop sending to them", session.player_id);
+        park(&app, &session, &taken_over);
+        return;
+    };
+    // Only once the old connection has stopped, so that nothing it sent is missed
+    let mut replay = vec![];
+    if let Some(last) = resumed_after {
+        match session.replay.lock().unwrap().since(last) {
+            Some(missed) => replay = missed,
+            None => session.resync.notify_one(),
+        }
+    }
+    want_features(&app, &session.player_id, &features);
+
+    let stop_sending = CancellationToken::new();
+    let sender = tokio::spawn(send_client_messages(ws_tx, client_rx, session.clone(), replay, stop_sending.clone()));
+
+    let ended = match first_message {
+        Some(message) => match handle_message(message, &app, &session, &mut limiter).await {
+            Ok(()) => recv_from_client(ws_rx, &app, &session, &mut limiter, &taken_over).await,
+            Err(ended) => ended,
+        },
+        None => recv_from_client(ws_rx, &app, &session, &mut limiter, &taken_over).await,
+    };
 
-    {
-        let mut world = app.world.lock().await;
-        world.players.remove(&player_id);
+    match ended {
+        Ended::ShuttingDown => {
+            say_restarting(&app, &session).await;
+            let _ = sender.await;
+        }
+        Ended::Kicked => {
+            // The close message has already been queued
+            let _ = sender.await;
+            if app.sessions.end(&session, &taken_over) {
+                leave(&app, &session.player_id);
+            }
+        }
+        // The new connection is waiting for the queue
+        Ended::TakenOver => {
+            stop_sending.cancel();
+            if let Ok(client_rx) = sender.await {
+                session.return_receiver(client_rx);
+            }
+        }
+        // Closing might just be the page reloading, so the player gets as long to come back
+        Ended::Closed | Ended::Dropped => {
+            stop_sending.cancel();
+            if let Ok(client_rx) = sender.await {
+                session.return_receiver(client_rx);
+            }
+            park(&app, &session, &taken_over);
+        }
+    }
+}
+
+/// Waits for the client to come back, and removes the player if it doesn't. Does nothing if
+/// the client has already come back on another connection.
+fn park(app: &AppState, session: &Arc<Session>, taken_over: &CancellationToken) {
+    let Some(parking) = app.sessions.park(session, taken_over) else { return };
+    let app = app.clone();
+    let resume_key = session.resume_key.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(RESUME_WINDOW).await;
+        if let Some(session) = app.sessions.expire(&resume_key, parking) {
+            leave(&app, &session.player_id);
+        }
+    });
+}
+
+async fn spectate_upgrade_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    State(app): State<AppState>,
+) -> Response {
+    let connections = app.connections.clone();
+    ws.on_upgrade(move |socket| connections.track_future(handle_spectator(socket, address.ip(), app)))
+}
+
+/// Sends chunks, events and everything else in the area a spectator is looking at, like for a
+/// player, but a spectator isn't a player. It has no cursor, nobody is told about it, and it
+/// can't do anything but look around. Spectators can't resume, so their connections are
+/// forgotten as soon as they end.
+async fn handle_spectator(ws: WebSocket, ip: IpAddr, app: AppState) {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let mut limiter = ConnectionLimiter::new(ip, app.ip_limits.clone());
+    let Some((features, first_message)) = greet(&mut ws_tx, &mut ws_rx, &app).await else { return };
+
+    // Can't be mistaken for a player, whose IDs are base64
+    let spectator_id = format!("spectator:{}", Player::random_id());
+    let (client_tx, client_rx) = tokio::sync::mpsc::channel(app.client_queue_capacity);
+    let resync = app.subscriptions.lock().unwrap().join(&spectator_id, client_tx.clone());
+    let session = Session::spectator(spectator_id, client_tx, client_rx, resync);
+    let Some(client_rx) = session.take_receiver().await else { return };
+    want_features(&app, &session.player_id, &features);
+    info!("{} started watching", session.player_id);
+    // Spectators can't resume, so nothing takes them over
+    let taken_over = CancellationToken::new();
+
+    let stop_sending = CancellationToken::new();
+    let sender = tokio::spawn(send_client_messages(ws_tx, client_rx, session.clone(), vec![], stop_sending.clone()));
+
+    let ended = match decode_client_message(first_message) {
+        Some(message) => match handle_message(message, &app, &session, &mut limiter).await {
+            Ok(()) => recv_from_client(ws_rx, &app, &session, &mut limiter, &taken_over).await,
+            Err(ended) => ended,
+        },
+        None => recv_from_client(ws_rx, &app, &session, &mut limiter, &taken_over).await,
+    };
+
+    match ended {
+        Ended::ShuttingDown => say_restarting(&app, &session).await,
+        Ended::Kicked => {}
+        Ended::TakenOver | Ended::Closed | Ended::Dropped => stop_sending.cancel(),
     }
-    let _ = app.broadcast_tx.send(Message::Binary(ServerMessageBundle(vec![ServerMessage::Disconnected(player_id)]).to_bytes()));
+    let _ = sender.await;
+    app.subscriptions.lock().unwrap().leave(&session.player_id);
+}
+
+/// Whether a spectator is allowed to send the message. They can only ask to see things.
+fn spectators_can_send(message: &ClientMessage) -> bool {
+    matches!(message, Query(_) | Viewport(_) | Holding(_) | Ack(_) | Hello { .. })
+}
+
+/// Tells the subscriptions which optional messages the client wants, from the features it
+/// agreed to
+fn want_features(app: &AppState, player_id: &str, features: &[String]) {
+    let wants = |wanted: &str| features.iter().any(|feature| feature == wanted);
+    let mut subscriptions = app.subscriptions.lock().unwrap();
+    subscriptions.want_cursors(player_id, wants(CURSORS));
+    subscriptions.want_chat(player_id, wants(CHAT));
+    subscriptions.want_markers(player_id, wants(MARKERS));
 }
 
-async fn recv_broadcast(
-    mut broadcast_rx: Receiver<Message>,
-    client_tx: UnboundedSender<Message>,
-) {
-    while let Ok(msg) = broadcast_rx.recv().await {
-        if client_tx.send(msg).is_err() {
-            return;
+/// Tells the client that the server is restarting and how long to wait, then closes the
+/// connection
+async fn say_restarting(app: &AppState, session: &Session) {
+    let goodbye = ServerMessageBundle(vec![ServerMessage::Restarting(app.reconnect_delay_secs)]).to_bytes();
+    session.tx.send(Message::Binary(goodbye)).await.unwrap_or_default();
+    session.tx.send(Message::Close(Some(CloseFrame {
+        code: close_code::RESTART,
+        reason: "Server restarting".into(),
+    }))).await.unwrap_or_default();
+}
+
+/// Agrees on a protocol version and features with a client that has just connected, if it says
+/// hello. Gives the features, and the first message after the hello, or `None` if the client
+/// has gone or was turned away.
+async fn greet(
+    ws_tx: &mut SplitSink<WebSocket, Message>,
+    ws_rx: &mut SplitStream<WebSocket>,
+    app: &AppState,
+) -> Option<(Vec<String>, Message)> {
+    let mut first_message = wait_for_message(ws_rx, app).await?;
+    let hello = match decode_client_message(first_message.clone()) {
+        Some(Hello { version, features }) => Some((version, features)),
+        _ => None,
+    };
+    let said_hello = hello.is_some();
+    // Clients from before there were versions don't say hello
+    let (version, features) = hello.unwrap_or((UNVERSIONED_PROTOCOL_VERSION, vec![TILES_BPE_1.to_string()]));
+    let features = match negotiate(version, &features) {
+        Ok(features) => {
+            if said_hello {
+                let hello = ServerMessage::Hello { version: PROTOCOL_VERSION, features: features.clone() };
+                let hello = ServerMessageBundle(vec![hello]).to_bytes();
+                ws_tx.send(Message::Binary(hello)).await.ok()?;
+            }
+            features
         }
+        Err(reason) => {
+            info!("Turning away a client: {reason}");
+            let error = ServerMessageBundle(vec![ServerMessage::Error(reason)]).to_bytes();
+            let _ = ws_tx.send(Message::Binary(error)).await;
+            let _ = ws_tx.send(Message::Close(Some(CloseFrame {
+                code: close_code::PROTOCOL,
+                reason: "Incompatible client".into(),
+            }))).await;
+            return None;
+        }
+    };
+    if said_hello {
+        first_message = wait_for_message(ws_rx, app).await?;
+    }
+    Some((features, first_message))
+}
+
+/// The next message from a client that's only just connected, or `None` if it's gone
+async fn wait_for_message(ws_rx: &mut SplitStream<WebSocket>, app: &AppState) -> Option<Message> {
+    let message = tokio::select! {
+        message = ws_rx.next() => message,
+        _ = app.shutdown.cancelled() => return None,
+    };
+    match message {
+        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => None,
+        Some(Ok(message)) => Some(message),
+    }
+}
+
+/// The player that the token was given to, or a new player if the token isn't known. If its
+/// player is already playing somewhere else, this is a guest who won't be remembered.
+fn identify(app: &AppState, token: &str) -> Identity {
+    let mut identities = app.identities.lock().unwrap();
+    let Some(identity) = identities.find(token) else { return identities.create() };
+    if app.sessions.take_player(&identity.player_id).is_some() {
+        // They came back before anyone was told that they'd gone
+        info!("{} is back", identity.player_id);
+    } else if app.players.lock().unwrap().contains_key(&identity.player_id) {
+        return identities.create_guest();
     }
+    identity
+}
+
+/// Adds the player, with a queue of messages to send to them. Also gives the token that says
+/// when another connection has taken the session over.
+fn start_session(app: &AppState, identity: Identity) -> (Arc<Session>, CancellationToken) {
+    let Identity { player_id, token, position, name, colour, .. } = identity;
+    app.players.lock().unwrap().insert(player_id.clone(), Player { player_id: player_id.clone(), position, name, colour });
+    let (client_tx, client_rx) = tokio::sync::mpsc::channel(app.client_queue_capacity);
+    let resync = app.subscriptions.lock().unwrap().join(&player_id, client_tx.clone());
+    let session = Session::new(player_id, token, client_tx, client_rx, resync);
+    let taken_over = app.sessions.connect(session.clone());
+    (session, taken_over)
+}
+
+/// Removes the player, and tells everyone who could see them that they've gone
+fn leave(app: &AppState, player_id: &str) {
+    app.identities.lock().unwrap().left(player_id);
+    app.players.lock().unwrap().remove(player_id);
+    app.subscriptions.lock().unwrap().leave(player_id);
 }
 
+/// Sends everything queued up for the client until told to stop, numbering each bundle on the
+/// way out. Gives back the queue, so that the next connection can carry on from it.
 async fn send_client_messages(
-    mut client_tx: SplitSink<WebSocket, Message>,
-    mut client_rx: UnboundedReceiver<Message>,
-) {
+    mut ws_tx: SplitSink<WebSocket, Message>,
+    mut client_rx: Receiver<Message>,
+    session: Arc<Session>,
+    replay: Vec<Vec<u8>>,
+    stop: CancellationToken,
+) -> Receiver<Message> {
+    for bundle in replay {
+        if ws_tx.feed(Message::Binary(bundle)).await.is_err() {
+            return client_rx;
+        }
+    }
+    if ws_tx.flush().await.is_err() {
+        return client_rx;
+    }
+
     let mut messages = vec![];
-    while client_rx.recv_many(&mut messages, 1024).await != 0 {
-        let messages = std::mem::take(&mut messages);
-        for message in messages {
-            if client_tx.feed(message).await.is_err() {
-                return; // Disconnected
-            }
+    loop {
+        let received = tokio::select! {
+            received = client_rx.recv_many(&mut messages, 1024) => received,
+            _ = stop.cancelled() => return client_rx,
+        };
+        if received == 0 {
+            return client_rx;
         }
-        if client_tx.flush().await.is_err() {
-            return;
+        // Numbered before anything is sent, so that if the connection drops part of the way
+        // through, these can still be replayed
+        let messages: Vec<_> = {
+            let mut replay = session.replay.lock().unwrap();
+            messages.drain(..)
+                .map(|message| match message {
+                    Message::Binary(bundle) => Message::Binary(replay.number(&bundle)),
+                    message => message,
+                })
+                .collect()
+        };
+        // Writing to a connection that has quietly died can take a long time, and a new
+        // connection might be waiting for the queue. Anything that wasn't sent can be replayed.
+        let sent = async {
+            for message in messages {
+                let closing = matches!(message, Message::Close(_));
+                if ws_tx.feed(message).await.is_err() {
+                    return false; // Disconnected
+                }
+                if closing {
+                    let _ = ws_tx.flush().await;
+                    return false;
+                }
+            }
+            ws_tx.flush().await.is_ok()
+        };
+        let sent = tokio::select! {
+            sent = sent => sent,
+            _ = stop.cancelled() => false,
+        };
+        if !sent {
+            return client_rx;
         }
     }
 }
 
+/// Clients used to send JSON, and newer ones send binary. Both are understood for now.
+fn decode_client_message(message: Message) -> Option<ClientMessage> {
+    match message {
+        Message::Text(text) => serde_json::from_str::<Value>(&text).ok().and_then(ClientMessage::decode),
+        Message::Binary(bytes) => ClientMessage::from_compressed(&bytes)
+            .inspect_err(|error| debug!("{error}"))
+            .ok(),
+        _ => None,
+    }
+}
+
+/// Handles messages from the client until the connection ends, and pings it so that a connection
+/// that has quietly died ends too
 async fn recv_from_client(
-    mut client_rx: SplitStream<WebSocket>,
-    client_tx: UnboundedSender<Message>,
-    broadcast_tx: Arc<Sender<Message>>,
-    event_log_writer: Arc<UnboundedSender<SourcedEvent>>,
-    world: Arc<Mutex<World>>,
-    player_id: &str,
-) {
-    while let Some(Ok(msg)) = client_rx.next().await {
-        let mut to_broadcast = vec![];
-        let mut to_client = vec![];
-        let mut new_chunks = VecDeque::new();
-        let mut event = None;
-        match msg {
-            Message::Text(text) => {
-                if let Ok(message) = serde_json::from_str::<Value>(&text) {
-                    if let Some(message) = ClientMessage::decode(message) {
-                        let mut world = world.lock().await;
-                        match message {
-                            // Click, Flag, and DoubleClick return a safety rect to send to the client
-                            // in case nothing has been updated.
-                            Click(position) => {
-                                event = world.click(position, player_id);
-                                if event.is_none() {
-                                    let rect = world.get_rect(&Rect::from_center_and_size(position, 1, 1));
-                                    to_client.push(ServerMessage::Rect(rect));
-                                }
-                            }
-                            Flag(position) => {
-                                event = world.flag(position, player_id);
-                                if event.is_none() {
-                                    let rect = world.get_rect(&Rect::from_center_and_size(position, 1, 1));
-                                    to_client.push(ServerMessage::Rect(rect));
-                                }
-                            }
-                            DoubleClick(position) => {
-                                event = world.double_click(position, player_id);
-                                if event.is_none() {
-                                    let rect = world.get_rect(&Rect::from_center_and_size(position, 3, 3));
-                                    to_client.push(ServerMessage::Rect(rect));
-                                }
-                            }
-                            Connected => {
-                                for player in world.players.values() {
-                                    to_client.push(ServerMessage::Player(player.clone()))
-                                }
-                                let player = Player::new(player_id.to_string());
-                                to_client.push(ServerMessage::Welcome(player.clone()));
-                                to_broadcast.push(ServerMessage::Player(player));
-                            },
-                            Query(rect) => {
-                                for chunk in world.query_chunks(&rect)
-                                    .iter().map(|chunk_id| &world.chunks[*chunk_id]) {
-                                    if chunk.should_send() {
-                                        // TODO: cloning all these chunks might be expensive
-                                        to_client.push(ServerMessage::Chunk(chunk.clone()));
-                                    }
-                                }
-                            }
-                        }
-                        std::mem::swap(&mut world.generated_chunks, &mut new_chunks);
-                    }
+    mut ws_rx: SplitStream<WebSocket>,
+    app: &AppState,
+    session: &Session,
+    limiter: &mut ConnectionLimiter,
+    taken_over: &CancellationToken,
+) -> Ended {
+    let mut ping = tokio::time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_heard = Instant::now();
+    loop {
+        // Only stop between messages, so that every action that has been applied to the world
+        // also gets logged.
+        let msg = tokio::select! {
+            msg = ws_rx.next() => msg,
+            _ = session.resync.notified() => {
+                resync_client(app, session).await;
+                continue;
+            }
+            _ = ping.tick() => {
+                if last_heard.elapsed() >= IDLE_TIMEOUT {
+                    info!("{} hasn't been heard from for {IDLE_TIMEOUT:?}", session.player_id);
+                    return Ended::Dropped;
                 }
+                // If the queue is full, the client has fallen behind and is already being
+                // dealt with
+                let _ = session.tx.try_send(Message::Ping(vec![]));
+                continue;
             }
-            Message::Binary(_) => {}
-            Message::Ping(_) => {}
-            Message::Pong(_) => {}
-            Message::Close(_) => return
+            _ = taken_over.cancelled() => return Ended::TakenOver,
+            _ = app.shutdown.cancelled() => return Ended::ShuttingDown,
+        };
+        let Some(Ok(msg)) = msg else { return Ended::Dropped };
+        last_heard = Instant::now();
+        if let Message::Close(_) = msg {
+            return Ended::Closed;
         }
+        let is_data = matches!(msg, Message::Text(_) | Message::Binary(_));
+        match decode_client_message(msg) {
+            Some(message) => {
+                if let Err(ended) = handle_message(message, app, session, limiter).await {
+                    return ended;
+                }
+            }
+            // Otherwise anyone could send garbage as fast as they like
+            None if is_data && !limiter.invalid_message() => {
+                info!("Disconnecting {} for sending too many invalid messages", session.player_id);
+                session.tx.send(Message::Close(Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "Too many invalid messages".into(),
+                }))).await.unwrap_or_default();
+                return Ended::Kicked;
+            }
+            None => {}
+        }
+    }
+}
 
-        for (position, mines) in new_chunks {
-            event_log_writer.send(SourcedEvent::ChunkGenerated(position, mines)).unwrap_or_default();
+async fn handle_message(
+    message: ClientMessage,
+    app: &AppState,
+    session: &Session,
+    limiter: &mut ConnectionLimiter,
+) -> Result<(), Ended> {
+    let player_id = session.player_id.as_str();
+    let client_tx = &session.tx;
+    let budget = match message {
+        Click(_) | Flag(_) | DoubleClick(_) | SetProfile { .. } => Some(Budget::Action),
+        Query(_) | Viewport(_) | Holding(_) => Some(Budget::Query),
+        CursorMoved(_) => Some(Budget::Cursor),
+        Chat { .. } | Ping(_) | AddMarker { .. } | RemoveMarker(_) => Some(Budget::Chat),
+        Connected | Identify(_) | Ack(_) | Resume { .. } | Hello { .. } => None,
+    };
+    match budget.map(|budget| limiter.check(budget)) {
+        None | Some(Verdict::Allow) => {}
+        // Another cursor movement will be along soon, so there's no need to tell the client
+        Some(Verdict::Throttle(_)) if budget == Some(Budget::Cursor) => return Ok(()),
+        Some(Verdict::Throttle(wait)) => {
+            let ms = (wait.as_millis() + 1).min(u16::MAX as u128) as u16;
+            let message = ServerMessageBundle(vec![ServerMessage::Throttled(ms)]).to_bytes();
+            client_tx.send(Message::Binary(message)).await.unwrap_or_default();
+            return Ok(());
         }
-        
-        if let Some(event) = event {
-            event_log_writer.send(SourcedEvent::from_event(&event))
-                .unwrap_or_default();
-            to_broadcast.push(ServerMessage::Event(event));
+        Some(Verdict::Disconnect) => {
+            info!("Disconnecting {player_id} for sending too many messages");
+            client_tx.send(Message::Close(Some(CloseFrame {
+                code: close_code::POLICY,
+                reason: "Too many messages".into(),
+            }))).await.unwrap_or_default();
+            return Err(Ended::Kicked);
+        }
+    }
+
+    if session.spectating && !spectators_can_send(&message) {
+        let message = ServerMessageBundle(vec![ServerMessage::Error("Spectators can only watch".to_string())]).to_bytes();
+        client_tx.send(Message::Binary(message)).await.unwrap_or_default();
+        return Ok(());
+    }
+
+    let mut to_client = vec![];
+    match message {
+        // Click, Flag, and DoubleClick return a safety rect to send to the client
+        // in case nothing has been updated.
+        Click(position) => {
+            to_client.extend(act(app, player_id, position, 1, |locked| {
+                rules::click(locked, position, player_id)
+            }).await);
+        }
+        Flag(position) => {
+            to_client.extend(act(app, player_id, position, 1, |locked| {
+                rules::flag(locked, position, player_id)
+            }).await);
+        }
+        DoubleClick(position) => {
+            to_client.extend(act(app, player_id, position, 3, |locked| {
+                rules::double_click(locked, position, player_id)
+            }).await);
+        }
+        Connected | Identify(_) => {
+            // Other players are sent along with the parts of the world that
+            // the client asks for
+            let player = app.players.lock().unwrap().get(player_id).cloned()
+                .unwrap_or_else(|| Player::new(player_id.to_string()));
+            let (stats, guest) = {
+                let identities = app.identities.lock().unwrap();
+                (identities.stats(player_id), identities.is_guest(player_id))
+            };
+            to_client.push(ServerMessage::Welcome {
+                player: player.clone(),
+                // So that a second tab doesn't take over the first one's player next time
+                token: if guest { String::new() } else { session.resume_key.clone() },
+                stats,
+                epoch: app.epoch,
+            });
+            to_client.push(ServerMessage::ResumeKey(session.resume_key.clone()));
+            let mut subscriptions = app.subscriptions.lock().unwrap();
+            if subscriptions.wants_chat(player_id) {
+                to_client.extend(app.chat.lock().unwrap().recent().cloned().map(ServerMessage::Chat));
+            }
+            if subscriptions.wants_markers(player_id) {
+                to_client.extend(app.markers.lock().unwrap().all().cloned().map(ServerMessage::Marker));
+            }
+            subscriptions.send_player(player);
+        },
+        Query(rect) => {
+            match check_area(&rect) {
+                Ok(()) => send_chunks(app, session, vec![rect]).await,
+                Err(reason) => to_client.push(ServerMessage::Error(reason)),
+            }
+        }
+        Viewport(viewport) => {
+            if let Err(reason) = check_area(&viewport) {
+                to_client.push(ServerMessage::Error(reason));
+            } else {
+                // Start listening before looking at the world, so that
+                // nothing that happens in between gets missed
+                let (newly_visible, players) = {
+                    let mut subscriptions = app.subscriptions.lock().unwrap();
+                    let newly_visible = subscriptions.set_viewport(player_id, viewport);
+                    let players = subscriptions.unknown_players_in(player_id, &viewport, &app.players.lock().unwrap());
+                    (newly_visible, players)
+                };
+                for player in players {
+                    to_client.push(ServerMessage::Player(player));
+                }
+                send_chunks(app, session, newly_visible).await;
+            }
+        }
+        Holding(versions) => {
+            if versions.len() > MAX_HOLDING {
+                to_client.push(ServerMessage::Error(format!("Can't hold more than {MAX_HOLDING} chunks at once")));
+            } else {
+                app.subscriptions.lock().unwrap().hold(player_id, versions);
+            }
+        }
+        CursorMoved(position) => {
+            if let Some(player) = app.players.lock().unwrap().get_mut(player_id) {
+                player.position = position;
+            }
+            app.identities.lock().unwrap().moved(player_id, position);
+            app.subscriptions.lock().unwrap().move_cursor(player_id, position);
+        }
+        SetProfile { name, colour } => match app.names.check(&name) {
+            Ok(name) => {
+                app.identities.lock().unwrap().set_profile(player_id, &name, colour);
+                let player = app.players.lock().unwrap().get_mut(player_id).map(|player| {
+                    player.name = name;
+                    player.colour = colour;
+                    player.clone()
+                });
+                if let Some(player) = player {
+                    app.subscriptions.lock().unwrap().send_player(player);
+                }
+            }
+            Err(reason) => to_client.push(ServerMessage::Error(reason)),
+        },
+        Chat { channel, text } => match ChatMessage::clean_text(&text) {
+            Ok(text) => {
+                // Said by whoever the player is, from wherever they are right now
+                let player = app.players.lock().unwrap().get(player_id).cloned()
+                    .unwrap_or_else(|| Player::new(player_id.to_string()));
+                let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+                let message = ChatMessage {
+                    channel,
+                    player_id: player.player_id,
+                    name: player.name,
+                    position: player.position,
+                    time,
+                    text,
+                };
+                app.chat.lock().unwrap().add(message.clone());
+                app.subscriptions.lock().unwrap().send_chat(message);
+            }
+            Err(reason) => to_client.push(ServerMessage::Error(reason)),
+        },
+        Ping(position) => app.subscriptions.lock().unwrap().send_ping(player_id, position),
+        AddMarker { position, name } => {
+            let added = app.names.check_marker(&name)
+                .and_then(|name| app.markers.lock().unwrap().add(player_id, position, name));
+            match added {
+                Ok(marker) => {
+                    // Otherwise the marker would go when the server restarts
+                    app.identities.lock().unwrap().keep(player_id);
+                    app.subscriptions.lock().unwrap().send_marker_change(ServerMessage::Marker(marker));
+                }
+                Err(reason) => to_client.push(ServerMessage::Error(reason)),
+            }
+        }
+        RemoveMarker(id) => {
+            let removed = app.markers.lock().unwrap().remove(player_id, id);
+            match removed {
+                Ok(()) => app.subscriptions.lock().unwrap().send_marker_change(ServerMessage::MarkerRemoved(id)),
+                Err(reason) => to_client.push(ServerMessage::Error(reason)),
+            }
         }
+        Ack(number) => session.replay.lock().unwrap().ack(number),
+        // These only make sense at the start of a connection
+        Resume { .. } | Hello { .. } => {}
+    }
+
+    if !to_client.is_empty() {
+        let message = ServerMessageBundle(to_client).to_bytes();
+        client_tx.send(Message::Binary(message)).await.unwrap_or_default();
+    }
+    Ok(())
+}
+
+/// Catches up a client that fell behind, by telling it so and sending its viewport again.
+async fn resync_client(app: &AppState, session: &Session) {
+    // Making room first means that nothing can get in before the client is told
+    let Ok(permit) = session.tx.reserve().await else { return };
+    let (viewport, players, wants_markers) = {
+        let mut subscriptions = app.subscriptions.lock().unwrap();
+        let (viewport, players) = subscriptions.resync(&session.player_id, &app.players.lock().unwrap());
+        (viewport, players, subscriptions.wants_markers(&session.player_id))
+    };
+    let mut messages = vec![ServerMessage::Stale];
+    messages.extend(players.into_iter().map(ServerMessage::Player));
+    // Some markers might have come and gone in the messages it missed
+    if wants_markers {
+        messages.extend(app.markers.lock().unwrap().all().cloned().map(ServerMessage::Marker));
+    }
+    permit.send(Message::Binary(ServerMessageBundle(messages).to_bytes()));
+    if let Some(viewport) = viewport {
+        send_chunks(app, session, vec![viewport]).await;
+    }
+}
 
-        if !to_broadcast.is_empty() {
-            let message = ServerMessageBundle(to_broadcast).to_bytes();
-            if broadcast_tx.send(Message::Binary(message)).is_err() {
-                error!("Unable to broadcast message");
+/// Sends the chunks in the rects one region at a time, as fast as the client takes them, so
+/// that a big query never has to clone its whole area at once and each bundle stays a
+/// reasonable size.
+async fn send_chunks(app: &AppState, session: &Session, rects: Vec<Rect>) {
+    let mut sent = HashSet::new();
+    for page in rects.iter().flat_map(split_by_region) {
+        let chunks: Vec<_> = app.regions.query_chunks(&page).await.into_iter()
+            .filter(|chunk| sent.insert(chunk.position))
+            .collect();
+        let chunks = app.subscriptions.lock().unwrap().changed_chunks(&session.player_id, chunks);
+        if !chunks.is_empty() {
+            let message = ServerMessageBundle::from_message_bytes(chunks.iter().map(|chunk| &chunk.message));
+            if session.tx.send(Message::Binary(message)).await.is_err() {
+                return;
             }
         }
+    }
+}
 
-        if !to_client.is_empty() {
-            let message = ServerMessageBundle(to_client).to_bytes();
-            client_tx.send(Message::Binary(message)).unwrap_or_default();
+/// Streams the events that happen in the world as server-sent events, for tools that don't
+/// speak the WebSocket protocol. A client that reconnects with the `Last-Event-ID` header
+/// carries on from where it was.
+async fn events_feed(extract::Query(query): extract::Query<FeedQuery>, headers: HeaderMap, State(app): State<AppState>) -> Response {
+    let filter = match FeedFilter::from_query(&query) {
+        Ok(filter) => filter,
+        Err(reason) => return (StatusCode::BAD_REQUEST, reason).into_response(),
+    };
+    let last_id = match headers.get("last-event-id") {
+        Some(id) => match id.to_str().ok().and_then(|id| id.parse().ok()) {
+            Some(id) => Some(id),
+            None => return (StatusCode::BAD_REQUEST, "Last-Event-ID isn't an event ID").into_response(),
+        },
+        None => query.last_event_id,
+    };
+    let joined = match app.event_log.feed.join(last_id) {
+        Ok(joined) => joined,
+        Err(reason) => return (StatusCode::BAD_REQUEST, reason).into_response(),
+    };
+    let events = joined.stream(filter, app.shutdown.clone());
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// Runs a click, flag or double click, and logs what happened. The event is sent to everyone
+/// looking once it's been logged. If nothing changed, this gives the tiles around the position
+/// instead, to send to the player in case their client was out of date.
+async fn act(
+    app: &AppState,
+    player_id: &str,
+    position: Position,
+    safety_size: i32,
+    mut action: impl FnMut(&mut LockedRegions) -> Result<Option<Event>, NotLocked>,
+) -> Option<ServerMessage> {
+    app.players.lock().unwrap().entry(player_id.to_string())
+        .or_insert_with(|| Player::new(player_id.to_string()))
+        .position = position;
+    let safety_rect = Rect::from_center_and_size(position, safety_size, safety_size);
+    let (result, mut locked) = app.regions.act(position, |locked| {
+        Ok(match action(locked)? {
+            Some(event) => Ok(event),
+            None => Err(ServerMessage::Rect(locked.get_rect(&safety_rect)?)),
+        })
+    }).await;
+
+    let mut to_log: Vec<_> = locked.take_generated_chunks().into_iter()
+        .map(|(position, mines)| (SourcedEvent::ChunkGenerated(position, mines), None))
+        .collect();
+    if let Ok(event) = &result {
+        to_log.push((SourcedEvent::from_event(event), Some(event.clone())));
+    }
+    // Players looking at chunks that have only just been filled in were never sent them
+    let filled = locked.take_filled_chunks();
+    let (event, message) = match result {
+        Ok(event) => (Some(event), None),
+        Err(message) => (None, Some(message)),
+    };
+    let recorded = event.clone();
+    let (subscriptions, players) = (app.subscriptions.clone(), app.players.clone());
+    // Queued with the regions still locked, so that everyone is sent what happened to each chunk
+    // in the order it happened, however long each action waits for the log
+    app.event_log.append(to_log, move || {
+        let mut subscriptions = subscriptions.lock().unwrap();
+        subscriptions.send_chunks(&filled);
+        if let Some(event) = event {
+            subscriptions.send_event(event, &players.lock().unwrap());
         }
+    });
+    drop(locked);
+    // Not while the regions are locked, so that saving the identities never holds up actions
+    if let Some(event) = &recorded {
+        app.identities.lock().unwrap().record(player_id, event);
     }
+    message
 }
 
 static STATIC_DIR: Dir<'_> = include_dir!("crates/server/static");
 // Thanks to https://matze.github.io/axum-notes/notes/misc/serve_static_from_binary/index.html
-async fn static_path(Path(path): Path<String>) -> impl IntoResponse {
+async fn static_path(Path(path): Path<String>, State(app): State<AppState>) -> impl IntoResponse {
     let path = path.trim_start_matches('/');
     let mime_type = mime_guess::from_path(path).first_or(TEXT_HTML);
     trace!("Serving {path} as {mime_type}");
 
+    if let Some(static_dir) = &app.static_dir {
+        return serve_file_from_directory(static_dir, path, mime_type).await;
+    }
+
     match STATIC_DIR.get_file(path) {
         None => Response::builder()
             .status(StatusCode::NOT_FOUND)
@@ -338,6 +1245,214 @@ async fn static_path(Path(path): Path<String>) -> impl IntoResponse {
     }
 }
 
-async fn root() -> impl IntoResponse {
-    static_path(Path("index.html".to_string())).await
-}
\ No newline at end of file
+/// Serves a file from a directory on disk, as long as the path doesn't try to leave the directory.
+async fn serve_file_from_directory(static_dir: &std::path::Path, path: &str, mime_type: Mime) -> Response {
+    let relative_path = std::path::Path::new(path);
+    if relative_path.components().any(|component| !matches!(component, Component::Normal(_))) {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Body::empty())
+            .unwrap();
+    }
+    let path = static_dir.join(relative_path);
+    trace!("Serving file from filesystem: {:?}", path);
+    match tokio::fs::read(path).await {
+        Ok(contents) => Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_str(mime_type.as_ref()).unwrap(),
+            )
+            .body(body::Body::from(contents))
+            .unwrap(),
+        Err(_) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Body::empty())
+            .unwrap(),
+    }
+}
+
+async fn root(state: State<AppState>) -> impl IntoResponse {
+    static_path(Path("index.html".to_string()), state).await
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use axum::extract::ws::Message;
+    use axum::routing::get;
+    use axum::Router;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::{connect_async, tungstenite, MaybeTlsStream, WebSocketStream};
+    use tokio_util::sync::CancellationToken;
+    use tokio_util::task::TaskTracker;
+    use world::{rules, Chunk, ClientMessage, Position, Rect, ServerMessage, ServerMessageBundle, World, PROTOCOL_VERSION, TILES_BPE_1};
+    use crate::chat::ChatHistory;
+    use crate::config::{LimitsConfig, WorldConfig};
+    use crate::eventlog::{Durability, EventLog, EventLogWriter};
+    use crate::identities::Identities;
+    use crate::markers::Markers;
+    use crate::rate_limit::IpLimits;
+    use crate::regions::Regions;
+    use crate::testing::TempDir;
+    use crate::{act, ws_upgrade_handler, AppState};
+
+    /// A server with a new world, keeping its data in `dir`
+    async fn app(dir: &TempDir, durability: Durability) -> AppState {
+        let writer = EventLogWriter::new(dir.join("eventlog")).await.unwrap();
+        AppState {
+            regions: Arc::new(Regions::from_world(World::new())),
+            players: Default::default(),
+            identities: Arc::new(Mutex::new(Identities::new(dir.join("players.json")))),
+            names: Default::default(),
+            markers: Arc::new(Mutex::new(Markers::new(dir.join("markers.json")))),
+            chat: Arc::new(Mutex::new(ChatHistory::new(dir.join("chat.json")))),
+            subscriptions: Default::default(),
+            ip_limits: Arc::new(IpLimits::new(LimitsConfig::default())),
+            sessions: Default::default(),
+            event_log: EventLog::spawn(writer, durability, &WorldConfig::default()),
+            static_dir: None,
+            shutdown: CancellationToken::new(),
+            connections: TaskTracker::new(),
+            reconnect_delay_secs: 0,
+            client_queue_capacity: 1024,
+            epoch: 0,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn watchers_see_chunks_and_events_in_the_order_they_happened() {
+        for durability in [Durability::Always, Durability::Os] {
+            let dir = TempDir::new(&format!("main-order-{durability:?}"));
+            let app = app(&dir, durability).await;
+            let area = Rect::from_center_and_size(Position(0, 0), 96, 96);
+            let (tx, mut rx) = tokio::sync::mpsc::channel(1_000_000);
+            {
+                let mut subscriptions = app.subscriptions.lock().unwrap();
+                subscriptions.join("watcher", tx);
+                subscriptions.set_viewport("watcher", area);
+            }
+            // Players all over the area at once, so that chunks change straight after being
+            // filled in, by someone else
+            let players: Vec<_> = (0..4).map(|i| {
+                let app = app.clone();
+                tokio::spawn(async move {
+                    let player_id = format!("player{i}");
+                    for n in 0..300 {
+                        let at = Position((n * 37 + i * 11) % 96 - 48, (n * 53 + i * 29) % 96 - 48);
+                        if n % 3 == 0 {
+                            act(&app, &player_id, at, 1, |locked| rules::flag(locked, at, &player_id)).await;
+                        } else {
+                            act(&app, &player_id, at, 1, |locked| rules::click(locked, at, &player_id)).await;
+                        }
+                    }
+                })
+            }).collect();
+            for player in players {
+                player.await.unwrap();
+            }
+            app.event_log.shutdown().await;
+
+            // Do what the watcher's client would
+            let mut client = World::new();
+            let mut received = HashSet::new();
+            while let Ok(Message::Binary(bytes)) = rx.try_recv() {
+                for message in ServerMessageBundle::from_compressed(&bytes).unwrap().0 {
+                    match message {
+                        ServerMessage::Chunk(chunk) => {
+                            received.insert(chunk.position);
+                            client.insert_chunk(chunk);
+                        }
+                        ServerMessage::Event(event) => {
+                            client.apply_updated_rect(event.updated_rect());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            assert!(!received.is_empty());
+            for compressed in app.regions.query_chunks(&area).await {
+                let chunk = Chunk::from_compressed(&compressed.message).unwrap();
+                assert!(received.contains(&chunk.position), "{:?} was never sent", chunk.position);
+                let seen = client.get_chunk(chunk.position.position()).unwrap();
+                assert_eq!(seen.public_tiles(), chunk.public_tiles(), "{durability:?}: {:?} is out of date", chunk.position);
+            }
+        }
+    }
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Serves the app's WebSocket, and gives where to connect to it
+    async fn serve(app: &AppState) -> String {
+        let router = Router::new().route("/ws", get(ws_upgrade_handler)).with_state(app.clone());
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", tcp.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(tcp, router.into_make_service_with_connect_info::<SocketAddr>()).await
+        });
+        url
+    }
+
+    async fn send(client: &mut Client, message: ClientMessage) {
+        client.send(tungstenite::Message::binary(Vec::<u8>::from(&message))).await.unwrap();
+    }
+
+    /// Connects and says hello, then sends the first message
+    async fn connect(url: &str, first: ClientMessage) -> Client {
+        let (mut client, _) = connect_async(url).await.unwrap();
+        send(&mut client, ClientMessage::Hello { version: PROTOCOL_VERSION, features: vec![TILES_BPE_1.to_string()] }).await;
+        send(&mut client, first).await;
+        client
+    }
+
+    /// The next bundle, and its number if it has one
+    async fn receive(client: &mut Client) -> Option<(Option<u16>, Vec<ServerMessage>)> {
+        loop {
+            let next = tokio::time::timeout(Duration::from_secs(5), client.next()).await.expect("nothing was received");
+            match next {
+                Some(Ok(tungstenite::Message::Binary(bytes))) => {
+                    let (number, ServerMessageBundle(messages)) = ServerMessageBundle::from_compressed_with_number(&bytes).unwrap();
+                    return Some((number, messages));
+                }
+                Some(Ok(tungstenite::Message::Close(_))) | Some(Err(_)) | None => return None,
+                Some(Ok(_)) => continue,
+            }
+        }
+    }
+
+    /// The player and token that the server welcomed the client as, and the number of the
+    /// bundle it was in
+    async fn welcomed(client: &mut Client) -> (String, String, u16) {
+        loop {
+            let (number, messages) = receive(client).await.expect("the connection ended");
+            for message in messages {
+                if let ServerMessage::Welcome { player, token, .. } = message {
+                    return (player.player_id, token, number.unwrap());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn resuming_before_the_old_connection_has_ended_takes_the_session_over() {
+        let dir = TempDir::new("main-take-over");
+        let app = app(&dir, Durability::Os).await;
+        let url = serve(&app).await;
+        let mut old = connect(&url, ClientMessage::Connected).await;
+        let (player_id, token, last) = welcomed(&mut old).await;
+
+        // The client's connection dropped, but the server hasn't noticed yet
+        let mut new = connect(&url, ClientMessage::Resume { key: token.clone(), last }).await;
+        send(&mut new, ClientMessage::Connected).await;
+        let (resumed_as, resumed_token, number) = welcomed(&mut new).await;
+        assert_eq!((resumed_as, resumed_token), (player_id.clone(), token), "not a guest");
+        assert!(number > last, "the bundles carry on being numbered from the old connection");
+        while receive(&mut old).await.is_some() {}
+
+        assert_eq!(app.players.lock().unwrap().keys().collect::<Vec<_>>(), vec![&player_id]);
+        assert_eq!(app.identities.lock().unwrap().len(), 1);
+    }
+}
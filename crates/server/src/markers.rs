@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use world::{Marker, Position};
+use crate::json_file::{JsonFile, Unsaved};
+
+/// The most markers there can be on the map at once
+pub const MAX_MARKERS: usize = 1000;
+/// The most markers one player can have on the map at once
+pub const MAX_MARKERS_PER_PLAYER: usize = 10;
+
+/// The named markers on the map, kept in a JSON file in the data directory so that they
+/// survive restarts.
+pub struct Markers {
+    pub file: JsonFile<Marker>,
+    by_id: BTreeMap<u32, Marker>,
+}
+
+impl Markers {
+    pub fn new(path: PathBuf) -> Self {
+        Self { file: JsonFile::new(path), by_id: BTreeMap::new() }
+    }
+
+    /// Reads the markers from the file, or starts with none if there isn't one yet
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let mut markers = Self::new(path);
+        markers.by_id = markers.file.load()?.into_iter().map(|marker| (marker.id, marker)).collect();
+        Ok(markers)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &Marker> {
+        self.by_id.values()
+    }
+
+    /// Puts a marker on the map, unless there are too many already. The name should already
+    /// have been checked.
+    pub fn add(&mut self, player_id: &str, position: Position, name: String) -> Result<Marker, String> {
+        if self.by_id.len() >= MAX_MARKERS {
+            return Err("There are too many markers on the map already".to_string());
+        }
+        let placed = self.by_id.values().filter(|marker| marker.player_id == player_id).count();
+        if placed >= MAX_MARKERS_PER_PLAYER {
+            return Err(format!("You can't have more than {MAX_MARKERS_PER_PLAYER} markers, remove one first"));
+        }
+        let id = self.by_id.last_key_value().map_or(1, |(&id, _)| id.wrapping_add(1));
+        let marker = Marker { id, player_id: player_id.to_string(), position, name };
+        self.by_id.insert(id, marker.clone());
+        self.file.mark_changed();
+        Ok(marker)
+    }
+
+    /// Takes one of the player's markers off the map
+    pub fn remove(&mut self, player_id: &str, id: u32) -> Result<(), String> {
+        match self.by_id.get(&id) {
+            None => Err("That marker has already gone".to_string()),
+            Some(marker) if marker.player_id != player_id => Err("That marker isn't yours".to_string()),
+            Some(_) => {
+                self.by_id.remove(&id);
+                self.file.mark_changed();
+                Ok(())
+            }
+        }
+    }
+
+    /// Takes the markers of players who have been forgotten off the map, and gives their IDs
+    pub fn forget_unless(&mut self, known: impl Fn(&str) -> bool) -> Vec<u32> {
+        let forgotten: Vec<_> = self.by_id.values()
+            .filter(|marker| !known(&marker.player_id))
+            .map(|marker| marker.id)
+            .collect();
+        for id in &forgotten {
+            self.by_id.remove(id);
+        }
+        if !forgotten.is_empty() {
+            self.file.mark_changed();
+        }
+        forgotten
+    }
+
+    pub fn unsaved(&self) -> Option<Unsaved<Marker>> {
+        self.file.unsaved(|| self.by_id.values().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use world::Position;
+    use crate::markers::{Markers, MAX_MARKERS_PER_PLAYER};
+    use crate::testing::TempDir;
+
+    #[tokio::test]
+    async fn markers_are_limited_and_survive_a_restart() {
+        let dir = TempDir::new("markers");
+        let path = dir.join("markers.json");
+
+        let mut markers = Markers::load(path.clone()).unwrap();
+        let base = markers.add("alice", Position(3, 4), "Base".to_string()).unwrap();
+        for i in 1..MAX_MARKERS_PER_PLAYER {
+            markers.add("alice", Position(i as i32, 0), i.to_string()).unwrap();
+        }
+        assert!(markers.add("alice", Position(0, 0), "One too many".to_string()).is_err());
+        let mine = markers.add("bob", Position(-10, 20), "Mine".to_string()).unwrap();
+        assert!(markers.remove("alice", mine.id).is_err(), "only bob can remove it");
+        markers.remove("alice", base.id).unwrap();
+        assert!(markers.remove("alice", base.id).is_err());
+        markers.unsaved().unwrap().write().await.unwrap();
+
+        let mut markers = Markers::load(path.clone()).unwrap();
+        assert_eq!(markers.len(), MAX_MARKERS_PER_PLAYER);
+        assert!(markers.all().any(|marker| marker == &mine));
+        // New IDs come after the highest one there is
+        let next = markers.add("alice", Position(0, 0), "Again".to_string()).unwrap();
+        assert!(next.id > mine.id);
+
+        // Bob hasn't been back for too long
+        assert_eq!(markers.forget_unless(|player_id| player_id == "alice"), vec![mine.id]);
+        assert_eq!(markers.len(), MAX_MARKERS_PER_PLAYER);
+        assert!(markers.all().all(|marker| marker.player_id == "alice"));
+        assert!(markers.forget_unless(|player_id| player_id == "alice").is_empty());
+    }
+}
@@ -0,0 +1,78 @@
+use std::io;
+use std::path::Path;
+use world::player::Player;
+use world::Marker;
+
+/// Decides which names players can pick, for themselves and for markers. On top of the rules
+/// every client knows about, names can't have any of the blocked words in them.
+#[derive(Default)]
+pub struct NameRules {
+    /// Lowercase, with only their letters and numbers
+    blocked_words: Vec<String>,
+}
+
+/// Lowercase letters and numbers only, so that "B.a d" is caught by "bad"
+fn squash(text: &str) -> String {
+    text.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+impl NameRules {
+    /// Reads the blocked words, one per line. Blank lines and lines starting with `#` are
+    /// skipped.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn new(blocked_words: &str) -> Self {
+        let blocked_words = blocked_words.lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with('#'))
+            .map(squash)
+            .filter(|word| !word.is_empty())
+            .collect();
+        Self { blocked_words }
+    }
+
+    /// The name tidied up, or why it isn't allowed
+    pub fn check(&self, name: &str) -> Result<String, String> {
+        self.allow(Player::clean_name(name)?)
+    }
+
+    /// The marker's name tidied up, or why it isn't allowed
+    pub fn check_marker(&self, name: &str) -> Result<String, String> {
+        self.allow(Marker::clean_name(name)?)
+    }
+
+    fn allow(&self, name: String) -> Result<String, String> {
+        let squashed = squash(&name);
+        if self.blocked_words.iter().any(|word| squashed.contains(word.as_str())) {
+            return Err("That name isn't allowed".to_string());
+        }
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use world::player::MAX_NAME_LENGTH;
+    use crate::names::NameRules;
+
+    #[test]
+    fn names_are_tidied_and_checked() {
+        let rules = NameRules::new("# Not a word\n\n  Rude \nworse\n");
+        assert_eq!(rules.check("  Alice   the\tGreat "), Ok("Alice the Great".to_string()));
+        assert_eq!(rules.check(""), Ok("".to_string()));
+        assert_eq!(rules.check("Zoë O'Brien-Smith"), Ok("Zoë O'Brien-Smith".to_string()));
+        assert!(rules.check("<script>").is_err());
+        assert!(rules.check(&"a".repeat(MAX_NAME_LENGTH + 1)).is_err());
+        assert!(rules.check("Not a word").is_ok());
+        assert!(rules.check("very rude").is_err());
+        assert!(rules.check("R.U.D.E").is_err());
+        assert!(rules.check("WorseThanEver").is_err());
+        assert_eq!(rules.check_marker(" Meet  here! "), Ok("Meet here!".to_string()));
+        assert!(rules.check_marker("Rude camp").is_err());
+    }
+}
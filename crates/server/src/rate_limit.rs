@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use crate::config::LimitsConfig;
+
+/// How often something can be done on average, and how many times it can be done in one go
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+impl Rate {
+    pub fn validate(&self, name: &str) -> Result<(), String> {
+        let valid = self.per_sec > 0.0 && self.burst >= 1.0;
+        if !valid {
+            return Err(format!("{name} needs per_sec above 0 and burst of at least 1"));
+        }
+        Ok(())
+    }
+}
+
+/// Fills up with tokens at a steady rate, up to the burst size. Doing something takes a token.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate) -> Self {
+        Self {
+            rate,
+            tokens: rate.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_sec).min(self.rate.burst);
+        self.updated = now;
+    }
+
+    /// How long until there's a token to take, or `None` if there's one now
+    fn wait(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate.per_sec))
+        }
+    }
+
+    /// Takes a token if there is one
+    pub fn take(&mut self, now: Instant) -> bool {
+        let available = self.wait(now).is_none();
+        if available {
+            self.tokens -= 1.0;
+        }
+        available
+    }
+}
+
+/// The kinds of message that are limited separately
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Budget {
+    /// Clicks, flags and double clicks, which change the world, and profile changes, which
+    /// get passed on to other players
+    Action,
+    /// Queries and viewports, which send chunks back
+    Query,
+    /// Cursor movements, which get passed on to other players
+    Cursor,
+    /// Chat messages, pings and markers, which get passed on to other players
+    Chat,
+}
+
+struct Buckets {
+    actions: TokenBucket,
+    queries: TokenBucket,
+    cursors: TokenBucket,
+    chat: TokenBucket,
+}
+
+impl Buckets {
+    fn get(&mut self, budget: Budget) -> &mut TokenBucket {
+        match budget {
+            Budget::Action => &mut self.actions,
+            Budget::Query => &mut self.queries,
+            Budget::Cursor => &mut self.cursors,
+            Budget::Chat => &mut self.chat,
+        }
+    }
+}
+
+struct IpBuckets {
+    connections: usize,
+    buckets: Buckets,
+}
+
+/// The budgets shared by every connection from the same address, so that opening lots of
+/// connections doesn't get around the limits.
+pub struct IpLimits {
+    limits: LimitsConfig,
+    addresses: Mutex<HashMap<IpAddr, IpBuckets>>,
+}
+
+impl IpLimits {
+    pub fn new(limits: LimitsConfig) -> Self {
+        Self {
+            limits,
+            addresses: Default::default(),
+        }
+    }
+}
+
+/// What to do with a message from a client
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Ignore the message. The client can try again after this long.
+    Throttle(Duration),
+    /// The client has kept on sending too much after being throttled
+    Disconnect,
+}
+
+/// Limits how quickly one connection can send messages.
+pub struct ConnectionLimiter {
+    ip: IpAddr,
+    ip_limits: Arc<IpLimits>,
+    buckets: Buckets,
+    /// Each throttled message takes a token from here, and the connection is closed once
+    /// they've run out
+    throttled: TokenBucket,
+}
+
+impl ConnectionLimiter {
+    pub fn new(ip: IpAddr, ip_limits: Arc<IpLimits>) -> Self {
+        let limits = ip_limits.limits;
+        ip_limits.addresses.lock().unwrap()
+            .entry(ip)
+            .or_insert_with(|| IpBuckets {
+                connections: 0,
+                buckets: Buckets {
+                    actions: TokenBucket::new(limits.ip_actions),
+                    queries: TokenBucket::new(limits.ip_queries),
+                    cursors: TokenBucket::new(limits.ip_cursors),
+                    chat: TokenBucket::new(limits.ip_chat),
+                },
+            })
+            .connections += 1;
+        Self {
+            ip,
+            ip_limits,
+            buckets: Buckets {
+                actions: TokenBucket::new(limits.actions),
+                queries: TokenBucket::new(limits.queries),
+                cursors: TokenBucket::new(limits.cursors),
+                chat: TokenBucket::new(limits.chat),
+            },
+            throttled: TokenBucket::new(limits.throttled),
+        }
+    }
+
+    pub fn check(&mut self, budget: Budget) -> Verdict {
+        self.check_at(budget, Instant::now())
+    }
+
+    fn check_at(&mut self, budget: Budget, now: Instant) -> Verdict {
+        let mut addresses = self.ip_limits.addresses.lock().unwrap();
+        let ip_bucket = addresses.get_mut(&self.ip)
+            .expect("Addresses are only removed once all their connections have gone")
+            .buckets.get(budget);
+        let bucket = self.buckets.get(budget);
+        let wait = bucket.wait(now).max(ip_bucket.wait(now));
+        match wait {
+            None => {
+                bucket.take(now);
+                ip_bucket.take(now);
+                Verdict::Allow
+            }
+            Some(_) if !self.throttled.take(now) => Verdict::Disconnect,
+            Some(wait) => Verdict::Throttle(wait),
+        }
+    }
+
+    /// Counts a message that couldn't be decoded, which costs the same as a throttled one.
+    /// Returns false once the client has sent too many and should be disconnected.
+    pub fn invalid_message(&mut self) -> bool {
+        self.throttled.take(Instant::now())
+    }
+}
+
+impl Drop for ConnectionLimiter {
+    fn drop(&mut self) {
+        let mut addresses = self.ip_limits.addresses.lock().unwrap();
+        if let Some(ip) = addresses.get_mut(&self.ip) {
+            ip.connections -= 1;
+            if ip.connections == 0 {
+                addresses.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use crate::config::LimitsConfig;
+    use crate::rate_limit::{Budget, ConnectionLimiter, IpLimits, Rate, TokenBucket, Verdict};
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn buckets_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(Rate { per_sec: 10.0, burst: 3.0 });
+        assert!((0..3).all(|_| bucket.take(start)));
+        assert!(!bucket.take(start));
+        assert!(!bucket.take(start + Duration::from_millis(50)));
+        assert!(bucket.take(start + Duration::from_millis(100)));
+        // It never holds more than the burst
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..10).filter(|_| bucket.take(later)).count(), 3);
+    }
+
+    #[test]
+    fn actions_and_queries_have_separate_budgets() {
+        let limits = LimitsConfig {
+            actions: Rate { per_sec: 1.0, burst: 2.0 },
+            queries: Rate { per_sec: 1.0, burst: 2.0 },
+            ..Default::default()
+        };
+        let mut limiter = ConnectionLimiter::new(IP, Arc::new(IpLimits::new(limits)));
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(Budget::Action, now), Verdict::Allow);
+        assert_eq!(limiter.check_at(Budget::Action, now), Verdict::Allow);
+        assert!(matches!(limiter.check_at(Budget::Action, now), Verdict::Throttle(_)));
+        assert_eq!(limiter.check_at(Budget::Query, now), Verdict::Allow);
+    }
+
+    #[test]
+    fn connections_from_one_address_share_a_budget() {
+        let limits = LimitsConfig {
+            ip_actions: Rate { per_sec: 1.0, burst: 3.0 },
+            ..Default::default()
+        };
+        let ip_limits = Arc::new(IpLimits::new(limits));
+        let mut first = ConnectionLimiter::new(IP, ip_limits.clone());
+        let mut second = ConnectionLimiter::new(IP, ip_limits.clone());
+        let now = Instant::now();
+        assert_eq!(first.check_at(Budget::Action, now), Verdict::Allow);
+        assert_eq!(first.check_at(Budget::Action, now), Verdict::Allow);
+        assert_eq!(second.check_at(Budget::Action, now), Verdict::Allow);
+        assert!(matches!(second.check_at(Budget::Action, now), Verdict::Throttle(_)));
+
+        drop(first);
+        drop(second);
+        assert!(ip_limits.addresses.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn persistent_flooding_disconnects() {
+        let limits = LimitsConfig {
+            actions: Rate { per_sec: 1.0, burst: 1.0 },
+            throttled: Rate { per_sec: 1.0, burst: 5.0 },
+            ..Default::default()
+        };
+        let mut limiter = ConnectionLimiter::new(IP, Arc::new(IpLimits::new(limits)));
+        let now = Instant::now();
+        let verdicts: Vec<_> = (0..7).map(|_| limiter.check_at(Budget::Action, now)).collect();
+        assert_eq!(verdicts[0], Verdict::Allow);
+        assert!(verdicts[1..6].iter().all(|verdict| matches!(verdict, Verdict::Throttle(_))));
+        assert_eq!(verdicts[6], Verdict::Disconnect);
+    }
+
+    #[test]
+    fn invalid_messages_use_up_the_throttled_budget() {
+        let limits = LimitsConfig {
+            actions: Rate { per_sec: 1.0, burst: 1.0 },
+            throttled: Rate { per_sec: 0.001, burst: 3.0 },
+            ..Default::default()
+        };
+        let mut limiter = ConnectionLimiter::new(IP, Arc::new(IpLimits::new(limits)));
+        assert!(limiter.invalid_message());
+        assert!(limiter.invalid_message());
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(Budget::Action, now), Verdict::Allow);
+        assert!(matches!(limiter.check_at(Budget::Action, now), Verdict::Throttle(_)));
+        assert!(!limiter.invalid_message());
+    }
+}
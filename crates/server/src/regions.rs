@@ -0,0 +1,429 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, RwLock};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use world::rules::ChunkAccess;
+use world::{Chunk, ChunkMines, ChunkPosition, Position, Rect, UpdatedRect, UpdatedTile, World};
+
+/// The width and height of a region in tiles. This is 16 by 16 chunks.
+const REGION_SIZE: i32 = 256;
+
+/// Which region a position is in, counting in regions from the origin.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct RegionPosition(i32, i32);
+
+impl RegionPosition {
+    pub fn of(Position(x, y): Position) -> Self {
+        Self(x.div_euclid(REGION_SIZE), y.div_euclid(REGION_SIZE))
+    }
+
+    fn rect(&self) -> Rect {
+        Rect::from_top_left_and_size(Position(self.0 * REGION_SIZE, self.1 * REGION_SIZE), REGION_SIZE, REGION_SIZE)
+    }
+
+    /// Every region that has some part of the rect in it
+    fn covering(rect: &Rect) -> impl Iterator<Item = RegionPosition> {
+        let RegionPosition(left, top) = Self::of(rect.top_left());
+        let RegionPosition(right, bottom) = Self::of(rect.bottom_right());
+        (left..=right).flat_map(move |x| (top..=bottom).map(move |y| RegionPosition(x, y)))
+    }
+}
+
+/// Splits the rect up along region borders, so that each part only needs one region locked
+pub fn split_by_region(rect: &Rect) -> Vec<Rect> {
+    RegionPosition::covering(rect)
+        .filter_map(|region| region.rect().intersection(rect))
+        .filter(|part| part.width() > 0 && part.height() > 0)
+        .collect()
+}
+
+/// The world, split up into square regions that are locked separately, so that players who are
+/// far apart don't have to wait for each other. Each region is a [World] that only has the
+/// chunks in that region.
+pub struct Regions {
+    regions: RwLock<HashMap<RegionPosition, Arc<Mutex<Region>>>>,
+    seed: u64,
+    mines_per_chunk: u8,
+}
+
+/// One region of the world, and its chunks that have been sent to players
+struct Region {
+    world: World,
+    /// The last version of each chunk that was sent to anyone, already compressed. A chunk that
+    /// has changed since has a different version, and gets compressed again the next time it's
+    /// asked for.
+    compressed: HashMap<ChunkPosition, CompressedChunk>,
+}
+
+impl Region {
+    fn new(world: World) -> Self {
+        Self { world, compressed: HashMap::new() }
+    }
+
+    /// Compresses the chunk, unless this version of it has been compressed already
+    fn compress(&mut self, position: ChunkPosition) -> Option<CompressedChunk> {
+        let chunk = self.world.get_chunk(position.position())?;
+        let compressed = self.compressed.entry(position)
+            .and_modify(|compressed| if compressed.version != chunk.version() {
+                *compressed = CompressedChunk::new(chunk);
+            })
+            .or_insert_with(|| CompressedChunk::new(chunk));
+        Some(compressed.clone())
+    }
+}
+
+/// A chunk message that's ready to go out, so that a chunk lots of players are looking at is
+/// only compressed once.
+#[derive(Clone, Debug)]
+pub struct CompressedChunk {
+    pub position: ChunkPosition,
+    pub version: u32,
+    pub message: Arc<[u8]>,
+}
+
+impl CompressedChunk {
+    pub fn new(chunk: &Chunk) -> Self {
+        Self {
+            position: chunk.position,
+            version: chunk.version(),
+            message: chunk.compress().into(),
+        }
+    }
+
+    pub fn rect(&self) -> Rect {
+        Rect::from_corners(self.position.position(), self.position.bottom_right().position())
+    }
+}
+
+/// An action needed a region that it didn't have locked.
+#[derive(Debug)]
+pub struct NotLocked(RegionPosition);
+
+impl Regions {
+    /// Splits up a world, such as one that has just been loaded from the event log.
+    pub fn from_world(world: World) -> Self {
+        let (seed, mines_per_chunk) = (world.seed, world.mines_per_chunk);
+        let mut regions: HashMap<RegionPosition, World> = HashMap::new();
+        for chunk in world.chunks {
+            regions.entry(RegionPosition::of(chunk.position.position()))
+                .or_insert_with(|| World::empty(seed, mines_per_chunk))
+                .insert_chunk(chunk);
+        }
+        Self {
+            regions: RwLock::new(regions.into_iter()
+                .map(|(position, world)| (position, Arc::new(Mutex::new(Region::new(world)))))
+                .collect()),
+            seed,
+            mines_per_chunk,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.regions.read().unwrap().len()
+    }
+
+    fn region(&self, position: RegionPosition) -> Arc<Mutex<Region>> {
+        if let Some(region) = self.regions.read().unwrap().get(&position) {
+            return region.clone();
+        }
+        self.regions.write().unwrap()
+            .entry(position)
+            .or_insert_with(|| Arc::new(Mutex::new(Region::new(World::empty(self.seed, self.mines_per_chunk)))))
+            .clone()
+    }
+
+    /// Runs an action that starts at the position, with the regions around it locked. If the
+    /// action needs a region that isn't locked, it stops, and runs again from the start once
+    /// that region is locked too. Actions that use [world::rules] don't change anything until
+    /// they're sure to succeed, so they can safely be stopped like this.
+    ///
+    /// This returns the locks as well, so that the caller can log what happened before anyone
+    /// else can change those regions.
+    pub async fn act<T>(
+        &self,
+        at: Position,
+        mut action: impl FnMut(&mut LockedRegions) -> Result<T, NotLocked>,
+    ) -> (T, LockedRegions) {
+        // Enough to reveal the tiles around the position, and fill in the chunks around those
+        let mut wanted: BTreeSet<_> = RegionPosition::covering(&Rect::from_center_and_size(at, 35, 35)).collect();
+        loop {
+            let mut locked = self.lock(&wanted).await;
+            loop {
+                match action(&mut locked) {
+                    Ok(result) => return (result, locked),
+                    Err(NotLocked(position)) => {
+                        wanted.insert(position);
+                        if !self.try_lock_more(&mut locked, position).await {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Locks the regions in order, so that two actions can never be waiting for each other.
+    async fn lock(&self, positions: &BTreeSet<RegionPosition>) -> LockedRegions {
+        let mut locked = BTreeMap::new();
+        for &position in positions {
+            locked.insert(position, self.region(position).lock_owned().await);
+        }
+        LockedRegions { locked }
+    }
+
+    /// Adds a region to the ones that are already locked. It's only safe to wait for a region
+    /// that comes after all the ones we have, otherwise it has to be free right now. Returns
+    /// false if everything needs to be unlocked and locked again in order.
+    async fn try_lock_more(&self, locked: &mut LockedRegions, position: RegionPosition) -> bool {
+        let region = self.region(position);
+        let guard = if locked.locked.last_key_value().is_none_or(|(&last, _)| last < position) {
+            region.lock_owned().await
+        } else {
+            match region.try_lock_owned() {
+                Ok(guard) => guard,
+                Err(_) => return false,
+            }
+        };
+        locked.locked.insert(position, guard);
+        true
+    }
+
+    /// The chunks in the rect that are ready to be sent to players, compressed. Each region is
+    /// locked in turn, rather than all at once.
+    pub async fn query_chunks(&self, rect: &Rect) -> Vec<CompressedChunk> {
+        let regions: Vec<_> = {
+            let regions = self.regions.read().unwrap();
+            let (width, height) = (rect.width() as i64 / REGION_SIZE as i64, rect.height() as i64 / REGION_SIZE as i64);
+            if (width + 2) * (height + 2) < regions.len() as i64 {
+                RegionPosition::covering(rect)
+                    .filter_map(|position| regions.get(&position).cloned())
+                    .collect()
+            } else {
+                regions.iter()
+                    .filter(|(position, _)| position.rect().intersection(rect).is_some())
+                    .map(|(_, region)| region.clone())
+                    .collect()
+            }
+        };
+        let mut chunks = vec![];
+        for region in regions {
+            let mut region = region.lock().await;
+            let ready: Vec<_> = region.world.query_chunks(rect).into_iter()
+                .map(|chunk_id| &region.world.chunks[chunk_id])
+                .filter(|chunk| chunk.should_send())
+                .map(|chunk| chunk.position)
+                .collect();
+            chunks.extend(ready.into_iter().filter_map(|position| region.compress(position)));
+        }
+        chunks
+    }
+}
+
+/// Some regions of the world, locked so that nothing else can change them.
+pub struct LockedRegions {
+    locked: BTreeMap<RegionPosition, OwnedMutexGuard<Region>>,
+}
+
+impl LockedRegions {
+    fn region(&self, position: ChunkPosition) -> Result<&World, NotLocked> {
+        let region = RegionPosition::of(position.position());
+        self.locked.get(&region).map(|guard| &guard.world).ok_or(NotLocked(region))
+    }
+
+    fn region_mut(&mut self, position: ChunkPosition) -> Result<&mut World, NotLocked> {
+        let region = RegionPosition::of(position.position());
+        self.locked.get_mut(&region).map(|guard| &mut guard.world).ok_or(NotLocked(region))
+    }
+
+    pub fn get_rect(&self, rect: &Rect) -> Result<UpdatedRect, NotLocked> {
+        let mut updated_tiles = vec![];
+        for position in rect.positions() {
+            updated_tiles.push(UpdatedTile {
+                position,
+                tile: self.tile(position)?,
+            })
+        }
+        Ok(UpdatedRect::new(updated_tiles))
+    }
+
+    /// Takes the chunks that have been generated in these regions, for the event log.
+    pub fn take_generated_chunks(&mut self) -> Vec<(ChunkPosition, ChunkMines)> {
+        self.locked.values_mut()
+            .flat_map(|region| region.world.generated_chunks.drain(..))
+            .collect()
+    }
+
+    /// Takes the chunks that have become ready to send in these regions, compressed, so that
+    /// players who are already looking at them can be sent them.
+    pub fn take_filled_chunks(&mut self) -> Vec<CompressedChunk> {
+        let mut filled = vec![];
+        for region in self.locked.values_mut() {
+            let positions: Vec<_> = std::mem::take(&mut region.world.filled_chunks).into_iter()
+                .filter(|&position| region.world.get_chunk(position.position()).is_some_and(Chunk::should_send))
+                .collect();
+            filled.extend(positions.into_iter().filter_map(|position| region.compress(position)));
+        }
+        filled
+    }
+}
+
+impl ChunkAccess for LockedRegions {
+    type Error = NotLocked;
+
+    fn chunk(&self, position: ChunkPosition) -> Result<Option<&Chunk>, NotLocked> {
+        Ok(self.region(position)?.get_chunk(position.position()))
+    }
+
+    fn chunk_mut(&mut self, position: ChunkPosition) -> Result<Option<&mut Chunk>, NotLocked> {
+        let Ok(chunk) = self.region_mut(position)?.chunk_mut(position);
+        Ok(chunk)
+    }
+
+    fn generate(&mut self, position: ChunkPosition) -> Result<(), NotLocked> {
+        self.region_mut(position)?.generate_chunk(position.position());
+        Ok(())
+    }
+
+    fn filled(&mut self, position: ChunkPosition) -> Result<(), NotLocked> {
+        self.region_mut(position)?.filled_chunks.push_back(position);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use world::rules;
+    use world::{Position, Rect, World};
+    use crate::regions::{split_by_region, RegionPosition, Regions};
+
+    #[test]
+    fn region_positions() {
+        assert_eq!(RegionPosition::of(Position(0, 0)), RegionPosition(0, 0));
+        assert_eq!(RegionPosition::of(Position(255, 255)), RegionPosition(0, 0));
+        assert_eq!(RegionPosition::of(Position(256, -1)), RegionPosition(1, -1));
+        assert_eq!(RegionPosition::of(Position(-256, -257)), RegionPosition(-1, -2));
+    }
+
+    #[test]
+    fn splitting_by_region() {
+        let rect = Rect::from_top_left_and_size(Position(-10, 250), 300, 6);
+        let parts = split_by_region(&rect);
+        assert_eq!(parts, vec![
+            Rect { left: -10, top: 250, right: 0, bottom: 256 },
+            Rect { left: 0, top: 250, right: 256, bottom: 256 },
+            Rect { left: 256, top: 250, right: 290, bottom: 256 },
+        ]);
+        assert_eq!(parts.iter().map(Rect::area).sum::<i64>(), rect.area());
+    }
+
+    #[tokio::test]
+    async fn clicks_across_region_borders_match_one_world() {
+        let regions = Regions::from_world(World::new());
+        let mut world = World::new();
+        // Clicking all along the border between four regions
+        for i in -300..300 {
+            for at in [Position(i * 7, 0), Position(0, i * 7), Position(i * 3, i * 3)] {
+                let (event, _locked) = regions.act(at, |locked| rules::click(locked, at, "player")).await;
+                assert_eq!(event, world.click(at, "player"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed_chunks_are_reused_until_they_change() {
+        let regions = Regions::from_world(World::new());
+        let at = Position(0, 0);
+        regions.act(at, |locked| rules::click(locked, at, "player")).await;
+        let rect = Rect::from_center_and_size(at, 64, 64);
+        let first = regions.query_chunks(&rect).await;
+        assert!(!first.is_empty());
+        let second = regions.query_chunks(&rect).await;
+        for (a, b) in first.iter().zip(&second) {
+            assert!(Arc::ptr_eq(&a.message, &b.message));
+        }
+
+        // Flag the first hidden tile, which changes its chunk and nothing else
+        let mut flagged = None;
+        for at in rect.positions() {
+            if regions.act(at, |locked| rules::flag(locked, at, "player")).await.0.is_some() {
+                flagged = Some(at.chunk_position());
+                break;
+            }
+        }
+        let flagged = flagged.expect("Nothing to flag");
+        let third: HashMap<_, _> = regions.query_chunks(&rect).await.into_iter()
+            .map(|chunk| (chunk.position, chunk))
+            .collect();
+        assert_eq!(third.len(), first.len());
+        for a in &first {
+            let b = &third[&a.position];
+            assert_eq!(Arc::ptr_eq(&a.message, &b.message), a.position != flagged);
+            assert_eq!(a.version == b.version, a.position != flagged);
+        }
+    }
+
+    #[tokio::test]
+    async fn far_apart_actions_run_at_the_same_time() {
+        let regions = Arc::new(Regions::from_world(World::new()));
+        let (_, locked) = regions.act(Position(0, 0), |_| Ok(())).await;
+        let far_away = tokio::spawn({
+            let regions = regions.clone();
+            async move {
+                let at = Position(10_000, 10_000);
+                regions.act(at, |locked| rules::click(locked, at, "player")).await.0
+            }
+        });
+        // This would never finish if the whole world was locked
+        let event = tokio::time::timeout(Duration::from_secs(5), far_away).await
+            .expect("Action far away waited for the lock at the origin");
+        assert!(event.unwrap().is_some());
+        drop(locked);
+    }
+
+    /// Runs `actions` actions on each of `threads` worker threads, each holding its locks for a
+    /// millisecond, as if it were doing something slow. Each thread's actions are at `at(thread)`.
+    fn time_actions(threads: usize, actions: usize, at: impl Fn(usize) -> Position) -> Duration {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .enable_all()
+            .build()
+            .unwrap();
+        let regions = Arc::new(Regions::from_world(World::new()));
+        runtime.block_on(async {
+            let start = Instant::now();
+            let tasks: Vec<_> = (0..threads).map(|thread| {
+                let regions = regions.clone();
+                let at = at(thread);
+                tokio::spawn(async move {
+                    for _ in 0..actions {
+                        regions.act(at, |_| {
+                            std::thread::sleep(Duration::from_millis(1));
+                            Ok(())
+                        }).await;
+                    }
+                })
+            }).collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+            start.elapsed()
+        })
+    }
+
+    #[test]
+    #[ignore = "measures wall-clock time, so run it on a quiet machine with --ignored"]
+    fn actions_in_different_regions_scale_with_threads() {
+        let actions = 50;
+        let apart = |thread: usize| Position(thread as i32 * 1000, 0);
+        let together = |_| Position(0, 0);
+        for threads in [2, 4, 8] {
+            let spread = time_actions(threads, actions, apart);
+            // The same as locking the whole world for every action
+            let crowded = time_actions(threads, actions, together);
+            // At least half of the ideal speedup, leaving room for a busy machine
+            assert!(spread * (threads as u32) < crowded * 2, "{threads} threads in different regions took {spread:?}, in one region {crowded:?}");
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use axum::extract::ws::Message;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use world::ServerMessageBundle;
+
+/// Bundles that haven't been acknowledged are kept until there are this many of them...
+const MAX_REPLAY_BUNDLES: usize = 4096;
+/// ...or until they add up to this many bytes. After that, a client that comes back has missed
+/// too much and has to be resynced instead.
+const MAX_REPLAY_BYTES: usize = 1 << 20;
+
+/// How long a connection that takes over a session waits for the old connection to stop
+/// sending to the client
+const TAKE_OVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether bundle number `a` was sent after `b`. The numbers wrap around, so this only works
+/// for numbers less than half the range apart, which [MAX_REPLAY_BUNDLES] makes sure of.
+fn after(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+/// Numbers the bundles sent to a client, and keeps the ones it hasn't acknowledged yet, so that
+/// they can be sent again if it reconnects.
+#[derive(Default)]
+pub struct ReplayBuffer {
+    next: u16,
+    /// Oldest first
+    unacked: VecDeque<(u16, Vec<u8>)>,
+    bytes: usize,
+}
+
+impl ReplayBuffer {
+    /// Gives the bundle the next number, and keeps a copy until the client acknowledges it
+    pub fn number(&mut self, bundle: &[u8]) -> Vec<u8> {
+        let number = self.next;
+        self.next = self.next.wrapping_add(1);
+        let numbered = ServerMessageBundle::number_bytes(bundle, number);
+        self.bytes += numbered.len();
+        self.unacked.push_back((number, numbered.clone()));
+        while self.unacked.len() > MAX_REPLAY_BUNDLES || self.bytes > MAX_REPLAY_BYTES {
+            if let Some((_, dropped)) = self.unacked.pop_front() {
+                self.bytes -= dropped.len();
+            }
+        }
+        numbered
+    }
+
+    fn was_sent(&self, number: u16) -> bool {
+        after(self.next, number)
+    }
+
+    /// The client has every bundle up to and including this one
+    pub fn ack(&mut self, number: u16) {
+        if !self.was_sent(number) {
+            return;
+        }
+        while let Some((oldest, bundle)) = self.unacked.front() {
+            if after(*oldest, number) {
+                break;
+            }
+            self.bytes -= bundle.len();
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Every bundle sent after `last`, or `None` if some of them aren't kept any more
+    pub fn since(&self, last: u16) -> Option<Vec<Vec<u8>>> {
+        if !self.was_sent(last) {
+            return None;
+        }
+        let first_missed = last.wrapping_add(1);
+        if first_missed == self.next {
+            return Some(vec![]);
+        }
+        match self.unacked.front() {
+            Some(&(oldest, _)) if !after(oldest, first_missed) => Some(self.unacked.iter()
+                .filter(|(number, _)| after(*number, last))
+                .map(|(_, bundle)| bundle.clone())
+                .collect()),
+            _ => None,
+        }
+    }
+}
+
+/// Everything about a player's connection that needs to outlive the WebSocket, so that a
+/// client whose connection drops can pick up where it left off.
+pub struct Session {
+    pub player_id: String,
+    /// The player's token, which also picks the session up again if the connection drops
+    pub resume_key: String,
+    /// Messages for the client. These keep being queued up while it's disconnected.
+    pub tx: Sender<Message>,
+    rx: Mutex<Option<Receiver<Message>>>,
+    pub replay: Mutex<ReplayBuffer>,
+    /// Notified when the client falls behind
+    pub resync: Arc<Notify>,
+    /// Whether the client is only watching, rather than a player
+    pub spectating: bool,
+    /// Cancelled when another connection takes the session over
+    connection: Mutex<CancellationToken>,
+    /// Notified when the receiver is given back
+    returned: Notify,
+}
+
+impl Session {
+    pub fn new(player_id: String, token: String, tx: Sender<Message>, rx: Receiver<Message>, resync: Arc<Notify>) -> Arc<Self> {
+        Arc::new(Self {
+            player_id,
+            resume_key: token,
+            tx,
+            rx: Mutex::new(Some(rx)),
+            replay: Default::default(),
+            resync,
+            spectating: false,
+            connection: Default::default(),
+            returned: Notify::new(),
+        })
+    }
+
+    /// A session for a client that's only watching. It has no token, because it can't be resumed.
+    pub fn spectator(spectator_id: String, tx: Sender<Message>, rx: Receiver<Message>, resync: Arc<Notify>) -> Arc<Self> {
+        Arc::new(Self {
+            player_id: spectator_id,
+            resume_key: String::new(),
+            tx,
+            rx: Mutex::new(Some(rx)),
+            replay: Default::default(),
+            resync,
+            spectating: true,
+            connection: Default::default(),
+            returned: Notify::new(),
+        })
+    }
+
+    /// Tells the connection that had the session that it's been taken over, and gives the
+    /// token for the new one
+    fn connect(&self) -> CancellationToken {
+        let mut connection = self.connection.lock().unwrap();
+        connection.cancel();
+        *connection = CancellationToken::new();
+        connection.clone()
+    }
+
+    /// The receiving end of the client's queue, for the current connection to send from. If
+    /// the session has just been taken over, this waits for the old connection to give it
+    /// back, or gives `None` if that takes too long.
+    pub async fn take_receiver(&self) -> Option<Receiver<Message>> {
+        let take = async {
+            loop {
+                let rx = self.rx.lock().unwrap().take();
+                if let Some(rx) = rx {
+                    return rx;
+                }
+                self.returned.notified().await;
+            }
+        };
+        tokio::time::timeout(TAKE_OVER_TIMEOUT, take).await.ok()
+    }
+
+    /// Gives the queue back once the connection has finished with it
+    pub fn return_receiver(&self, rx: Receiver<Message>) {
+        *self.rx.lock().unwrap() = Some(rx);
+        self.returned.notify_one();
+    }
+}
+
+/// Whether a session's client is connected, or it's waiting to see if it comes back
+enum State {
+    Connected,
+    /// With the number given by [Sessions::park]
+    Parked(u64),
+}
+
+/// Every player's session, by resume key, so that a client can pick its session up again
+/// whether or not the server has noticed that its old connection dropped
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, (State, Arc<Session>)>>,
+    next_parking: Mutex<u64>,
+}
+
+impl Sessions {
+    /// Gives the token that says when another connection has taken the session over
+    pub fn connect(&self, session: Arc<Session>) -> CancellationToken {
+        let mut sessions = self.sessions.lock().unwrap();
+        let taken_over = session.connect();
+        sessions.insert(session.resume_key.clone(), (State::Connected, session));
+        taken_over
+    }
+
+    /// Returns a number that has to be given to [Sessions::expire], so that a timer from an
+    /// earlier disconnection can't expire the session. Returns `None` if another connection
+    /// has already taken the session over.
+    pub fn park(&self, session: &Arc<Session>, taken_over: &CancellationToken) -> Option<u64> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if taken_over.is_cancelled() {
+            return None;
+        }
+        let parking = {
+            let mut next = self.next_parking.lock().unwrap();
+            *next += 1;
+            *next
+        };
+        sessions.insert(session.resume_key.clone(), (State::Parked(parking), session.clone()));
+        Some(parking)
+    }
+
+    /// Picks the session up for a new connection. If the old connection is still open, it's
+    /// told that it's been taken over, because the client wouldn't be resuming if it could
+    /// still hear from it. Also gives the new connection's token from [Sessions::connect].
+    pub fn resume(&self, resume_key: &str) -> Option<(Arc<Session>, CancellationToken)> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let (state, session) = sessions.get_mut(resume_key)?;
+        *state = State::Connected;
+        Some((session.clone(), session.connect()))
+    }
+
+    /// Takes the player's parked session, if they have one, so that it can't be resumed or
+    /// expire any more
+    pub fn take_player(&self, player_id: &str) -> Option<Arc<Session>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let resume_key = sessions.iter()
+            .find(|(_, (state, session))| matches!(state, State::Parked(_)) && session.player_id == player_id)
+            .map(|(resume_key, _)| resume_key.clone())?;
+        sessions.remove(&resume_key).map(|(_, session)| session)
+    }
+
+    /// Gives up on the session if it's still parked from the same disconnection
+    pub fn expire(&self, resume_key: &str, parking: u64) -> Option<Arc<Session>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(resume_key) {
+            Some((State::Parked(parked_at), _)) if *parked_at == parking => {
+                sessions.remove(resume_key).map(|(_, session)| session)
+            }
+            _ => None,
+        }
+    }
+
+    /// Forgets the session, unless another connection has taken it over. Returns whether it
+    /// was forgotten.
+    pub fn end(&self, session: &Session, taken_over: &CancellationToken) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        if taken_over.is_cancelled() {
+            return false;
+        }
+        sessions.remove(&session.resume_key);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::mpsc::channel;
+    use tokio::sync::Notify;
+    use world::ServerMessageBundle;
+    use crate::session::{ReplayBuffer, Session, Sessions, MAX_REPLAY_BUNDLES};
+
+    fn number_of(bundle: &[u8]) -> u16 {
+        ServerMessageBundle::from_compressed_with_number(bundle).unwrap().0.unwrap()
+    }
+
+    fn numbers(bundles: Option<Vec<Vec<u8>>>) -> Option<Vec<u16>> {
+        bundles.map(|bundles| bundles.iter().map(|bundle| number_of(bundle)).collect())
+    }
+
+    #[test]
+    fn replaying_what_was_missed() {
+        let mut replay = ReplayBuffer::default();
+        let empty = ServerMessageBundle(vec![]).to_bytes();
+        for expected in 0..5 {
+            assert_eq!(number_of(&replay.number(&empty)), expected);
+        }
+        assert_eq!(numbers(replay.since(1)), Some(vec![2, 3, 4]));
+        assert_eq!(numbers(replay.since(4)), Some(vec![]));
+        // Nothing after 4 has been sent
+        assert_eq!(numbers(replay.since(5)), None);
+
+        replay.ack(2);
+        assert_eq!(numbers(replay.since(2)), Some(vec![3, 4]));
+        // The client said it had these, so they're gone
+        assert_eq!(numbers(replay.since(1)), None);
+    }
+
+    #[test]
+    fn numbers_wrap_around() {
+        let mut replay = ReplayBuffer { next: u16::MAX - 1, ..Default::default() };
+        let empty = ServerMessageBundle(vec![]).to_bytes();
+        for _ in 0..4 {
+            replay.number(&empty);
+        }
+        assert_eq!(numbers(replay.since(u16::MAX)), Some(vec![0, 1]));
+        replay.ack(0);
+        assert_eq!(numbers(replay.since(0)), Some(vec![1]));
+    }
+
+    #[test]
+    fn old_bundles_are_dropped() {
+        let mut replay = ReplayBuffer::default();
+        let empty = ServerMessageBundle(vec![]).to_bytes();
+        for _ in 0..MAX_REPLAY_BUNDLES + 10 {
+            replay.number(&empty);
+        }
+        assert_eq!(numbers(replay.since(0)), None);
+        assert_eq!(numbers(replay.since(10)).map(|numbers| numbers.len()), Some(MAX_REPLAY_BUNDLES - 1));
+    }
+
+    #[test]
+    fn expiring_parked_sessions() {
+        let sessions = Sessions::default();
+        let (tx, rx) = channel(1);
+        let session = Session::new("player".to_string(), "token".to_string(), tx, rx, Arc::new(Notify::new()));
+        let connection = sessions.connect(session.clone());
+        assert!(sessions.take_player("player").is_none(), "it's still connected");
+        let first = sessions.park(&session, &connection).unwrap();
+        let (_, connection) = sessions.resume(&session.resume_key).unwrap();
+        assert!(sessions.expire(&session.resume_key, first).is_none(), "it's been resumed");
+        let second = sessions.park(&session, &connection).unwrap();
+        // The timer from the first time it was parked doesn't count any more
+        assert!(sessions.expire(&session.resume_key, first).is_none());
+        assert!(sessions.expire(&session.resume_key, second).is_some());
+        assert!(sessions.resume(&session.resume_key).is_none());
+
+        let connection = sessions.connect(session.clone());
+        sessions.park(&session, &connection);
+        assert!(sessions.take_player("someone else").is_none());
+        assert!(sessions.take_player("player").is_some());
+        assert!(sessions.resume(&session.resume_key).is_none());
+    }
+
+    #[tokio::test]
+    async fn taking_over_a_connected_session() {
+        let sessions = Sessions::default();
+        let (tx, rx) = channel(1);
+        let session = Session::new("player".to_string(), "token".to_string(), tx, rx, Arc::new(Notify::new()));
+        let old = sessions.connect(session.clone());
+        let old_rx = session.take_receiver().await.unwrap();
+
+        let (resumed, new) = sessions.resume(&session.resume_key).unwrap();
+        assert!(Arc::ptr_eq(&resumed, &session));
+        assert!(old.is_cancelled() && !new.is_cancelled());
+        // The old connection finishes after being told, and mustn't park or end the session
+        let waiting = tokio::spawn(async move { resumed.take_receiver().await.is_some() });
+        session.return_receiver(old_rx);
+        assert!(waiting.await.unwrap());
+        assert!(sessions.park(&session, &old).is_none());
+        assert!(!sessions.end(&session, &old));
+        assert!(sessions.resume(&session.resume_key).is_some());
+    }
+}
@@ -0,0 +1,25 @@
+use std::path::{Path, PathBuf};
+
+/// A new directory for a test's files, which is deleted when it's dropped, even if the test
+/// fails
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// The name only has to be different from other tests' names
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("sweeper-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
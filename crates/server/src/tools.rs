@@ -0,0 +1,302 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Instant;
+use futures_util::StreamExt;
+use world::{ChunkPosition, Position, Rect, ServerMessage, ServerMessageBundle, World};
+use crate::config::WorldConfig;
+use crate::eventlog::{EventLogReader, EventReadResult, SourcedEvent};
+use crate::regions::{split_by_region, Regions};
+
+async fn open(log: PathBuf) -> Option<EventLogReader> {
+    match EventLogReader::open(log.clone()).await {
+        Ok(reader) => Some(reader),
+        Err(err) => {
+            eprintln!("Unable to open event log {:?}: {}", log, err);
+            None
+        }
+    }
+}
+
+/// Loads the event log into a fresh world and reports how long it took.
+pub async fn replay(log: PathBuf, config: &WorldConfig) -> bool {
+    let Some(reader) = open(log).await else { return false };
+    let mut world = config.new_world();
+    let summary = reader.replay(&mut world).await;
+    let seconds = summary.elapsed.as_secs_f64();
+    println!("Events read:    {}", summary.events_read);
+    println!("Invalid lines:  {}", summary.invalid);
+    println!("Chunks:         {}", world.chunks.len());
+    println!("Time taken:     {:?}", summary.elapsed);
+    if seconds > 0.0 {
+        println!("Events/second:  {:.0}", summary.events_read as f64 / seconds);
+    }
+    true
+}
+
+/// Checks that every line of the event log parses, and that every logged chunk has the mines
+/// that the generator would give it today. Returns false if any problems were found.
+pub async fn verify(log: PathBuf, config: &WorldConfig) -> bool {
+    let Some(reader) = open(log).await else { return false };
+    let report = check(reader, config).await;
+    for line in &report.lines {
+        println!("{line}");
+    }
+    println!("Checked {} lines: {} problems, {} warnings", report.checked, report.problems, report.warnings);
+    report.problems == 0
+}
+
+/// What [verify] found
+#[derive(Default)]
+struct VerifyReport {
+    checked: usize,
+    problems: usize,
+    warnings: usize,
+    /// A line about each problem or warning
+    lines: Vec<String>,
+}
+
+async fn check(reader: EventLogReader, config: &WorldConfig) -> VerifyReport {
+    let world = config.new_world();
+    let mut events = reader.events();
+    // The world always starts with some chunks, which never get logged:
+    let mut generated: HashSet<ChunkPosition> = world.chunk_ids.keys().copied().collect();
+    let mut report = VerifyReport::default();
+    while let Some(event) = events.next().await {
+        report.checked += 1;
+        let line = report.checked;
+        match event {
+            EventReadResult::Ok(SourcedEvent::ChunkGenerated(position, mines)) => {
+                if position != ChunkPosition::new(position.0, position.1) {
+                    report.problems += 1;
+                    report.lines.push(format!("line {line}: chunk at {:?} is not aligned to the chunk grid", position));
+                    continue;
+                }
+                if mines != world.generate_mines(position) {
+                    report.problems += 1;
+                    report.lines.push(format!("line {line}: mines in chunk {:?} don't match the generator", position));
+                }
+                if !generated.insert(position) {
+                    report.warnings += 1;
+                    report.lines.push(format!("line {line}: chunk {:?} was generated more than once", position));
+                }
+            }
+            EventReadResult::Ok(event) => {
+                let chunk_position = event.position().chunk_position();
+                if !generated.contains(&chunk_position) {
+                    report.warnings += 1;
+                    report.lines.push(format!("line {line}: {} at {:?} happened before chunk {:?} was generated",
+                                              event.name(), event.position(), chunk_position));
+                }
+            }
+            EventReadResult::Invalid(text) => {
+                report.problems += 1;
+                report.lines.push(format!("line {line}: can't parse event: {text}"));
+            }
+            EventReadResult::Eof => {}
+        }
+    }
+    report
+}
+
+/// Counts the events in the log by type, and by which region of the world they happened in.
+pub async fn stats(log: PathBuf, region_size: i32, top: usize) -> bool {
+    let Some(reader) = open(log).await else { return false };
+    let EventCounts { by_type, by_region, invalid } = count(reader, region_size).await;
+
+    let mut by_type: Vec<_> = by_type.into_iter().collect();
+    by_type.sort_by_key(|&(_, count)| Reverse(count));
+    println!("Events by type:");
+    for (name, count) in by_type {
+        println!("  {:<16}{}", name, count);
+    }
+    println!("  {:<16}{}", "Invalid", invalid);
+
+    let mut by_region: Vec<_> = by_region.into_iter().collect();
+    by_region.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    println!("Busiest regions ({region_size}x{region_size} tiles):");
+    for (Position(x, y), count) in by_region.into_iter().take(top) {
+        let Rect { left, top, right, bottom } = Rect::from_top_left_and_size(
+            Position(x * region_size, y * region_size), region_size, region_size
+        );
+        println!("  {:<40}{}", format!("({left}, {top}) to ({right}, {bottom})"), count);
+    }
+    true
+}
+
+/// What [stats] counted. Regions are numbered, so region (1, 0) is the one to the right of
+/// the one with (0, 0) in its top left corner.
+struct EventCounts {
+    by_type: HashMap<&'static str, usize>,
+    by_region: HashMap<Position, usize>,
+    invalid: usize,
+}
+
+async fn count(reader: EventLogReader, region_size: i32) -> EventCounts {
+    let mut events = reader.events();
+    let mut counts = EventCounts { by_type: HashMap::new(), by_region: HashMap::new(), invalid: 0 };
+    while let Some(event) = events.next().await {
+        match event {
+            EventReadResult::Ok(event) => {
+                *counts.by_type.entry(event.name()).or_default() += 1;
+                let Position(x, y) = event.position();
+                let region = Position(x.div_euclid(region_size), y.div_euclid(region_size));
+                *counts.by_region.entry(region).or_default() += 1;
+            }
+            EventReadResult::Invalid(_) => counts.invalid += 1,
+            EventReadResult::Eof => {}
+        }
+    }
+    counts
+}
+
+/// Loads the event log and prints the tiles in the rect, one row per line.
+pub async fn render(log: PathBuf, config: &WorldConfig, rect: Rect) -> bool {
+    let Some(reader) = open(log).await else { return false };
+    let mut world = config.new_world();
+    let start_time = Instant::now();
+    reader.replay(&mut world).await;
+    eprintln!("Loaded world in {:?}", start_time.elapsed());
+    for row in rows(&world, rect) {
+        println!("{}", row);
+    }
+    true
+}
+
+/// The tiles in the rect as text, one string per row
+fn rows(world: &World, rect: Rect) -> Vec<String> {
+    (rect.top..rect.bottom)
+        .map(|y| (rect.left..rect.right).map(|x| world.get_tile(&Position(x, y)).to_string()).collect())
+        .collect()
+}
+
+/// Fills the area around spawn with clicks, then times sending all of its chunks to every
+/// player, first compressing each chunk for each player, then the way the server does it, with
+/// each chunk compressed once and shared.
+pub async fn bench(config: &WorldConfig, players: usize, size: i32) -> bool {
+    let spawn = Rect::from_center_and_size(Position(0, 0), size, size);
+    let mut world = config.new_world();
+    let start_time = Instant::now();
+    let clicks = spawn.positions().into_iter()
+        .filter(|Position(x, y)| x % 5 == 0 && y % 5 == 0)
+        .filter(|&at| world.click(at, "bench").is_some())
+        .count();
+    println!("Clicks:                  {}", clicks);
+    println!("Chunks:                  {}", world.chunks.len());
+    println!("Time to play:            {:?}", start_time.elapsed());
+
+    let start_time = Instant::now();
+    let mut bytes = 0;
+    for _ in 0..players {
+        let chunks = world.query_chunks(&spawn).into_iter()
+            .map(|chunk_id| &world.chunks[chunk_id])
+            .filter(|chunk| chunk.should_send())
+            .map(|chunk| ServerMessage::Chunk(chunk.clone()))
+            .collect();
+        bytes += ServerMessageBundle(chunks).to_bytes().len();
+    }
+    let uncached = start_time.elapsed();
+    println!("Bytes per player:        {}", bytes / players.max(1));
+    println!("Compressing every time:  {:?}", uncached);
+
+    let regions = Regions::from_world(world);
+    let start_time = Instant::now();
+    let mut cached_bytes = 0;
+    for _ in 0..players {
+        for page in split_by_region(&spawn) {
+            let chunks = regions.query_chunks(&page).await;
+            cached_bytes += ServerMessageBundle::from_message_bytes(chunks.iter().map(|chunk| &chunk.message)).len();
+        }
+    }
+    let cached = start_time.elapsed();
+    println!("Compressing once:        {:?}", cached);
+    if cached.as_secs_f64() > 0.0 {
+        println!("Speedup:                 {:.1}x", uncached.as_secs_f64() / cached.as_secs_f64());
+    }
+    // The cached bundles are split by region, so they have a few more headers
+    if cached_bytes < bytes {
+        eprintln!("The cached chunks came to fewer bytes than the uncached ones");
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use world::{ChunkPosition, Position, Rect};
+    use crate::config::WorldConfig;
+    use crate::eventlog::{EventLogReader, EventLogWriter, SourcedEvent};
+    use crate::testing::TempDir;
+    use crate::tools::{check, count, rows};
+
+    /// Writes the events to a new log, with a line that isn't an event after them
+    async fn fixture(name: &str, events: Vec<SourcedEvent>) -> (TempDir, PathBuf) {
+        let dir = TempDir::new(&format!("tools-{name}"));
+        let path = dir.join("eventlog");
+        let mut writer = EventLogWriter::new(path.clone()).await.unwrap();
+        for event in events {
+            writer.write(&event).unwrap();
+        }
+        writer.flush().await.unwrap();
+        (dir, path)
+    }
+
+    async fn reader(path: &Path) -> EventLogReader {
+        EventLogReader::open(path.to_path_buf()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn verify_finds_chunks_that_dont_match_the_generator() {
+        let config = WorldConfig::default();
+        let world = config.new_world();
+        let good = ChunkPosition::new(1024, 0);
+        let bad = ChunkPosition::new(2048, 0);
+        let wrong_mines = world.generate_mines(ChunkPosition::new(4096, 0));
+        let (_dir, path) = fixture("verify", vec![
+            SourcedEvent::ChunkGenerated(good, world.generate_mines(good)),
+            SourcedEvent::Click(Position(1030, 5)),
+            SourcedEvent::ChunkGenerated(bad, wrong_mines),
+            SourcedEvent::Flag(Position(-5000, 0)),
+        ]).await;
+
+        let report = check(reader(&path).await, &config).await;
+        assert_eq!(report.checked, 4);
+        assert_eq!(report.problems, 1);
+        assert!(report.lines.iter().any(|line| line.starts_with("line 3:") && line.contains("don't match")), "{:?}", report.lines);
+        assert_eq!(report.warnings, 1, "the flag came before its chunk");
+    }
+
+    #[tokio::test]
+    async fn stats_counts_by_type_and_region() {
+        let (_dir, path) = fixture("stats", vec![
+            SourcedEvent::Click(Position(1, 1)),
+            SourcedEvent::Click(Position(300, 1)),
+            SourcedEvent::Flag(Position(2, 2)),
+            SourcedEvent::Unflag(Position(-1, 2)),
+        ]).await;
+        std::fs::write(&path, std::fs::read_to_string(&path).unwrap() + "not an event\n").unwrap();
+
+        let counts = count(reader(&path).await, 256).await;
+        assert_eq!(counts.by_type["Click"], 2);
+        assert_eq!(counts.by_type["Flag"], 1);
+        assert_eq!(counts.by_type["Unflag"], 1);
+        assert_eq!(counts.invalid, 1);
+        assert_eq!(counts.by_region[&Position(0, 0)], 2);
+        assert_eq!(counts.by_region[&Position(1, 0)], 1);
+        assert_eq!(counts.by_region[&Position(-1, 0)], 1);
+    }
+
+    #[tokio::test]
+    async fn render_draws_the_tiles() {
+        let config = WorldConfig::default();
+        let (_dir, path) = fixture("render", vec![
+            SourcedEvent::Flag(Position(1, 0)),
+            SourcedEvent::Flag(Position(3, 1)),
+        ]).await;
+        let mut world = config.new_world();
+        reader(&path).await.replay(&mut world).await;
+
+        assert_eq!(rows(&world, Rect { left: 0, top: 0, right: 5, bottom: 2 }), vec![" F   ", "   F "]);
+    }
+}
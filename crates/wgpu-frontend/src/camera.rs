@@ -255,6 +255,10 @@ impl Camera {
         distance
     }
 
+    pub fn mouse_position(&self) -> PhysicalPosition<f64> {
+        self.mouse_position
+    }
+
     pub fn update_mouse_position(&mut self, mouse_position: &PhysicalPosition<f64>) {
         self.mouse_position = *mouse_position;
         if let Some(drag) = &self.drag {
@@ -285,6 +289,16 @@ impl Camera {
         self.center - distance_from_view_center_in_world_space
     }
     
+    /// How big the screen is, in the same pixels as [Camera::world_to_screen]
+    pub fn screen_size(&self) -> PhysicalSize<f64> {
+        PhysicalSize::new(self.size.x, self.size.y)
+    }
+
+    pub fn world_to_screen(&self, position: Vector2<f64>) -> PhysicalPosition<f64> {
+        let screen = self.size/2.0 + (position - self.center)*self.tile_size();
+        PhysicalPosition::new(screen.x, screen.y)
+    }
+
     pub fn world_center(&self) -> Position {
         as_world_position(self.center)
     }
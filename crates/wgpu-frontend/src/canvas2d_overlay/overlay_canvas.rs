@@ -49,7 +49,46 @@ impl OverlayCanvas {
         self.canvas.set_height(size.height);
     }
 
+    pub fn clear(&self) {
+        self.context.clear_rect(0.0, 0.0, self.canvas.width() as f64, self.canvas.height() as f64);
+    }
+
+    pub fn set_fill(&self, color: &CanvasColor) {
+        self.context.set_fill_style_str(&color.css());
+    }
+
+    pub fn set_stroke(&self, color: &CanvasColor, width: f64) {
+        self.context.set_stroke_style_str(&color.css());
+        self.context.set_line_width(width);
+    }
+
+    /// The outline of a circle, in the stroke colour
+    pub fn circle(&self, center: PhysicalPosition<f64>, radius: f64) {
+        self.context.begin_path();
+        self.context.arc(center.x, center.y, radius, 0.0, std::f64::consts::TAU).unwrap();
+        self.context.stroke();
+    }
+
+    /// A triangle in the fill colour with its tip at `tip`, pointing `angle` radians clockwise
+    /// from the right
+    pub fn arrow(&self, tip: PhysicalPosition<f64>, angle: f64, size: f64) {
+        let corner = |side: f64| (tip.x - size * (angle + side).cos(), tip.y - size * (angle + side).sin());
+        let (left, right) = (corner(0.4), corner(-0.4));
+        self.context.begin_path();
+        self.context.move_to(tip.x, tip.y);
+        self.context.line_to(left.0, left.1);
+        self.context.line_to(right.0, right.1);
+        self.context.close_path();
+        self.context.fill();
+    }
+
     pub fn text(&self, text: &str, size: f64, anchor: Anchor, position: PhysicalPosition<f64>) {
+        self.context.set_font(&format!("{size}px sans-serif"));
+        self.context.set_text_align(match anchor {
+            Anchor::Left => "left",
+            Anchor::Middle => "center",
+            Anchor::Right => "right",
+        });
         self.context.fill_text(text, position.x, position.y).unwrap();
     }
 }
@@ -61,4 +100,13 @@ pub enum Anchor {
 pub enum CanvasColor {
     Rgba(f64, f64, f64, f64),
     Oklcha(f64, f64, f64, f64),
+}
+
+impl CanvasColor {
+    fn css(&self) -> String {
+        match self {
+            CanvasColor::Rgba(r, g, b, a) => format!("rgb({} {} {} / {a})", r * 255.0, g * 255.0, b * 255.0),
+            CanvasColor::Oklcha(l, c, h, a) => format!("oklch({l} {c} {h} / {a})"),
+        }
+    }
 }
\ No newline at end of file
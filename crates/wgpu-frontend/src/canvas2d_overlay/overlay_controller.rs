@@ -1,7 +1,11 @@
 use winit::dpi::PhysicalSize;
 
 #[cfg(target_arch = "wasm32")]
-use crate::canvas2d_overlay::overlay_canvas::OverlayCanvas;
+use crate::camera::Camera;
+#[cfg(target_arch = "wasm32")]
+use crate::canvas2d_overlay::overlay_canvas::{Anchor, CanvasColor, OverlayCanvas};
+#[cfg(target_arch = "wasm32")]
+use crate::map_markers::ShownMarker;
 
 pub struct OverlayController {
     #[cfg(target_arch = "wasm32")]
@@ -20,4 +24,64 @@ impl OverlayController {
         #[cfg(target_arch = "wasm32")]
         self.overlay_canvas.set_size(size)
     }
+
+    /// Writes each name next to its cursor, in the cursor's colour. Takes the names, where
+    /// their cursors are drawn in the world, and their hues out of 256.
+    #[cfg(target_arch = "wasm32")]
+    pub fn draw_names<'a>(&self, camera: &Camera, scale_factor: f64, names: impl Iterator<Item = (&'a str, [f32; 2], u8)>) {
+        self.overlay_canvas.clear();
+        // Cursors are drawn from the middle of their tile, and are two tiles across when
+        // zoomed in, or 64 pixels when zoomed out
+        let cursor_size = 2.0 * camera.tile_size().max(32.0);
+        for (name, [x, y], hue) in names {
+            let tip = camera.world_to_screen(cgmath::Vector2::new(x as f64 + 0.5, y as f64 + 0.5));
+            let position = winit::dpi::PhysicalPosition::new(tip.x + cursor_size * 0.6, tip.y + cursor_size);
+            self.overlay_canvas.set_fill(&CanvasColor::Oklcha(0.8, 0.15, hue as f64 * 360.0 / 256.0, 1.0));
+            self.overlay_canvas.text(name, 14.0 * scale_factor, Anchor::Left, position);
+        }
+    }
+
+    /// Draws rings around pings and named markers, or for the ones that are off the screen, an
+    /// arrow at the edge of the screen pointing towards them. Call after [Self::draw_names],
+    /// which clears the canvas.
+    #[cfg(target_arch = "wasm32")]
+    pub fn draw_markers(&self, camera: &Camera, scale_factor: f64, markers: &[ShownMarker]) {
+        let size = camera.screen_size();
+        let center = winit::dpi::PhysicalPosition::new(size.width / 2.0, size.height / 2.0);
+        let margin = 24.0 * scale_factor;
+        let radius = (camera.tile_size() / 2.0).max(6.0 * scale_factor);
+        for marker in markers {
+            let world::Position(x, y) = marker.position;
+            let at = camera.world_to_screen(cgmath::Vector2::new(x as f64 + 0.5, y as f64 + 0.5));
+            // Pings fade away
+            let alpha = marker.ping_age_ms
+                .map_or(1.0, |age| 1.0 - age as f64 / world::PING_DURATION_MS as f64);
+            let colour = CanvasColor::Oklcha(0.8, 0.15, marker.hue as f64 * 360.0 / 256.0, alpha);
+            self.overlay_canvas.set_fill(&colour);
+            self.overlay_canvas.set_stroke(&colour, 3.0 * scale_factor);
+            let on_screen = (0.0..size.width).contains(&at.x) && (0.0..size.height).contains(&at.y);
+            if on_screen {
+                match marker.ping_age_ms {
+                    // A ring that spreads out from the tile once a second
+                    Some(age) => self.overlay_canvas.circle(at, radius * (1.0 + 2.0 * (age % 1000) as f64 / 1000.0)),
+                    None => self.overlay_canvas.circle(at, radius),
+                }
+                let above = winit::dpi::PhysicalPosition::new(at.x, at.y - radius - 6.0 * scale_factor);
+                self.overlay_canvas.text(&marker.label, 14.0 * scale_factor, Anchor::Middle, above);
+            } else {
+                // Where the line from the middle of the screen to the marker leaves the screen,
+                // less the margin
+                let (dx, dy) = (at.x - center.x, at.y - center.y);
+                let along = ((center.x - margin) / dx.abs()).min((center.y - margin) / dy.abs());
+                let edge = winit::dpi::PhysicalPosition::new(center.x + dx * along, center.y + dy * along);
+                let angle = dy.atan2(dx);
+                self.overlay_canvas.arrow(edge, angle, 16.0 * scale_factor);
+                let inside = winit::dpi::PhysicalPosition::new(
+                    edge.x - angle.cos() * 28.0 * scale_factor,
+                    edge.y - angle.sin() * 28.0 * scale_factor,
+                );
+                self.overlay_canvas.text(&marker.label, 12.0 * scale_factor, Anchor::Middle, inside);
+            }
+        }
+    }
 }
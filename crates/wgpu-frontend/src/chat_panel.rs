@@ -0,0 +1,158 @@
+#[cfg(target_arch = "wasm32")]
+use std::sync::mpsc::{self, Receiver};
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+#[cfg(target_arch = "wasm32")]
+use web_sys::{Document, Element, HtmlInputElement, HtmlSelectElement, KeyboardEvent};
+#[cfg(target_arch = "wasm32")]
+use world::{ChatChannel, ChatMessage, MAX_CHAT_LENGTH};
+use world::ClientMessage;
+
+/// The log only keeps this many lines, dropping the oldest
+#[cfg(target_arch = "wasm32")]
+const MAX_LINES: u32 = 200;
+
+/// A box in the corner of the page with the chat log and a field to type in. It's styled by
+/// `#chat` in index.html.
+pub struct ChatPanel {
+    #[cfg(target_arch = "wasm32")]
+    document: Document,
+    #[cfg(target_arch = "wasm32")]
+    log: Element,
+    /// Messages that have been typed, waiting to be sent
+    #[cfg(target_arch = "wasm32")]
+    typed: Receiver<ClientMessage>,
+}
+
+impl ChatPanel {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn new() -> Self {
+        let document = web_sys::window().unwrap().document().unwrap();
+        let panel = element(&document, "div", "chat");
+        let log = element(&document, "div", "chat-log");
+        let channel: HtmlSelectElement = element(&document, "select", "chat-channel").dyn_into().unwrap();
+        for (value, label) in [("Nearby", "Nearby"), ("Global", "Everyone")] {
+            let option = document.create_element("option").unwrap();
+            option.set_attribute("value", value).unwrap();
+            option.set_text_content(Some(label));
+            channel.append_child(&option).unwrap();
+        }
+        let input: HtmlInputElement = element(&document, "input", "chat-input").dyn_into().unwrap();
+        input.set_max_length(MAX_CHAT_LENGTH as i32);
+        input.set_placeholder("Say something");
+        panel.append_child(&log).unwrap();
+        panel.append_child(&channel).unwrap();
+        panel.append_child(&input).unwrap();
+        document.body().unwrap().append_child(&panel).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let field = input.clone();
+        let on_keydown = Closure::<dyn FnMut(_)>::new(move |e: KeyboardEvent| {
+            match e.key().as_str() {
+                "Enter" => {
+                    let text = field.value();
+                    if text.trim().is_empty() {
+                        return;
+                    }
+                    let channel = match channel.value().as_str() {
+                        "Global" => ChatChannel::Global,
+                        _ => ChatChannel::Nearby,
+                    };
+                    let _ = tx.send(ClientMessage::Chat { channel, text });
+                    field.set_value("");
+                }
+                // Back to the game
+                "Escape" => field.blur().unwrap(),
+                _ => {}
+            }
+        });
+        input.set_onkeydown(Some(on_keydown.as_ref().unchecked_ref()));
+        on_keydown.forget();
+
+        Self {
+            document,
+            log,
+            typed: rx,
+        }
+    }
+
+    /// The next message the player has typed, to send to the server
+    pub fn typed(&self) -> Option<ClientMessage> {
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "wasm32")] {
+                self.typed.try_recv().ok()
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Adds a message to the log, with the sender's name in their colour if we know it. Everything
+    /// is put in as text rather than HTML, so players can't put markup in each other's pages.
+    #[cfg(target_arch = "wasm32")]
+    pub fn show(&self, message: &ChatMessage, hue: Option<u8>) {
+        let line = self.document.create_element("div").unwrap();
+        line.set_class_name("chat-line");
+        let date = js_sys::Date::new(&JsValue::from_f64(message.time as f64));
+        let time = format!("{:02}:{:02}", date.get_hours(), date.get_minutes());
+        line.append_child(&self.span("chat-time", &time)).unwrap();
+        if message.channel == ChatChannel::Nearby {
+            line.append_child(&self.span("chat-nearby", "nearby")).unwrap();
+        }
+        let name = match message.name.as_str() {
+            "" => message.player_id.as_str(),
+            name => name,
+        };
+        let name = self.span("chat-name", name);
+        if let Some(hue) = hue {
+            let style = format!("color: oklch(0.8 0.15 {})", hue as f64 * 360.0 / 256.0);
+            name.set_attribute("style", &style).unwrap();
+        }
+        line.append_child(&name).unwrap();
+        line.append_child(&self.span("chat-text", &message.text)).unwrap();
+        self.add_line(line);
+    }
+
+    /// Adds a line from the game rather than a player, like why a message was rejected
+    #[cfg(target_arch = "wasm32")]
+    pub fn show_notice(&self, text: &str) {
+        let line = self.span("chat-line chat-notice", text);
+        self.add_line(line);
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn span(&self, class: &str, text: &str) -> Element {
+        let span = self.document.create_element("span").unwrap();
+        span.set_class_name(class);
+        span.set_text_content(Some(text));
+        span
+    }
+
+    /// Keeps the log scrolled to the bottom, unless the player has scrolled up to read
+    #[cfg(target_arch = "wasm32")]
+    fn add_line(&self, line: Element) {
+        let log = &self.log;
+        let at_bottom = log.scroll_top() + log.client_height() >= log.scroll_height() - 4;
+        log.append_child(&line).unwrap();
+        while log.child_element_count() > MAX_LINES {
+            if let Some(oldest) = log.first_element_child() {
+                oldest.remove();
+            }
+        }
+        if at_bottom {
+            log.set_scroll_top(log.scroll_height());
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn element(document: &Document, tag: &str, id: &str) -> Element {
+    let element = document.create_element(tag).unwrap();
+    element.set_id(id);
+    element
+}
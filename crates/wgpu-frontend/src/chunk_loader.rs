@@ -1,12 +1,22 @@
-use std::collections::VecDeque;
-use world::Position;
-use world::ClientMessage;
+use std::collections::HashMap;
+use chrono::{DateTime, TimeDelta, Utc};
+use world::{Chunk, ChunkPosition, ClientMessage, MAX_HOLDING};
 use world::Rect;
 
+/// Keeps track of the area around the camera that the server should keep us up to date with.
+/// It grows a few chunks at a time as the camera moves, so that the server isn't sent a new
+/// viewport every frame.
 #[derive(Debug)]
 pub struct ChunkLoader {
     loaded: Rect,
-    queries: VecDeque<Rect>,
+    /// Whether the server needs to be told about a new viewport
+    changed: bool,
+    /// The server is ignoring our messages until then
+    throttled_until: Option<DateTime<Utc>>,
+    /// The version of each chunk the server has sent us
+    versions: HashMap<ChunkPosition, u32>,
+    /// Which run of the server the versions came from
+    epoch: Option<u64>,
 }
 
 impl ChunkLoader {
@@ -14,19 +24,22 @@ impl ChunkLoader {
     pub fn new(visible_area: Rect) -> Self {
         let top_left = visible_area.top_left().chunk_position().position();
         let bottom_right = visible_area.bottom_right().chunk_position().bottom_right().position();
+        let mut loaded = Rect::from_corners(top_left, bottom_right);
+        // Lining up with chunks can make it a little bigger than the server will accept
+        loaded.right = loaded.right.min(loaded.left + Self::MAX_VISIBLE);
+        loaded.bottom = loaded.bottom.min(loaded.top + Self::MAX_VISIBLE);
         Self {
-            loaded: Rect::from_corners(top_left, bottom_right),
-            queries: VecDeque::from([visible_area]),
+            loaded,
+            changed: true,
+            throttled_until: None,
+            versions: HashMap::new(),
+            epoch: None,
         }
     }
     
     fn grow_right(&mut self, columns: u32) {
         let columns = (columns * 16) as i32;
-        self.queries.push_back(Rect::from_top_left_and_size(
-            self.loaded.top_right(),
-            columns,
-            self.loaded.height()
-        ));
+        self.changed = true;
         self.loaded.right += columns;
         if self.loaded.width() > Self::MAX_VISIBLE {
             self.loaded.left = self.loaded.right - Self::MAX_VISIBLE;
@@ -34,11 +47,7 @@ impl ChunkLoader {
     }
     fn grow_bottom(&mut self, rows: u32) {
         let rows = (rows * 16) as i32;
-        self.queries.push_back(Rect::from_top_left_and_size(
-            self.loaded.bottom_left(),
-            self.loaded.width(),
-            rows,
-        ));
+        self.changed = true;
         self.loaded.bottom += rows;
         if self.loaded.height() > Self::MAX_VISIBLE {
             self.loaded.top = self.loaded.bottom - Self::MAX_VISIBLE;
@@ -46,11 +55,7 @@ impl ChunkLoader {
     }
     fn grow_left(&mut self, columns: u32) {
         let columns = (columns * 16) as i32;
-        self.queries.push_back(Rect::from_top_left_and_size(
-            self.loaded.top_left() - Position(columns, 0),
-            columns,
-            self.loaded.height()
-        ));
+        self.changed = true;
         self.loaded.left -= columns;
         if self.loaded.width() > Self::MAX_VISIBLE {
             self.loaded.right = self.loaded.left + Self::MAX_VISIBLE;
@@ -58,11 +63,7 @@ impl ChunkLoader {
     }
     fn grow_top(&mut self, rows: u32) {
         let rows = (rows * 16) as i32;
-        self.queries.push_back(Rect::from_top_left_and_size(
-            self.loaded.top_left() - Position(0, rows),
-            self.loaded.width(),
-            rows,
-        ));
+        self.changed = true;
         self.loaded.top -= rows;
         if self.loaded.height() > Self::MAX_VISIBLE {
             self.loaded.bottom = self.loaded.top + Self::MAX_VISIBLE;
@@ -91,10 +92,51 @@ impl ChunkLoader {
         }
     }
     
-    pub fn next_query_message(&mut self) -> Option<ClientMessage> {
-        match self.queries.pop_front() {
-            None => None,
-            Some(query) =>  Some(ClientMessage::Query(query))
+    /// The server has forgotten our viewport, like after reconnecting, so send it again
+    pub fn resend(&mut self) {
+        self.changed = true;
+    }
+
+    /// The server ignored a message, which might have been our viewport, so send it again once
+    /// the server is ready for more.
+    pub fn resend_after(&mut self, delay: TimeDelta) {
+        self.changed = true;
+        self.throttled_until = Some(Utc::now() + delay);
+    }
+
+    /// The server has welcomed us. If it has restarted since the last time, the versions we
+    /// have don't mean anything any more.
+    pub fn welcome(&mut self, epoch: u64) {
+        if self.epoch != Some(epoch) {
+            self.versions.clear();
+            self.epoch = Some(epoch);
+        }
+    }
+
+    pub fn received(&mut self, chunk: &Chunk) {
+        self.versions.insert(chunk.position, chunk.version());
+    }
+
+    /// Tells a new session on the server which chunks we already have, so it doesn't send
+    /// them again
+    pub fn holding_message(&self) -> Option<ClientMessage> {
+        let holding: Vec<_> = self.versions.iter()
+            .filter(|(position, _)| {
+                let loaded = &self.loaded;
+                (loaded.left..loaded.right).contains(&position.0) && (loaded.top..loaded.bottom).contains(&position.1)
+            })
+            .map(|(&position, &version)| (position, version))
+            .take(MAX_HOLDING)
+            .collect();
+        (!holding.is_empty()).then_some(ClientMessage::Holding(holding))
+    }
+
+    pub fn next_viewport_message(&mut self) -> Option<ClientMessage> {
+        if !self.changed || self.throttled_until.is_some_and(|until| Utc::now() < until) {
+            return None;
         }
+        self.changed = false;
+        self.throttled_until = None;
+        Some(ClientMessage::Viewport(self.loaded))
     }
 }
\ No newline at end of file
@@ -1,12 +1,15 @@
 use crate::camera::Camera;
 use crate::shader::HasBindGroup;
 use crate::texture::Texture;
+use std::collections::HashMap;
+use chrono::{DateTime, TimeDelta, Utc};
 use log::info;
 #[cfg(target_arch = "wasm32")]
 use web_sys::Performance;
 use wgpu::VertexFormat::{Float32x2, Sint32, Uint32};
 use wgpu::{BufferAddress, RenderPipeline, ShaderLocation, ShaderSource, VertexBufferLayout};
 use world::player::Player;
+use world::{ClientMessage, Position, CURSOR_INTERVAL_MS};
 
 #[derive(Debug)]
 pub struct Cursors {
@@ -16,11 +19,38 @@ pub struct Cursors {
     render_pipeline: RenderPipeline,
     instance_buffer: wgpu::Buffer,
     your_player_id: Option<String>,
+    /// The names of the players with cursors that have one, by the cursor's index
+    names: HashMap<usize, String>,
     #[cfg(target_arch = "wasm32")]
     performance: Performance,
 }
 
-impl Cursors {
+/// How long a cursor takes to glide to where it has moved to. This has to match cursors.wgsl.
+#[cfg(target_arch = "wasm32")]
+const GLIDE_MS: f32 = 200.0;
+
+/// Decides when to tell the server where our cursor is: only when it's over a different tile,
+/// and no more often than [CURSOR_INTERVAL_MS].
+#[derive(Debug, Default)]
+pub struct CursorThrottle {
+    sent: Option<Position>,
+    sent_at: Option<DateTime<Utc>>,
+}
+
+impl CursorThrottle {
+    pub fn message(&mut self, position: Position) -> Option<ClientMessage> {
+        if self.sent == Some(position) {
+            return None;
+        }
+        let now = Utc::now();
+        let interval = TimeDelta::milliseconds(CURSOR_INTERVAL_MS as i64);
+        if self.sent_at.is_some_and(|sent_at| now - sent_at < interval) {
+            return None;
+        }
+        self.sent = Some(position);
+        self.sent_at = Some(now);
+        Some(ClientMessage::CursorMoved(position))
+    }
 }
 
 impl Cursors {
@@ -105,6 +135,7 @@ impl Cursors {
             render_pipeline,
             instance_buffer,
             your_player_id: None,
+            names: HashMap::new(),
             #[cfg(target_arch = "wasm32")]
             performance: web_sys::window().unwrap().performance().unwrap(),
         }
@@ -148,14 +179,19 @@ impl Cursors {
     pub fn update_player(&mut self, player: &Player, queue: &wgpu::Queue) {
         let index = Player::numeric_hash(&player.player_id, Self::N_CURSORS);
         let cursor_instance = &mut self.cursors[index];
-        cursor_instance.prev_position = cursor_instance.position;
-        cursor_instance.position = [player.position.0 as f32, player.position.1 as f32];
-        if cursor_instance.prev_position.eq(&[0.0, 0.0]) {
-            cursor_instance.prev_position = cursor_instance.position;
+        let new_position = [player.position.0 as f32, player.position.1 as f32];
+        if cursor_instance.position.eq(&[0.0, 0.0]) {
+            cursor_instance.position = new_position;
         }
+        cursor_instance.prev_position = cursor_instance.position;
         #[cfg(target_arch = "wasm32")] {
-            cursor_instance.time_moved = self.performance.now() as i32;
+            // Cursors move often, so start from wherever it's drawn now, rather than jumping to
+            // where it was last heading
+            let now = self.performance.now() as i32;
+            cursor_instance.prev_position = cursor_instance.drawn_position(now);
+            cursor_instance.time_moved = now;
         }
+        cursor_instance.position = new_position;
         let offset = (size_of::<CursorInstance>() * index) as BufferAddress;
         let is_you = match &self.your_player_id {
             None => false,
@@ -167,7 +203,12 @@ impl Cursors {
             info!("Updating your cursor");
         }
         
-        cursor_instance.properties = CursorProperties::new(is_you, &player.player_id);
+        cursor_instance.properties = CursorProperties::new(is_you, player.colour);
+        if is_you || player.name.is_empty() {
+            self.names.remove(&index);
+        } else {
+            self.names.insert(index, player.name.clone());
+        }
         queue.write_buffer(&self.instance_buffer, offset, bytemuck::cast_slice(&self.cursors[index..index+1]));
     }
     
@@ -175,8 +216,19 @@ impl Cursors {
         let index = Player::numeric_hash(player_id, Self::N_CURSORS);
         let offset = (size_of::<CursorInstance>() * index) as BufferAddress;
         self.cursors[index] = CursorInstance::deleted();
+        self.names.remove(&index);
         queue.write_buffer(&self.instance_buffer, offset, bytemuck::cast_slice(&self.cursors[index..index+1]));
     }
+
+    /// Each name with where its cursor is drawn right now and its hue
+    #[cfg(target_arch = "wasm32")]
+    pub fn names(&self) -> impl Iterator<Item = (&str, [f32; 2], u8)> {
+        let now = self.performance.now() as i32;
+        self.names.iter().map(move |(&index, name)| {
+            let cursor = &self.cursors[index];
+            (name.as_str(), cursor.drawn_position(now), cursor.properties.hue())
+        })
+    }
 }
 
 #[repr(C)]
@@ -198,15 +250,19 @@ struct CursorProperties(u32);
 impl CursorProperties {
     const IS_YOU: u32 = 1;
     const CONNECTED: u32 = 2;
-    fn new(is_you: bool, player_id: &str) -> Self {
+    fn new(is_you: bool, hue: u8) -> Self {
         let mut props = 0;
         if is_you { props |= Self::IS_YOU }
         props |= Self::CONNECTED;
-        let hue = Player::numeric_hash(player_id, 256) as u32;
-        props |= hue << 8;
+        props |= (hue as u32) << 8;
         
         Self(props)
     }
+
+    #[cfg(target_arch = "wasm32")]
+    fn hue(&self) -> u8 {
+        (self.0 >> 8) as u8
+    }
     
     fn deleted() -> Self {
         Self(0)
@@ -215,6 +271,13 @@ impl CursorProperties {
 
 impl CursorInstance {
     const SHADER_LOCATION_OFFSET: ShaderLocation = 0;
+
+    /// Where the cursor is drawn at this time, partway from its previous position to its new one
+    #[cfg(target_arch = "wasm32")]
+    fn drawn_position(&self, now: i32) -> [f32; 2] {
+        let t = ((now - self.time_moved) as f32 / GLIDE_MS).clamp(0.0, 1.0);
+        [0, 1].map(|i| t * self.position[i] + (1.0 - t) * self.prev_position[i])
+    }
     
     fn deleted() -> Self {
         Self {
@@ -10,19 +10,23 @@ mod chunk_loader;
 mod chunk_update_queue;
 mod canvas2d_overlay;
 mod sweeper_socket;
+mod chat_panel;
+mod map_markers;
 
 use std::default::Default;
 use std::future::Future;
 use std::sync::Arc;
 use cgmath::Vector2;
 use chrono::prelude::*;
-use log::info;
+use log::{error, info};
 use winit::event::{ButtonSource, ElementState, MouseButton, MouseScrollDelta, PointerSource, WindowEvent};
 use winit::event_loop::{ActiveEventLoop, EventLoop};
+use winit::keyboard::ModifiersState;
 use winit::window::{Window, WindowAttributes, WindowId};
 use wgpu::{CompositeAlphaMode, PresentMode, ShaderSource};
 use winit::dpi::{PhysicalPosition, PhysicalSize};
 use world::Position;
+use world::player::Player;
 use crate::camera::Camera;
 use crate::shader::HasBindGroup;
 use crate::tilerender_texture::TileMapTexture;
@@ -35,9 +39,11 @@ use world::ServerMessage;
 use world::Tile;
 use crate::chunk_loader::ChunkLoader;
 use crate::chunk_update_queue::ChunkUpdateQueue;
-use crate::cursors::Cursors;
+use crate::cursors::{CursorThrottle, Cursors};
 use crate::fingers::Fingers;
 use crate::canvas2d_overlay::OverlayController;
+use crate::chat_panel::ChatPanel;
+use crate::map_markers::MapMarkers;
 use crate::sweeper_socket::interface::SweeperSocket;
 use crate::sweeper_socket::SocketWorld;
 use crate::tile_sprites::DarkMode;
@@ -119,13 +125,19 @@ struct State {
     tile_map_texture: TileMapTexture,
     world: SocketWorld,
     cursors: Cursors,
+    cursor_throttle: CursorThrottle,
+    /// Whether the mouse has been over the window, so that there's a cursor to show others
+    mouse_moved: bool,
     surface_configured: bool,
     right_mouse_button_down: bool,
+    modifiers: ModifiersState,
     fingers: Fingers,
     double_click_overlay: Option<Position>,
     chunk_loader: ChunkLoader,
     chunk_update_queue: ChunkUpdateQueue,
     overlay: OverlayController,
+    chat: ChatPanel,
+    markers: MapMarkers,
 }
 
 impl State {
@@ -291,13 +303,18 @@ impl State {
                 world,
                 tile_map_texture,
                 cursors,
+                cursor_throttle: Default::default(),
+                mouse_moved: false,
                 surface_configured: false,
                 right_mouse_button_down: false,
+                modifiers: ModifiersState::default(),
                 fingers: Fingers::new(view_matrix),
                 double_click_overlay: None,
                 chunk_loader,
                 chunk_update_queue: ChunkUpdateQueue::new(),
                 overlay: OverlayController::new(),
+                chat: ChatPanel::new(),
+                markers: MapMarkers::default(),
             }
         }
     }
@@ -421,6 +438,7 @@ impl State {
                 ..
             } => {
                 self.camera.update_mouse_position(&position);
+                self.mouse_moved = true;
                 if self.double_click_overlay.is_some() {
                     self.start_double_click_overlay(&position);
                 }
@@ -451,6 +469,21 @@ impl State {
             } => {
                 self.right_mouse_button_down = false;
             }
+            WindowEvent::PointerButton {
+                state: ElementState::Pressed,
+                button: ButtonSource::Mouse(MouseButton::Middle),
+                position,
+                ..
+            } => {
+                if self.modifiers.shift_key() {
+                    self.toggle_marker_at(&position);
+                } else {
+                    self.ping_at(&position);
+                }
+            }
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers.state();
+            }
             _ => {}
         }
     }
@@ -473,9 +506,19 @@ impl State {
         self.camera.write_to_queue(&self.queue, 0, self.tile_map_texture.texture_size());
         
         self.chunk_loader.query(self.camera.visible_world_rect());
-        match self.chunk_loader.next_query_message() {
-            None => {}
-            Some(query) => { self.world.send(query); }
+        if let Some(viewport) = self.chunk_loader.next_viewport_message() {
+            self.world.send(viewport);
+        }
+        // The tile under the mouse changes when the camera moves, as well as when the mouse does
+        if self.mouse_moved {
+            let position = as_world_position(self.camera.screen_to_world(&self.camera.mouse_position()));
+            if let Some(cursor_moved) = self.cursor_throttle.message(position) {
+                self.world.send(cursor_moved);
+            }
+        }
+
+        while let Some(chat) = self.chat.typed() {
+            self.world.send(chat);
         }
 
         // Load in any new chunks
@@ -506,9 +549,11 @@ impl State {
                     self.chunk_update_queue.add_chunk_ids(
                         self.world.world().apply_updated_rect(event.updated_rect())
                     );
-                    self.cursors.update_player(&event.player(), &self.queue);
+                    let Player { player_id, position, .. } = event.player();
+                    self.move_player(player_id, position);
                 }
                 ServerMessage::Chunk(chunk) => {
+                    self.chunk_loader.received(&chunk);
                     self.chunk_update_queue.add_chunk_ids(
                         vec![self.world.world().insert_chunk(chunk.clone())]
                     );
@@ -517,9 +562,30 @@ impl State {
                     self.world.world().players.insert(player.player_id.clone(), player.clone());
                     self.cursors.update_player(&player, &self.queue);
                 }
-                ServerMessage::Welcome(player) => {
-                    info!("Welcome");
+                ServerMessage::Cursors(players) => {
+                    for Player { player_id, position, .. } in players {
+                        self.move_player(player_id, position);
+                    }
+                }
+                ServerMessage::Welcome { player, stats, epoch, .. } => {
+                    info!("Welcome, {:?}", stats);
                     self.cursors.set_you(player.player_id.clone(), &player, &self.queue);
+                    // Every marker comes after this
+                    self.markers.set_you(player.player_id.clone());
+                    self.markers.clear_named();
+                    // Nor where our cursor is
+                    self.cursor_throttle = Default::default();
+                    #[cfg(target_arch = "wasm32")]
+                    if let Some(profile) = profile_from_url(&player) {
+                        self.world.send(profile);
+                    }
+                    // A new session on the server doesn't know what we're looking at, or what
+                    // we already have
+                    self.chunk_loader.welcome(epoch);
+                    if let Some(holding) = self.chunk_loader.holding_message() {
+                        self.world.send(holding);
+                    }
+                    self.chunk_loader.resend();
                 }
                 ServerMessage::Disconnected(player_id) => {
                     self.world.world().players.remove(&player_id);
@@ -530,7 +596,40 @@ impl State {
                         self.world.world().apply_updated_rect(rect)
                     );
                 }
-                ServerMessage::Connected => {}
+                ServerMessage::Restarting(_) | ServerMessage::Stale => {
+                    // The server forgets every player when it restarts, and when we fall
+                    // behind it sends the ones we can see again, and every marker
+                    self.markers.clear_named();
+                    for player_id in std::mem::take(&mut self.world.world().players).into_keys() {
+                        self.cursors.delete_player(&player_id, &self.queue);
+                    }
+                }
+                ServerMessage::Throttled(ms) => {
+                    info!("Sending too quickly, waiting {}ms", ms);
+                    self.chunk_loader.resend_after(chrono::TimeDelta::milliseconds(ms as i64));
+                }
+                ServerMessage::Error(reason) => {
+                    error!("Server rejected a message: {}", reason);
+                    #[cfg(target_arch = "wasm32")]
+                    self.chat.show_notice(&reason);
+                }
+                #[cfg(target_arch = "wasm32")]
+                ServerMessage::Chat(message) => {
+                    let hue = self.world.world().players.get(&message.player_id).map(|player| player.colour);
+                    self.chat.show(&message, hue);
+                }
+                #[cfg(not(target_arch = "wasm32"))]
+                ServerMessage::Chat(_) => {}
+                ServerMessage::Ping { player_id, position } => {
+                    self.markers.ping(player_id, position);
+                }
+                ServerMessage::Marker(marker) => {
+                    self.markers.add(marker);
+                }
+                ServerMessage::MarkerRemoved(id) => {
+                    self.markers.remove(id);
+                }
+                ServerMessage::Connected | ServerMessage::ResumeKey(_) | ServerMessage::Hello { .. } => {}
             }
         }
 
@@ -574,12 +673,28 @@ impl State {
         self.queue.submit(std::iter::once(encoder.finish()));
 
         self.cursors.render(&self.device, &self.queue, &view, &self.camera);
+        #[cfg(target_arch = "wasm32")]
+        self.overlay.draw_names(&self.camera, self.scale_factor, self.cursors.names());
+        #[cfg(target_arch = "wasm32")]
+        {
+            let markers = self.markers.shown(&self.world.world().players);
+            self.overlay.draw_markers(&self.camera, self.scale_factor, &markers);
+        }
 
         output.present();
 
         Ok(())
     }
 
+    /// Moves a player's cursor, keeping the name and colour we already know for them
+    fn move_player(&mut self, player_id: String, position: Position) {
+        let player = self.world.world().players.entry(player_id.clone())
+            .or_insert_with(|| Player::new(player_id));
+        player.position = position;
+        let player = player.clone();
+        self.cursors.update_player(&player, &self.queue);
+    }
+
     pub fn touch_at(&mut self, finger_position: &PhysicalPosition<f64>) {
         let position = as_world_position(self.camera.screen_to_world(finger_position));
         let tile = self.world.world().get_tile(&position);
@@ -661,6 +776,30 @@ impl State {
         }
     }
 
+    pub fn ping_at(&mut self, mouse_position: &PhysicalPosition<f64>) {
+        let position = as_world_position(self.camera.screen_to_world(mouse_position));
+        self.world.send(ClientMessage::Ping(position));
+    }
+
+    /// Removes our marker from the tile, or asks for a name and puts a new one there
+    pub fn toggle_marker_at(&mut self, mouse_position: &PhysicalPosition<f64>) {
+        let position = as_world_position(self.camera.screen_to_world(mouse_position));
+        if let Some(id) = self.markers.own_marker_at(position) {
+            self.world.send(ClientMessage::RemoveMarker(id));
+            return;
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            let name = web_sys::window()
+                .and_then(|window| window.prompt_with_message("Name this place").ok().flatten());
+            // The prompt takes the focus, so we don't hear about Shift being let go
+            self.modifiers = ModifiersState::default();
+            if let Some(name) = name {
+                self.world.send(ClientMessage::AddMarker { position, name });
+            }
+        }
+    }
+
     pub fn toggle_flag_at(&mut self, mouse_position: &PhysicalPosition<f64>) {
         let position_at_mouse = self.camera.screen_to_world(mouse_position);
         let position = as_world_position(position_at_mouse);
@@ -684,4 +823,16 @@ impl State {
 
 fn as_world_position(vector: Vector2<f64>) -> Position {
     Position(vector.x.floor() as i32, vector.y.floor() as i32)
-}
\ No newline at end of file
+}
+/// `?name=...&colour=...` in the page's address sets the player's name and the hue of their
+/// cursor, out of 256. Gives the message to send if they're not what the server has already.
+#[cfg(target_arch = "wasm32")]
+fn profile_from_url(player: &Player) -> Option<ClientMessage> {
+    let url = crate::url::url::UrlInfo::new();
+    let name = url.get_string("name").unwrap_or_else(|| player.name.clone());
+    let colour = url.get_string("colour")
+        .and_then(|colour| colour.parse().ok())
+        .unwrap_or(player.colour);
+    let changed = name != player.name || colour != player.colour;
+    changed.then_some(ClientMessage::SetProfile { name, colour })
+}
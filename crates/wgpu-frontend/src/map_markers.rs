@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+#[cfg(target_arch = "wasm32")]
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+#[cfg(target_arch = "wasm32")]
+use chrono::TimeDelta;
+#[cfg(target_arch = "wasm32")]
+use world::player::Player;
+#[cfg(target_arch = "wasm32")]
+use world::PING_DURATION_MS;
+use world::{Marker, Position};
+
+struct Ping {
+    player_id: String,
+    position: Position,
+    at: DateTime<Utc>,
+}
+
+/// A ping or marker, ready to be drawn
+#[cfg(target_arch = "wasm32")]
+pub struct ShownMarker {
+    pub position: Position,
+    pub label: String,
+    /// The hue out of 256 of the player who put it there
+    pub hue: u8,
+    /// How long ago a ping was sent, in milliseconds. Named markers don't have one.
+    pub ping_age_ms: Option<i64>,
+}
+
+/// The pings and named markers that the server has told us about
+#[derive(Default)]
+pub struct MapMarkers {
+    pings: Vec<Ping>,
+    named: BTreeMap<u32, Marker>,
+    you: Option<String>,
+}
+
+impl MapMarkers {
+    pub fn set_you(&mut self, player_id: String) {
+        self.you = Some(player_id);
+    }
+
+    /// A new ping from a player replaces their last one
+    pub fn ping(&mut self, player_id: String, position: Position) {
+        self.pings.retain(|ping| ping.player_id != player_id);
+        self.pings.push(Ping { player_id, position, at: Utc::now() });
+    }
+
+    pub fn add(&mut self, marker: Marker) {
+        self.named.insert(marker.id, marker);
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.named.remove(&id);
+    }
+
+    /// Forgets the named markers, for when the server is about to send all of them again
+    pub fn clear_named(&mut self) {
+        self.named.clear();
+    }
+
+    /// The ID of our own marker on the tile, if there is one
+    pub fn own_marker_at(&self, position: Position) -> Option<u32> {
+        self.named.values()
+            .find(|marker| Some(&marker.player_id) == self.you.as_ref() && marker.position == position)
+            .map(|marker| marker.id)
+    }
+
+    /// Forgets the pings that have run out, then gives everything that's left to draw. Pings
+    /// are labelled with the name of the player who sent them, if they have one.
+    #[cfg(target_arch = "wasm32")]
+    pub fn shown(&mut self, players: &HashMap<String, Player>) -> Vec<ShownMarker> {
+        let now = Utc::now();
+        self.pings.retain(|ping| now - ping.at < TimeDelta::milliseconds(PING_DURATION_MS as i64));
+        let player = |player_id: &str| players.get(player_id).cloned()
+            .unwrap_or_else(|| Player::new(player_id.to_string()));
+        let pings = self.pings.iter().map(|ping| {
+            let Player { name, colour, .. } = player(&ping.player_id);
+            ShownMarker {
+                position: ping.position,
+                label: name,
+                hue: colour,
+                ping_age_ms: Some((now - ping.at).num_milliseconds()),
+            }
+        });
+        let named = self.named.values().map(|marker| ShownMarker {
+            position: marker.position,
+            label: marker.name.clone(),
+            hue: player(&marker.player_id).colour,
+            ping_age_ms: None,
+        });
+        named.chain(pings).collect()
+    }
+}
@@ -4,27 +4,87 @@ use std::sync::mpsc::Receiver;
 use log::{error, info};
 use wasm_bindgen::closure::Closure;
 use wasm_bindgen::{JsCast, JsValue};
-use wasm_bindgen::__rt::IntoJsResult;
 use web_sys::{js_sys, BinaryType, ErrorEvent, MessageEvent, WebSocket};
-use world::{World, ClientMessage, ServerMessage, ServerMessageBundle};
+use world::{World, ClientMessage, ServerMessage, ServerMessageBundle, BINARY_CLIENT_MESSAGES, PROTOCOL_VERSION, SUPPORTED_FEATURES};
 use crate::sweeper_socket::interface::SweeperSocket;
 
 pub struct WebSocketWorld {
     world: World,
     send_queue: VecDeque<ClientMessage>,
     connection: ConnectionState,
+    /// When the server says it's restarting, don't try to reconnect until this time
+    reconnect_after: Option<f64>,
+    /// Lets us carry on with the same session if the connection drops
+    resume_key: Option<String>,
+    /// Lets us be the same player after the page reloads or the server restarts
+    token: Option<String>,
+    acks: Acks,
+    /// The server turned this client away, so there's no point reconnecting
+    incompatible: bool,
 }
 
+/// Keeps track of which numbered bundles have arrived, so the server can stop keeping them
+#[derive(Default)]
+struct Acks {
+    /// The number of the last bundle received in this session
+    last_received: Option<u16>,
+    /// Bundles received since we last told the server
+    unacked: u16,
+    last_ack_time: f64,
+}
+
+/// Where the token from the last `Welcome` is kept between page loads
+const TOKEN_KEY: &str = "sweeper-token";
+
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+/// Acknowledge bundles after this many have arrived...
+const ACK_EVERY: u16 = 32;
+/// ...or after this many milliseconds, whichever comes first
+const ACK_INTERVAL_MS: f64 = 1000.0;
+
 impl WebSocketWorld {
     pub fn new() -> Self {
         Self {
             world: World::new(),
             send_queue: Default::default(),
             connection: ConnectionState::Disconnected,
+            reconnect_after: None,
+            resume_key: None,
+            token: local_storage().and_then(|storage| storage.get_item(TOKEN_KEY).ok().flatten()),
+            acks: Default::default(),
+            incompatible: false,
         }
     }
 }
 
+impl Acks {
+    fn received(&mut self, number: u16, send_queue: &mut VecDeque<ClientMessage>) {
+        self.last_received = Some(number);
+        self.unacked += 1;
+        if self.unacked >= ACK_EVERY {
+            self.send(send_queue);
+        }
+    }
+
+    /// Acknowledges anything received a while ago that hasn't been yet
+    fn send_if_due(&mut self, send_queue: &mut VecDeque<ClientMessage>) {
+        if self.unacked > 0 && js_sys::Date::now() - self.last_ack_time > ACK_INTERVAL_MS {
+            self.send(send_queue);
+        }
+    }
+
+    fn send(&mut self, send_queue: &mut VecDeque<ClientMessage>) {
+        if let Some(last) = self.last_received {
+            send_queue.push_back(ClientMessage::Ack(last));
+        }
+        self.unacked = 0;
+        self.last_ack_time = js_sys::Date::now();
+    }
+}
+
 enum ConnectionState {
     Connected(Connection),
     Disconnected,
@@ -33,12 +93,18 @@ enum ConnectionState {
 struct Connection {
     web_socket: WebSocket,
     connected: bool,
+    /// The server has answered our hello
+    greeted: bool,
+    /// The server understands binary messages. Until it says so, messages are sent as JSON.
+    binary: bool,
     receiver: Receiver<WebSocketMessage>,
 }
 
 enum WebSocketMessage {
     Disconnect,
-    Message(ServerMessage)
+    Message(ServerMessage),
+    /// Comes after the messages in a numbered bundle
+    Received(u16),
 }
 
 impl Connection {
@@ -54,14 +120,17 @@ impl Connection {
         let onmessage_callback = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
             if let Ok(buffer) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
                 let array = js_sys::Uint8Array::new(&buffer);
-                match ServerMessageBundle::from_compressed(&*array.to_vec()) {
-                    Ok(ServerMessageBundle(messages)) => {
+                match ServerMessageBundle::from_compressed_with_number(&*array.to_vec()) {
+                    Ok((number, ServerMessageBundle(messages))) => {
                         for message in messages {
                             let _ = tx_clone.send(WebSocketMessage::Message(message));
                         }
+                        if let Some(number) = number {
+                            let _ = tx_clone.send(WebSocketMessage::Received(number));
+                        }
                     }
-                    Err(_) => {
-                        error!("Error receiving event")
+                    Err(error) => {
+                        error!("Couldn't read a bundle from the server: {}", error)
                     }
                 }
             }
@@ -74,7 +143,7 @@ impl Connection {
         let tx_clone = tx.clone();
         let onclose_callback = Closure::<dyn FnMut(_)>::new(move |e: ErrorEvent| {
             info!("error event: {:?}", e);
-            tx_clone.send(WebSocketMessage::Disconnect).unwrap();
+            let _ = tx_clone.send(WebSocketMessage::Disconnect);
         });
         ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
         onclose_callback.forget();
@@ -91,6 +160,8 @@ impl Connection {
             web_socket: ws,
             receiver: rx,
             connected: false,
+            greeted: false,
+            binary: false,
         })
     }
 }
@@ -106,44 +177,104 @@ impl SweeperSocket for WebSocketWorld {
                 // Send any messages in the queue:
                 if connection.connected {
                     while let Some(message) = self.send_queue.pop_front() {
-                        if let Ok(message) = (|| {
-                            let js_message = serde_wasm_bindgen::to_value(&message).into_js_result()?;
-                            js_sys::JSON::stringify(&js_message)
-                        })() {
-                            info!("Sending message: {:?}", message);
-                            match connection.web_socket.send_with_str(&message.as_string().unwrap_or("".to_string())) {
-                                Ok(_) => {}
-                                Err(_) => {
-                                    self.connection = ConnectionState::Disconnected;
-                                    return None;
-                                }
-                            }
+                        info!("Sending message: {:?}", message);
+                        let sent = if connection.binary {
+                            let bytes: Vec<u8> = (&message).into();
+                            connection.web_socket.send_with_u8_array(&bytes)
                         } else {
-                            error!("Message could not be serialized");
+                            let text = serde_json::to_string(&message).unwrap_or_default();
+                            connection.web_socket.send_with_str(&text)
+                        };
+                        if sent.is_err() {
+                            self.connection = ConnectionState::Disconnected;
+                            return None;
                         }
                     }
                 }
 
-                if let Ok(message) = connection.receiver.try_recv() {
+                if connection.connected {
+                    self.acks.send_if_due(&mut self.send_queue);
+                }
+
+                while let Ok(message) = connection.receiver.try_recv() {
                     match message {
                         WebSocketMessage::Disconnect => {
                             self.connection = ConnectionState::Disconnected;
-                            None
+                            return None;
+                        }
+                        WebSocketMessage::Received(number) => {
+                            self.acks.received(number, &mut self.send_queue);
                         }
                         WebSocketMessage::Message(message) => {
-                            match message {
+                            match &message {
                                 ServerMessage::Connected => {
                                     connection.connected = true;
-                                    self.send(ClientMessage::Connected);
+                                    let hello = ClientMessage::Hello {
+                                        version: PROTOCOL_VERSION,
+                                        features: SUPPORTED_FEATURES.iter().map(|feature| feature.to_string()).collect(),
+                                    };
+                                    let resume = self.resume_key.clone().zip(self.acks.last_received);
+                                    match resume {
+                                        Some((key, last)) => {
+                                            info!("Resuming session after bundle {}", last);
+                                            self.send_queue.push_front(ClientMessage::Resume { key, last });
+                                        }
+                                        None => match self.token.clone() {
+                                            Some(token) => self.send_queue.push_front(ClientMessage::Identify(token)),
+                                            None => self.send_queue.push_front(ClientMessage::Connected),
+                                        },
+                                    }
+                                    self.send_queue.push_front(hello);
+                                }
+                                ServerMessage::Hello { version, features } => {
+                                    info!("Server speaks protocol version {}, with {:?}", version, features);
+                                    connection.greeted = true;
+                                    connection.binary = features.iter().any(|feature| feature == BINARY_CLIENT_MESSAGES);
+                                }
+                                ServerMessage::Error(reason) if !connection.greeted => {
+                                    error!("The server won't talk to this client: {}", reason);
+                                    self.incompatible = true;
+                                }
+                                // An empty token means that this is a guest, because another
+                                // tab is already playing as the player this one asked to be
+                                ServerMessage::Welcome { token, .. } if !token.is_empty() => {
+                                    if self.token.as_ref() != Some(token) {
+                                        self.token = Some(token.clone());
+                                        if let Some(storage) = local_storage() {
+                                            storage.set_item(TOKEN_KEY, token).unwrap_or_default();
+                                        }
+                                    }
+                                }
+                                ServerMessage::ResumeKey(key) => {
+                                    // A new session, which numbers its bundles from the start
+                                    self.resume_key = Some(key.clone());
+                                    self.acks = Default::default();
+                                }
+                                ServerMessage::Restarting(seconds) => {
+                                    info!("Server restarting, reconnecting in {} seconds", seconds);
+                                    self.reconnect_after = Some(js_sys::Date::now() + *seconds as f64 * 1000.0);
+                                    // The session won't survive the restart
+                                    self.resume_key = None;
+                                    self.acks = Default::default();
                                 }
                                 _ => {}
                             }
-                            Some(message)
+                            return Some(message);
                         }
                     }
-                } else { None }
+                }
+                None
             }
             ConnectionState::Disconnected => {
+                if self.incompatible {
+                    return None;
+                }
+                if let Some(reconnect_after) = self.reconnect_after {
+                    if js_sys::Date::now() < reconnect_after {
+                        return None;
+                    }
+                    self.reconnect_after = None;
+                }
                 match Connection::new() {
                     Ok(connection) => {
                         self.connection = ConnectionState::Connected(connection);
@@ -25,7 +25,11 @@ impl SweeperSocket for LocalWorld {
             ClientMessage::Click(position) => { self.world.click(position, "") }
             ClientMessage::Flag(position) => { self.world.flag(position, "") }
             ClientMessage::DoubleClick(position) => { self.world.double_click(position, "") }
-            ClientMessage::Query(_) => { None }
+            ClientMessage::Query(_) | ClientMessage::Viewport(_) => { None }
+            ClientMessage::Ack(_) | ClientMessage::Resume { .. } | ClientMessage::Hello { .. } => { None }
+            ClientMessage::Identify(_) | ClientMessage::SetProfile { .. } | ClientMessage::Chat { .. } => { None }
+            ClientMessage::Ping(_) | ClientMessage::AddMarker { .. } | ClientMessage::RemoveMarker(_) => { None }
+            ClientMessage::Holding(_) | ClientMessage::CursorMoved(_) => { None }
         };
         if let Some(event) = event {
             self.message_queue.push_back(ServerMessage::Event(event));
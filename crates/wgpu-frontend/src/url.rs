@@ -46,6 +46,10 @@ pub mod url {
             self.url.search_params().get(key).unwrap_or_default().parse::<f64>().ok()
         }
 
+        pub fn get_string(&self, key: &str) -> Option<String> {
+            self.url.search_params().get(key)
+        }
+
         pub fn set_f64(&mut self, key: &str, value: f64) {
             self.url.search_params().set(key, &format!("{:.2}", value));
         }
@@ -10,7 +10,10 @@ use crate::tile::Tile;
 pub struct Chunk {
     pub tiles: ChunkTiles,
     pub position: ChunkPosition,
-    adjacent_mines_filled: bool
+    adjacent_mines_filled: bool,
+    /// Goes up every time a tile changes, so that a client that already has this version of
+    /// the chunk doesn't need to be sent it again
+    pub(crate) version: u32,
 }
 
 impl Chunk {
@@ -19,13 +22,15 @@ impl Chunk {
             tiles: ChunkTiles([Tile(0); 256]),
             position,
             adjacent_mines_filled: false,
+            version: 0,
         }
     }
 
     pub fn from_position_and_tiles(position: ChunkPosition, tiles: ChunkTiles) -> Self {
         Self {
             tiles, position,
-            adjacent_mines_filled: true
+            adjacent_mines_filled: true,
+            version: 0,
         }
     }
 }
@@ -46,9 +51,14 @@ impl Chunk {
     }
     pub fn set_tile(&mut self, position: Position, tile: Tile) -> Tile {
         self.tiles[*position.position_in_chunk()] = tile;
+        self.version = self.version.wrapping_add(1);
         tile
     }
 
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
     pub fn fill_adjacent_mines(surrounding_chunks: [&Chunk; 9]) -> Chunk {
         let is_mine = |position: Position| {
             let Position(x, y) = position;
@@ -98,6 +108,7 @@ impl Chunk {
             tiles: new_tiles,
             position: surrounding_chunks[4].position,
             adjacent_mines_filled: true,
+            version: surrounding_chunks[4].version,
         }
     }
 
@@ -108,6 +119,8 @@ impl Chunk {
 
 impl Arbitrary for Chunk {
     fn arbitrary(g: &mut Gen) -> Self {
-        Self::from_position_and_tiles(Position::arbitrary(g).chunk_position(), ChunkTiles::arbitrary(g))
+        let mut chunk = Self::from_position_and_tiles(Position::arbitrary(g).chunk_position(), ChunkTiles::arbitrary(g));
+        chunk.version = u32::arbitrary(g);
+        chunk
     }
 }
\ No newline at end of file
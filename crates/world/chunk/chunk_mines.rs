@@ -1,4 +1,5 @@
 use rand::prelude::{IteratorRandom, StdRng};
+use rand::SeedableRng;
 use std::ops::{Deref, DerefMut};
 use bitvec::order::Lsb0;
 use serde::{Deserialize, Serialize};
@@ -8,7 +9,7 @@ use crate::{Chunk, ChunkPosition, PositionInChunk};
 use crate::tile::Tile;
 
 #[derive(Serialize, Deserialize)]
-#[derive(Default, Clone, Debug)]
+#[derive(Default, Clone, Debug, PartialEq)]
 pub struct ChunkMines ([u8; 32]);
 
 impl ChunkMines {
@@ -20,6 +21,11 @@ impl ChunkMines {
         result
     }
 
+    /// The mines for the chunk at this position in a world with the given seed
+    pub fn for_chunk(position: ChunkPosition, seed: u64, number_of_mines: u8) -> Self {
+        Self::random(number_of_mines, StdRng::seed_from_u64(position.seed(seed)))
+    }
+
     pub fn positions(&self) -> Vec<PositionInChunk> {
         let n_ones = self.count_ones();
         let mut result = Vec::with_capacity(n_ones);
@@ -1,14 +1,13 @@
 use crate::chunk_store::ChunkStore;
 use crate::player::Player;
-use base64::prelude::BASE64_STANDARD;
-use base64::Engine;
-use rand::rngs::StdRng;
-use rand::{thread_rng, RngCore, SeedableRng};
 use std::collections::hash_map::Entry;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use crate::rules::ChunkAccess;
 
 pub mod chunk_store;
 pub mod player;
+pub mod rules;
 mod position;
 mod rect;
 mod chunk;
@@ -26,8 +25,11 @@ pub struct World {
     pub chunk_ids: HashMap<ChunkPosition, usize>,
     pub chunks: Vec<Chunk>,
     pub seed: u64,
+    pub mines_per_chunk: u8,
 
     pub generated_chunks: VecDeque<(ChunkPosition, ChunkMines)>,
+    /// Chunks that have had their adjacent mines filled in, and so are ready to be sent
+    pub filled_chunks: VecDeque<ChunkPosition>,
     pub chunk_store: ChunkStore,
     pub players: HashMap<String, Player>,
 }
@@ -76,22 +78,33 @@ impl World {
     }
 
     pub fn new() -> World {
-        let mut world = World {
+        Self::with_parameters(0, 40)
+    }
+
+    /// Makes a world whose chunks are generated from the seed, each with the given number of mines.
+    pub fn with_parameters(seed: u64, mines_per_chunk: u8) -> World {
+        let mut world = Self::empty(seed, mines_per_chunk);
+        world.generate_chunk(Position(0, 0));
+        world
+    }
+
+    /// Like [World::with_parameters], but without the chunk at the origin, for holding just one
+    /// part of a bigger world.
+    pub fn empty(seed: u64, mines_per_chunk: u8) -> World {
+        World {
             chunk_ids: Default::default(),
             chunks: vec![],
-            seed: 0,
+            seed,
+            mines_per_chunk,
             generated_chunks: Default::default(),
+            filled_chunks: Default::default(),
             chunk_store: ChunkStore::new(),
             players: Default::default(),
-        };
-        world.generate_chunk(Position(0, 0));
-        world
+        }
     }
     
     pub fn new_player_id(&mut self) -> String {
-        let mut buf: [u8; 8] = Default::default();
-        thread_rng().fill_bytes(&mut buf);
-        let player_id = BASE64_STANDARD.encode(buf);
+        let player_id = Player::random_id();
         let new_player = Player::new(player_id.clone());
         self.players.insert(player_id.clone(), new_player);
         player_id
@@ -138,8 +151,7 @@ impl World {
             Entry::Occupied(entry) => *entry.get(),
             Entry::Vacant(entry) => {
                 let position = *entry.key();
-                let rng = StdRng::seed_from_u64(position.seed(0));
-                let mines = ChunkMines::random(40, rng);
+                let mines = ChunkMines::for_chunk(position, self.seed, self.mines_per_chunk);
                 let new_chunk = mines.to_chunk(position);
                 entry.insert(new_id);
                 self.chunks.push(new_chunk);
@@ -150,149 +162,71 @@ impl World {
         }
     }
 
+    /// The mines that [World::generate_chunk] places in a new chunk. This only depends on the
+    /// position and the world's parameters, so it can be used to check that a logged chunk
+    /// matches what we would generate.
+    pub fn generate_mines(&self, position: ChunkPosition) -> ChunkMines {
+        ChunkMines::for_chunk(position, self.seed, self.mines_per_chunk)
+    }
+
     pub fn generate_surrounding_chunks(&mut self, position: Position) -> [usize; 9] {
-        [
-            self.generate_chunk(&position + (-16, -16)),
-            self.generate_chunk(&position + (-16,   0)),
-            self.generate_chunk(&position + (-16,  16)),
-            self.generate_chunk(&position + (  0, -16)),
-            self.generate_chunk(&position + (  0,   0)),
-            self.generate_chunk(&position + (  0,  16)),
-            self.generate_chunk(&position + ( 16, -16)),
-            self.generate_chunk(&position + ( 16,   0)),
-            self.generate_chunk(&position + ( 16,  16)),
-        ]
+        rules::surrounding_chunks(position.chunk_position())
+            .map(|chunk_position| self.generate_chunk(chunk_position.position()))
     }
 
     pub fn fill_adjacent_mines(&mut self, position: Position) {
-        let surrounding_chunk_ids = self.generate_surrounding_chunks(position);
-        let chunk_id = surrounding_chunk_ids[4];
-        if let Some(chunk) = self.chunks.get(chunk_id) {
-            if chunk.adjacent_mines_filled() {
-                return;
-            }
-        }
-        let surrounding_chunks = surrounding_chunk_ids.map(|chunk_id| unsafe {
-            self.chunks.get_unchecked(chunk_id)
-        });
-        self.chunks[surrounding_chunk_ids[4]] = Chunk::fill_adjacent_mines(surrounding_chunks)
+        let Ok(()) = rules::fill_adjacent_mines(self, position.chunk_position());
     }
     
     fn set_player_position(&mut self, player_id: &str, position: Position) {
-        self.players.insert(player_id.to_string(), Player {
-            player_id: player_id.to_string(),
-            position,
-        });
+        self.players.entry(player_id.to_string())
+            .or_insert_with(|| Player::new(player_id.to_string()))
+            .position = position;
     }
 
     pub fn click(&mut self, at: Position, by_player_id: &str) -> Option<Event> {
         self.set_player_position(by_player_id, at);
-        let updated = self.reveal(vec![at]);
-        if !updated.is_empty() {
-            Some(Event::Clicked {
-                player_id: by_player_id.to_string(),
-                at,
-                updated,
-            })
-        } else {
-            None
-        }
-    }
-    
-    fn reveal(&mut self, mut to_reveal: Vec<Position>) -> UpdatedRect {
-        if to_reveal.is_empty() {
-            return Default::default();
-        }
-
-        let mut updated_chunk_ids = HashSet::new();
-        let mut updated_tiles = vec![];
-
-        while let Some(position) = to_reveal.pop() {
-            let current_chunk_id = self.generate_chunk(position);
-            self.fill_adjacent_mines(position);
-            let current_chunk = match self.chunks.get_mut(current_chunk_id) {
-                None => continue,
-                Some(c) => c,
-            };
-            // It's okay to get unchecked here because we know that tile_index() always returns a number < 256
-            let tile = &mut current_chunk.tiles[position.tile_index()];
-            if !tile.is_revealed() {
-                *tile = tile.with_revealed();
-                if tile.adjacent() == 0 {
-                    to_reveal.append(&mut position.neighbors());
-                }
-                updated_tiles.push(UpdatedTile {position, tile: *tile});
-                updated_chunk_ids.insert(current_chunk_id);
-            }
-        }
-
-        UpdatedRect::new(updated_tiles)
+        let Ok(event) = rules::click(self, at, by_player_id);
+        event
     }
     
     pub fn check_double_click(&self, position: &Position) -> Option<Vec<Position>> {
-        let tile = self.get_tile(position);
-        if !tile.is_revealed() || tile.adjacent() == 0 {
-            return None;
-        }
-        let mut surrounding_flags = 0;
-        let mut to_reveal = vec![];
-        for pos in position.neighbors() {
-            // TODO: speed up this part, it will be slow because it keeps getting the same chunk from the hashmap
-            if let Some(chunk) = self.get_chunk(pos) {
-                let t = chunk.get_tile(pos);
-                if !t.is_revealed() {
-                    if t.is_flag() {
-                        surrounding_flags += 1;
-                    } else {
-                        to_reveal.push(pos);
-                    }
-                } else if t.is_mine() {
-                    surrounding_flags += 1;
-                }
-            }
-        }
-        if surrounding_flags == tile.adjacent() {
-            Some(to_reveal)
-        } else {
-            None
-        }
+        let Ok(to_reveal) = rules::check_double_click(self, position);
+        to_reveal
     }
 
     pub fn double_click(&mut self, position: Position, by_player_id: &str) -> Option<Event> {
         self.set_player_position(by_player_id, position);
-        if let Some(to_reveal) = self.check_double_click(&position) {
-            let updated = self.reveal(to_reveal);
-            Some(Event::DoubleClicked {
-                player_id: by_player_id.to_string(),
-                at: position,
-                updated
-            })
-        } else { None }
+        let Ok(event) = rules::double_click(self, position, by_player_id);
+        event
     }
 
     pub fn flag(&mut self, position: Position, by_player_id: &str) -> Option<Event> {
-        let chunk_id = *self.get_chunk_id(position)?;
+        self.get_chunk_id(position)?;
         self.set_player_position(by_player_id, position);
-        let chunk = self.chunks.get_mut(chunk_id)?;
-        let tile = &mut chunk.tiles[*position.position_in_chunk()];
-        if !tile.is_revealed() {
-            if tile.is_flag() {
-                // Unflag
-                *tile = tile.without_flag();
-                Some(Event::Unflag {
-                    player_id: by_player_id.to_string(),
-                    at: position
-                })
-            } else {
-                // Flag
-                *tile = tile.with_flag();
-                Some(Event::Flag {
-                    player_id: by_player_id.to_string(),
-                    at: position
-                })
-            }
-        } else {
-            None
-        }
+        let Ok(event) = rules::flag(self, position, by_player_id);
+        event
     }
-}
\ No newline at end of file
+}
+
+impl ChunkAccess for World {
+    type Error = Infallible;
+
+    fn chunk(&self, position: ChunkPosition) -> Result<Option<&Chunk>, Infallible> {
+        Ok(self.get_chunk(position.position()))
+    }
+
+    fn chunk_mut(&mut self, position: ChunkPosition) -> Result<Option<&mut Chunk>, Infallible> {
+        Ok(self.chunk_ids.get(&position).map(|&chunk_id| &mut self.chunks[chunk_id]))
+    }
+
+    fn generate(&mut self, position: ChunkPosition) -> Result<(), Infallible> {
+        self.generate_chunk(position.position());
+        Ok(())
+    }
+
+    fn filled(&mut self, position: ChunkPosition) -> Result<(), Infallible> {
+        self.filled_chunks.push_back(position);
+        Ok(())
+    }
+}
@@ -1,6 +1,14 @@
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use rand::{thread_rng, RngCore};
 use serde::{Deserialize, Serialize};
-use crate::Event;
+use crate::{Event, ServerMessageError};
 use crate::Position;
+use crate::ServerMessageErrorKind::BadPlayer;
+use crate::updates::{compress_string, read_string};
+
+/// The longest name a player can pick, in characters
+pub const MAX_NAME_LENGTH: usize = 24;
 
 #[derive(Serialize, Deserialize)]
 #[derive(Debug, Clone)]
@@ -8,16 +16,97 @@ use crate::Position;
 pub struct Player {
     pub player_id: String,
     pub position: Position,
+    /// Empty until the player picks one
+    pub name: String,
+    /// The hue of the player's cursor, out of 256
+    pub colour: u8,
+}
+
+/// What a player has done. These are kept with the player's identity, so that they carry on
+/// when the player comes back.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct PlayerStats {
+    /// Clicks and double clicks that revealed something
+    pub clicks: u32,
+    pub flags: u32,
+    pub tiles_revealed: u32,
+    pub mines_revealed: u32,
+}
+
+impl PlayerStats {
+    pub fn record(&mut self, event: &Event) {
+        match event {
+            Event::Clicked { updated, .. } |
+            Event::DoubleClicked { updated, .. } => {
+                let revealed: Vec<_> = updated.tiles_updated().into_iter()
+                    .filter(|updated| updated.tile.is_revealed())
+                    .collect();
+                self.clicks = self.clicks.saturating_add(1);
+                self.tiles_revealed = self.tiles_revealed.saturating_add(revealed.len() as u32);
+                let mines = revealed.iter().filter(|updated| updated.tile.is_mine()).count();
+                self.mines_revealed = self.mines_revealed.saturating_add(mines as u32);
+            }
+            Event::Flag { .. } => self.flags = self.flags.saturating_add(1),
+            Event::Unflag { .. } => {}
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.clicks, self.flags, self.tiles_revealed, self.mines_revealed].iter()
+            .flat_map(|stat| stat.to_be_bytes())
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut stats = bytes.chunks_exact(4).map(|stat| u32::from_be_bytes(stat.try_into().unwrap()));
+        Some(Self {
+            clicks: stats.next()?,
+            flags: stats.next()?,
+            tiles_revealed: stats.next()?,
+            mines_revealed: stats.next()?,
+        })
+    }
 }
 
 impl Player {
+    /// A player who hasn't picked a name, with a colour that comes from their ID
     pub fn new(player_id: String) -> Self {
         Self {
+            colour: Self::numeric_hash(&player_id, 256) as u8,
             player_id,
             position: Position::origin(),
+            name: String::new(),
         }
     }
 
+    /// Tidies up the spaces in the name, and checks that it's short enough and only has
+    /// letters, numbers, spaces and a little punctuation in it. An empty name is allowed, and
+    /// means the player doesn't have one.
+    pub fn clean_name(name: &str) -> Result<String, String> {
+        let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+        if name.chars().count() > MAX_NAME_LENGTH {
+            return Err(format!("Names can't be longer than {MAX_NAME_LENGTH} characters"));
+        }
+        if let Some(bad) = name.chars().find(|&c| !c.is_alphanumeric() && !" -_.'".contains(c)) {
+            return Err(format!("Names can't have {bad:?} in them"));
+        }
+        Ok(name)
+    }
+
+    pub fn random_id() -> String {
+        let mut buf: [u8; 8] = Default::default();
+        thread_rng().fill_bytes(&mut buf);
+        BASE64_STANDARD.encode(buf)
+    }
+
+    /// A secret that's too long to guess, for a client to prove that it's the one that was given it
+    pub fn random_token() -> String {
+        let mut buf: [u8; 16] = Default::default();
+        thread_rng().fill_bytes(&mut buf);
+        BASE64_STANDARD.encode(buf)
+    }
+
     pub fn update(&mut self, event: &Event) {
         match event {
             Event::Clicked { at, .. } |
@@ -42,29 +131,39 @@ impl Player {
 
     pub fn compress(&self, header: u8) -> Vec<u8> {
         let mut binary = vec![header];
-        binary.append(&mut self.position.0.to_be_bytes().to_vec());
-        binary.append(&mut self.position.1.to_be_bytes().to_vec());
-        binary.append(&mut self.player_id.clone().into_bytes());
+        self.compress_into(&mut binary);
         binary
     }
 
-    pub fn from_compressed(compressed: &[u8]) -> Option<Player> {
-        let header = String::from_utf8_lossy(&compressed[0..=0]);
-        if header != "p" && header != "w" {
-            return None;
+    /// The position, ID, name and colour, which `Player` and `Welcome` messages both start with
+    pub(crate) fn compress_into(&self, binary: &mut Vec<u8>) {
+        binary.extend_from_slice(&self.position.0.to_be_bytes());
+        binary.extend_from_slice(&self.position.1.to_be_bytes());
+        compress_string(&self.player_id, binary);
+        compress_string(&self.name, binary);
+        binary.push(self.colour);
+    }
+
+    pub fn from_compressed(compressed: &[u8]) -> Result<Player, ServerMessageError> {
+        if compressed.first() != Some(&b'p') {
+            return Err(ServerMessageError::new(BadPlayer, 0, "expected a player header"));
         }
-        let mut index = 1;
-        let position = {
-            let x = i32::from_be_bytes(*compressed[index..].first_chunk()?);
-            let y = i32::from_be_bytes(*compressed[index+4..].first_chunk()?);
-            index += 8;
-            Position(x, y)
-        };
-        let player_id = String::from_utf8_lossy(&compressed[index..]).to_string();
-
-        Some(Player {
-            player_id,
-            position
-        })
+        Ok(Self::read(compressed, 1)?.0)
+    }
+
+    /// Reads what [Player::compress_into] wrote, starting `start` bytes into the message, and
+    /// gives back where it ended
+    pub(crate) fn read(compressed: &[u8], start: usize) -> Result<(Player, usize), ServerMessageError> {
+        let position = Position::from_compressed(&compressed[start..])
+            .ok_or(ServerMessageError::new(BadPlayer, start, "expected 8 bytes of position"))?;
+        let (player_id, rest) = read_string(&compressed[start + 8..])
+            .ok_or(ServerMessageError::new(BadPlayer, start + 8, "player ID cut short or not UTF-8"))?;
+        let offset = compressed.len() - rest.len();
+        let (name, rest) = read_string(rest)
+            .ok_or(ServerMessageError::new(BadPlayer, offset, "name cut short or not UTF-8"))?;
+        let offset = compressed.len() - rest.len();
+        let &colour = rest.first()
+            .ok_or(ServerMessageError::new(BadPlayer, offset, "expected a colour"))?;
+        Ok((Player { player_id, position, name, colour }, offset + 1))
     }
 }
@@ -44,7 +44,7 @@ impl Position {
 
     pub fn from_compressed(bytes: &[u8]) -> Option<Self> {
         let x = i32::from_be_bytes(*bytes[0..].first_chunk()?);
-        let y = i32::from_be_bytes(*bytes[4..].first_chunk()?);
+        let y = i32::from_be_bytes(*bytes.get(4..)?.first_chunk()?);
         Some(Position(x, y))
     }
 }
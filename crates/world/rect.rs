@@ -116,6 +116,26 @@ impl Rect {
         }
     }
 
+    /// The parts of this rect that aren't in the other one, as up to four rects that don't
+    /// overlap
+    pub fn subtract(&self, other: &Rect) -> Vec<Rect> {
+        let left = max(self.left, other.left);
+        let right = min(self.right, other.right);
+        let top = max(self.top, other.top);
+        let bottom = min(self.bottom, other.bottom);
+        if left >= right || top >= bottom {
+            return vec![*self];
+        }
+        [
+            Rect { bottom: top, ..*self },
+            Rect { top: bottom, ..*self },
+            Rect { top, bottom, right: left, ..*self },
+            Rect { top, bottom, left: right, ..*self },
+        ].into_iter()
+            .filter(|rect| rect.width() > 0 && rect.height() > 0)
+            .collect()
+    }
+
     pub fn top_left(&self) -> Position {
         Position(self.left, self.top)
     }
@@ -0,0 +1,302 @@
+use std::collections::HashSet;
+use crate::{Chunk, ChunkPosition, Event, Position, Tile, UpdatedRect, UpdatedTile};
+
+/// Where the rules of the game get their chunks from. [World](crate::World) has all of its
+/// chunks to hand, but something that only holds part of the world can return an error to
+/// stop an action, and the action won't have revealed or flagged anything when it stops.
+pub trait ChunkAccess {
+    type Error;
+
+    /// The chunk at the position, if it has been generated
+    fn chunk(&self, position: ChunkPosition) -> Result<Option<&Chunk>, Self::Error>;
+
+    fn chunk_mut(&mut self, position: ChunkPosition) -> Result<Option<&mut Chunk>, Self::Error>;
+
+    /// Generates the chunk at the position if it doesn't exist yet
+    fn generate(&mut self, position: ChunkPosition) -> Result<(), Self::Error>;
+
+    /// Called once the mines next to every tile in the chunk have been counted, which is when
+    /// it can first be shown to players
+    fn filled(&mut self, _position: ChunkPosition) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn tile(&self, position: Position) -> Result<Tile, Self::Error> {
+        Ok(self.chunk(position.chunk_position())?
+            .map(|chunk| chunk.get_tile(position))
+            .unwrap_or(Tile::empty()))
+    }
+}
+
+pub fn click<W: ChunkAccess>(world: &mut W, at: Position, by_player_id: &str) -> Result<Option<Event>, W::Error> {
+    let updated = reveal(world, vec![at])?;
+    if !updated.is_empty() {
+        Ok(Some(Event::Clicked {
+            player_id: by_player_id.to_string(),
+            at,
+            updated,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn double_click<W: ChunkAccess>(world: &mut W, position: Position, by_player_id: &str) -> Result<Option<Event>, W::Error> {
+    if let Some(to_reveal) = check_double_click(world, &position)? {
+        let updated = reveal(world, to_reveal)?;
+        Ok(Some(Event::DoubleClicked {
+            player_id: by_player_id.to_string(),
+            at: position,
+            updated
+        }))
+    } else { Ok(None) }
+}
+
+/// Flags or unflags the tile. Returns `None` for tiles that are revealed or haven't been
+/// generated yet.
+pub fn flag<W: ChunkAccess>(world: &mut W, position: Position, by_player_id: &str) -> Result<Option<Event>, W::Error> {
+    let Some(chunk) = world.chunk_mut(position.chunk_position())? else {
+        return Ok(None);
+    };
+    let tile = chunk.get_tile(position);
+    if !tile.is_revealed() {
+        if tile.is_flag() {
+            // Unflag
+            chunk.set_tile(position, tile.without_flag());
+            Ok(Some(Event::Unflag {
+                player_id: by_player_id.to_string(),
+                at: position
+            }))
+        } else {
+            // Flag
+            chunk.set_tile(position, tile.with_flag());
+            Ok(Some(Event::Flag {
+                player_id: by_player_id.to_string(),
+                at: position
+            }))
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn check_double_click<W: ChunkAccess>(world: &W, position: &Position) -> Result<Option<Vec<Position>>, W::Error> {
+    let tile = world.tile(*position)?;
+    if !tile.is_revealed() || tile.adjacent() == 0 {
+        return Ok(None);
+    }
+    let mut surrounding_flags = 0;
+    let mut to_reveal = vec![];
+    for pos in position.neighbors() {
+        if let Some(chunk) = world.chunk(pos.chunk_position())? {
+            let t = chunk.get_tile(pos);
+            if !t.is_revealed() {
+                if t.is_flag() {
+                    surrounding_flags += 1;
+                } else {
+                    to_reveal.push(pos);
+                }
+            } else if t.is_mine() {
+                surrounding_flags += 1;
+            }
+        }
+    }
+    if surrounding_flags == tile.adjacent() {
+        Ok(Some(to_reveal))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reveals the tiles, and keeps going through the neighbours of any that have no adjacent mines.
+/// The whole cascade is worked out before any tile is revealed, so if the world returns an
+/// error, the only things that have changed are chunks being generated and filled in.
+pub fn reveal<W: ChunkAccess>(world: &mut W, mut to_reveal: Vec<Position>) -> Result<UpdatedRect, W::Error> {
+    if to_reveal.is_empty() {
+        return Ok(Default::default());
+    }
+
+    let mut revealed = HashSet::new();
+    let mut updated_tiles = vec![];
+
+    while let Some(position) = to_reveal.pop() {
+        let chunk_position = position.chunk_position();
+        world.generate(chunk_position)?;
+        fill_adjacent_mines(world, chunk_position)?;
+        let tile = world.tile(position)?;
+        if !tile.is_revealed() && revealed.insert(position) {
+            let tile = tile.with_revealed();
+            if tile.adjacent() == 0 {
+                to_reveal.append(&mut position.neighbors());
+            }
+            updated_tiles.push(UpdatedTile { position, tile });
+        }
+    }
+
+    // Every chunk here was generated above, so this can't fail part of the way through
+    for &UpdatedTile { position, tile } in &updated_tiles {
+        if let Some(chunk) = world.chunk_mut(position.chunk_position())? {
+            chunk.set_tile(position, tile);
+        }
+    }
+
+    Ok(UpdatedRect::new(updated_tiles))
+}
+
+/// Counts the mines next to every tile in the chunk, which needs all 8 chunks around it to have
+/// been generated.
+pub fn fill_adjacent_mines<W: ChunkAccess>(world: &mut W, position: ChunkPosition) -> Result<(), W::Error> {
+    if world.chunk(position)?.is_some_and(|chunk| chunk.adjacent_mines_filled()) {
+        return Ok(());
+    }
+    let surrounding_positions = surrounding_chunks(position);
+    for &chunk_position in &surrounding_positions {
+        world.generate(chunk_position)?;
+    }
+    let mut surrounding_chunks = Vec::with_capacity(9);
+    for &chunk_position in &surrounding_positions {
+        match world.chunk(chunk_position)? {
+            Some(chunk) => surrounding_chunks.push(chunk),
+            None => return Ok(()),
+        }
+    }
+    let Ok(surrounding_chunks) = surrounding_chunks.try_into() else { return Ok(()) };
+    let filled = Chunk::fill_adjacent_mines(surrounding_chunks);
+    if let Some(chunk) = world.chunk_mut(position)? {
+        *chunk = filled;
+        world.filled(position)?;
+    }
+    Ok(())
+}
+
+/// The chunk and the 8 around it, in the order [Chunk::fill_adjacent_mines] wants them
+pub fn surrounding_chunks(position: ChunkPosition) -> [ChunkPosition; 9] {
+    let Position(x, y) = position.position();
+    [
+        ChunkPosition::new(x - 16, y - 16),
+        ChunkPosition::new(x - 16, y),
+        ChunkPosition::new(x - 16, y + 16),
+        ChunkPosition::new(x, y - 16),
+        ChunkPosition::new(x, y),
+        ChunkPosition::new(x, y + 16),
+        ChunkPosition::new(x + 16, y - 16),
+        ChunkPosition::new(x + 16, y),
+        ChunkPosition::new(x + 16, y + 16),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use quickcheck_macros::quickcheck;
+    use crate::rules::{self, ChunkAccess};
+    use crate::{Chunk, ChunkPosition, Position, World};
+
+    /// A world that can only be used within some chunks, like a server that has only locked
+    /// some of its regions.
+    struct Partial {
+        world: World,
+        allowed: HashSet<ChunkPosition>,
+    }
+
+    impl Partial {
+        fn check(&self, position: ChunkPosition) -> Result<(), ChunkPosition> {
+            if self.allowed.contains(&position) { Ok(()) } else { Err(position) }
+        }
+    }
+
+    impl ChunkAccess for Partial {
+        type Error = ChunkPosition;
+
+        fn chunk(&self, position: ChunkPosition) -> Result<Option<&Chunk>, ChunkPosition> {
+            self.check(position)?;
+            Ok(self.world.get_chunk(position.position()))
+        }
+
+        fn chunk_mut(&mut self, position: ChunkPosition) -> Result<Option<&mut Chunk>, ChunkPosition> {
+            self.check(position)?;
+            let Ok(chunk) = self.world.chunk_mut(position);
+            Ok(chunk)
+        }
+
+        fn generate(&mut self, position: ChunkPosition) -> Result<(), ChunkPosition> {
+            self.check(position)?;
+            self.world.generate_chunk(position.position());
+            Ok(())
+        }
+    }
+
+    fn revealed_tiles(world: &World) -> usize {
+        world.chunks.iter()
+            .map(|chunk| chunk.tiles.0.iter().filter(|tile| tile.is_revealed()).count())
+            .sum()
+    }
+
+    #[quickcheck]
+    fn stopped_clicks_reveal_nothing(x: i8, y: i8) {
+        let at = Position(x as i32, y as i32);
+        let mut partial = Partial {
+            world: World::new(),
+            allowed: rules::surrounding_chunks(at.chunk_position()).into(),
+        };
+        let mut whole = World::new();
+        match rules::click(&mut partial, at, "player") {
+            Ok(event) => assert_eq!(event, whole.click(at, "player")),
+            Err(position) => {
+                assert!(!partial.allowed.contains(&position));
+                assert_eq!(revealed_tiles(&partial.world), 0);
+            }
+        }
+    }
+
+    #[quickcheck]
+    fn growing_the_allowed_area_finishes_the_click(x: i8, y: i8) {
+        let at = Position(x as i32, y as i32);
+        let mut partial = Partial {
+            world: World::new(),
+            allowed: HashSet::new(),
+        };
+        let event = loop {
+            match rules::click(&mut partial, at, "player") {
+                Ok(event) => break event,
+                Err(position) => { partial.allowed.insert(position); }
+            }
+        };
+        assert_eq!(event, World::new().click(at, "player"));
+    }
+
+    #[test]
+    fn changes_bump_the_chunk_version() {
+        let mut world = World::new();
+        let at = Position(5, 5);
+        world.click(at, "player");
+        assert!(world.get_chunk(at).unwrap().version() > 0);
+
+        let hidden = world.chunks.iter()
+            .flat_map(|chunk| chunk.rect().positions())
+            .find(|position| !world.get_tile(position).is_revealed())
+            .unwrap();
+        let version = |world: &World| world.get_chunk(hidden).unwrap().version();
+        let before = version(&world);
+        rules::flag(&mut world, hidden, "player").unwrap();
+        assert_eq!(version(&world), before + 1);
+        rules::flag(&mut world, hidden, "player").unwrap();
+        assert_eq!(version(&world), before + 2);
+    }
+
+    #[test]
+    fn filled_chunks_are_recorded_once() {
+        let mut world = World::new();
+        let at = Position(5, 5);
+        world.click(at, "player");
+        let filled: HashSet<_> = world.filled_chunks.drain(..).collect();
+        assert!(filled.contains(&at.chunk_position()));
+        // Every chunk that was filled in can be sent, and every chunk that can be sent was filled
+        for chunk in &world.chunks {
+            assert_eq!(filled.contains(&chunk.position), chunk.should_send());
+        }
+
+        world.click(at, "player");
+        assert!(world.filled_chunks.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+use quickcheck::{Arbitrary, Gen};
+use serde::{Deserialize, Serialize};
+use crate::{Position, ServerMessageError};
+use crate::ServerMessageErrorKind::BadChat;
+use crate::updates::{compress_string, read_string};
+
+/// The longest chat message a player can send, in characters
+pub const MAX_CHAT_LENGTH: usize = 280;
+
+/// Who hears a chat message
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChatChannel {
+    /// Everyone playing
+    Global,
+    /// Only the players looking at where the sender's cursor is
+    Nearby,
+}
+
+impl ChatChannel {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            ChatChannel::Global => b'g',
+            ChatChannel::Nearby => b'n',
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            b'g' => Some(ChatChannel::Global),
+            b'n' => Some(ChatChannel::Nearby),
+            _ => None,
+        }
+    }
+}
+
+/// A chat message, as the server passes it on. The name and position are the sender's when
+/// they sent it.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChatMessage {
+    pub channel: ChatChannel,
+    pub player_id: String,
+    pub name: String,
+    pub position: Position,
+    /// Milliseconds since the Unix epoch
+    pub time: u64,
+    pub text: String,
+}
+
+/// Characters that change how the text around them is shown without being seen themselves,
+/// like U+202E, which makes the rest of a line read right to left, so that one player could
+/// make their message look like someone else's. The zero width joiner stays, for emoji.
+fn is_format(c: char) -> bool {
+    matches!(c,
+        '\u{AD}' | '\u{61C}' | '\u{180E}' | '\u{200B}' | '\u{200C}' | '\u{200E}' | '\u{200F}' |
+        '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2064}' | '\u{2066}'..='\u{206F}' |
+        '\u{FEFF}' | '\u{FFF9}'..='\u{FFFB}' | '\u{E0001}' | '\u{E0020}'..='\u{E007F}'
+    )
+}
+
+impl ChatMessage {
+    /// The text with control characters turned into spaces, invisible formatting characters
+    /// taken out and the whitespace at either end trimmed, or why it can't be sent
+    pub fn clean_text(text: &str) -> Result<String, String> {
+        let text: String = text.chars()
+            .filter(|&c| !is_format(c))
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        let text = text.trim().to_string();
+        if text.is_empty() {
+            return Err("Chat messages can't be empty".to_string());
+        }
+        if text.chars().count() > MAX_CHAT_LENGTH {
+            return Err(format!("Chat messages can't be longer than {MAX_CHAT_LENGTH} characters"));
+        }
+        Ok(text)
+    }
+
+    pub fn compress(&self, header: u8) -> Vec<u8> {
+        let Position(x, y) = self.position;
+        let mut result = vec![header, self.channel.to_byte()];
+        result.extend_from_slice(&x.to_be_bytes());
+        result.extend_from_slice(&y.to_be_bytes());
+        result.extend_from_slice(&self.time.to_be_bytes());
+        compress_string(&self.player_id, &mut result);
+        compress_string(&self.name, &mut result);
+        compress_string(&self.text, &mut result);
+        result
+    }
+
+    /// Reads a message compressed with [ChatMessage::compress], header and all
+    pub fn from_compressed(compressed: &[u8]) -> Result<Self, ServerMessageError> {
+        let channel = compressed.get(1).copied().and_then(ChatChannel::from_byte)
+            .ok_or(ServerMessageError::new(BadChat, 1, "expected a channel"))?;
+        let position = compressed.get(2..).and_then(Position::from_compressed)
+            .ok_or(ServerMessageError::new(BadChat, 2, "expected 8 bytes of position"))?;
+        let time = compressed.get(10..).and_then(|bytes| bytes.first_chunk())
+            .ok_or(ServerMessageError::new(BadChat, 10, "expected 8 bytes of time"))?;
+        let rest = &compressed[18..];
+        let (player_id, rest) = read_string(rest)
+            .ok_or(ServerMessageError::new(BadChat, 18, "player ID cut short or not UTF-8"))?;
+        let offset = compressed.len() - rest.len();
+        let (name, rest) = read_string(rest)
+            .ok_or(ServerMessageError::new(BadChat, offset, "name cut short or not UTF-8"))?;
+        let offset = compressed.len() - rest.len();
+        let (text, _) = read_string(rest)
+            .ok_or(ServerMessageError::new(BadChat, offset, "text cut short or not UTF-8"))?;
+        Ok(Self { channel, player_id, name, position, time: u64::from_be_bytes(*time), text })
+    }
+}
+
+impl Arbitrary for ChatChannel {
+    fn arbitrary(g: &mut Gen) -> Self {
+        *g.choose(&[ChatChannel::Global, ChatChannel::Nearby]).unwrap()
+    }
+}
+
+impl Arbitrary for ChatMessage {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Self {
+            channel: ChatChannel::arbitrary(g),
+            player_id: String::arbitrary(g),
+            name: String::arbitrary(g),
+            position: Position::arbitrary(g),
+            time: u64::arbitrary(g),
+            text: String::arbitrary(g),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ChatMessage, MAX_CHAT_LENGTH};
+
+    #[test]
+    fn chat_text_is_tidied_and_checked() {
+        assert_eq!(ChatMessage::clean_text("  hello\tthere \n"), Ok("hello there".to_string()));
+        assert_eq!(ChatMessage::clean_text("a\u{7}b"), Ok("a b".to_string()));
+        assert_eq!(ChatMessage::clean_text("\u{202E}olleh\u{2066}!\u{2069}\u{200B}"), Ok("olleh!".to_string()));
+        assert_eq!(ChatMessage::clean_text("👩\u{200D}🚀"), Ok("👩\u{200D}🚀".to_string()));
+        assert!(ChatMessage::clean_text("\u{202E} \u{FEFF}").is_err());
+        assert!(ChatMessage::clean_text(" \n ").is_err());
+        assert!(ChatMessage::clean_text(&"é".repeat(MAX_CHAT_LENGTH)).is_ok());
+        assert!(ChatMessage::clean_text(&"é".repeat(MAX_CHAT_LENGTH + 1)).is_err());
+    }
+}
@@ -1,19 +1,327 @@
+use std::fmt::{Display, Formatter};
+use quickcheck::{Arbitrary, Gen};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use crate::{Position, Rect};
+use crate::{ChatChannel, ChunkPosition, Position, Rect};
+use crate::updates::server_message_bundle::{compress_string, compress_strings, read_string, read_strings, MessageLength};
 
-#[derive(Debug, Eq, PartialEq)]
+/// The most chunk versions a client can send in one `Holding` message
+pub const MAX_HOLDING: usize = 4096;
+/// Clients send `CursorMoved` at most this often, and the server sends out where everyone's
+/// cursors have moved to this often
+pub const CURSOR_INTERVAL_MS: u64 = 100;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub enum ClientMessage {
-    Connected,
-    Click(Position),
-    Flag(Position),
-    DoubleClick(Position),
-    Query(Rect),
+    Connected = b'+',
+    Click(Position) = b'c',
+    Flag(Position) = b'f',
+    DoubleClick(Position) = b'd',
+    /// Sends the chunks in the rect, once
+    Query(Rect) = b'q',
+    /// Replaces the area that the client is looking at. The server sends the chunks that
+    /// weren't in the previous viewport, then keeps the client up to date with everything
+    /// that happens in it.
+    Viewport(Rect) = b'v',
+    /// The client has received every numbered bundle up to and including this one
+    Ack(u16) = b'a',
+    /// Sent instead of `Connected` after the connection drops, to carry on with the same
+    /// player and get sent every bundle after `last`
+    Resume { key: String, last: u16 } = b'z',
+    /// Sent as JSON before anything else, so that a server of any version can read it. Says
+    /// which version of the protocol the client speaks, and the features it supports.
+    Hello { version: u16, features: Vec<String> } = b'h',
+    /// The versions of the chunks that the client already has. Sent before a `Query` or
+    /// `Viewport`, so that the server only sends the chunks that have changed since.
+    Holding(Vec<(ChunkPosition, u32)>) = b'o',
+    /// The tile under the player's mouse, sent when it changes, but no more often than every
+    /// [CURSOR_INTERVAL_MS]
+    CursorMoved(Position) = b'm',
+    /// Sent instead of `Connected` by a client that has played before, with the token from its
+    /// last `Welcome`, to carry on as the same player
+    Identify(String) = b'i',
+    /// The name the player wants to be shown with, which can be empty, and the hue of their
+    /// cursor out of 256
+    SetProfile { name: String, colour: u8 } = b'p',
+    /// Something the player wants to say, to everyone or only to the players nearby. The
+    /// server trims it, and rejects it if it's empty or longer than
+    /// [MAX_CHAT_LENGTH](crate::MAX_CHAT_LENGTH) characters.
+    Chat { channel: ChatChannel, text: String } = b't',
+    /// Points at a place, for the players looking near it
+    Ping(Position) = b'g',
+    /// Puts a named marker on the map for everyone, which stays until the player removes it
+    AddMarker { position: Position, name: String } = b'n',
+    /// Takes away one of the player's own markers
+    RemoveMarker(u32) = b'r',
 }
 
 impl ClientMessage {
     pub fn decode(data: Value) -> Option<ClientMessage> {
         serde_json::from_value(data).ok()
     }
-}
\ No newline at end of file
+
+    fn header(&self) -> u8 {
+        // We're using the discriminant as the header, so this unsafe code gets that:
+        unsafe { *<*const _>::from(self).cast::<u8>() }
+    }
+}
+
+fn compress_position(Position(x, y): &Position, binary: &mut Vec<u8>) {
+    binary.extend_from_slice(&x.to_be_bytes());
+    binary.extend_from_slice(&y.to_be_bytes());
+}
+
+fn compress_rect(rect: &Rect, binary: &mut Vec<u8>) {
+    for side in [rect.left, rect.top, rect.right, rect.bottom] {
+        binary.extend_from_slice(&side.to_be_bytes());
+    }
+}
+
+fn rect_from_compressed(bytes: &[u8]) -> Option<Rect> {
+    let Position(left, top) = Position::from_compressed(bytes)?;
+    let Position(right, bottom) = Position::from_compressed(bytes.get(8..)?)?;
+    Some(Rect { left, top, right, bottom })
+}
+
+/// Client messages can also be sent as binary, which is smaller and quicker to read than JSON.
+/// Positions and rects are big-endian i32s, and strings have their length in front.
+impl From<&ClientMessage> for Vec<u8> {
+    fn from(value: &ClientMessage) -> Self {
+        let mut result = vec![value.header()];
+        match value {
+            ClientMessage::Connected => {}
+            ClientMessage::Click(position) |
+            ClientMessage::Flag(position) |
+            ClientMessage::DoubleClick(position) |
+            ClientMessage::CursorMoved(position) |
+            ClientMessage::Ping(position) => {
+                compress_position(position, &mut result);
+            }
+            ClientMessage::Query(rect) |
+            ClientMessage::Viewport(rect) => {
+                compress_rect(rect, &mut result);
+            }
+            ClientMessage::Ack(number) => {
+                result.extend_from_slice(&number.to_be_bytes());
+            }
+            ClientMessage::Resume { key, last } => {
+                result.extend_from_slice(&last.to_be_bytes());
+                compress_string(key, &mut result);
+            }
+            ClientMessage::Identify(token) => {
+                compress_string(token, &mut result);
+            }
+            ClientMessage::SetProfile { name, colour } => {
+                compress_string(name, &mut result);
+                result.push(*colour);
+            }
+            ClientMessage::Chat { channel, text } => {
+                result.push(channel.to_byte());
+                compress_string(text, &mut result);
+            }
+            ClientMessage::AddMarker { position, name } => {
+                compress_position(position, &mut result);
+                compress_string(name, &mut result);
+            }
+            ClientMessage::RemoveMarker(id) => {
+                result.extend_from_slice(&id.to_be_bytes());
+            }
+            ClientMessage::Hello { version, features } => {
+                result.extend_from_slice(&version.to_be_bytes());
+                compress_strings(features, &mut result);
+            }
+            ClientMessage::Holding(versions) => {
+                result.append(&mut MessageLength(versions.len()).to_bytes());
+                for (position, version) in versions {
+                    result.append(&mut position.to_bytes());
+                    result.extend_from_slice(&version.to_be_bytes());
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Why some bytes from a client couldn't be read, and where
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ClientMessageError {
+    /// How far into the bytes the problem is
+    pub offset: usize,
+    pub reason: &'static str,
+}
+
+impl Display for ClientMessageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Bad client message at byte {}: {}", self.offset, self.reason)
+    }
+}
+
+impl std::error::Error for ClientMessageError {}
+
+/// The value, or an error at the offset if there isn't one
+fn expect<T>(value: Option<T>, offset: usize, reason: &'static str) -> Result<T, ClientMessageError> {
+    value.ok_or(ClientMessageError { offset, reason })
+}
+
+impl ClientMessage {
+    pub fn from_compressed(compressed: &[u8]) -> Result<ClientMessage, ClientMessageError> {
+        let (&header, body) = expect(compressed.split_first(), 0, "empty message")?;
+        // Offsets are counted from the start of the message, which is one byte before the body
+        let position = |offset: usize| expect(
+            body.get(offset..).and_then(Position::from_compressed), offset + 1, "expected 8 bytes of position",
+        );
+        let rect = || expect(rect_from_compressed(body), 1, "expected 16 bytes of rect");
+        let string = |offset: usize| expect(
+            body.get(offset..).and_then(read_string), offset + 1, "expected a string",
+        );
+        let u16_at_start = || expect(body.first_chunk().copied(), 1, "expected 2 bytes").map(u16::from_be_bytes);
+        let message = match header {
+            b'+' => ClientMessage::Connected,
+            b'c' => ClientMessage::Click(position(0)?),
+            b'f' => ClientMessage::Flag(position(0)?),
+            b'd' => ClientMessage::DoubleClick(position(0)?),
+            b'm' => ClientMessage::CursorMoved(position(0)?),
+            b'q' => ClientMessage::Query(rect()?),
+            b'v' => ClientMessage::Viewport(rect()?),
+            b'a' => ClientMessage::Ack(u16_at_start()?),
+            b'z' => {
+                let last = u16_at_start()?;
+                let (key, _) = string(2)?;
+                ClientMessage::Resume { key, last }
+            }
+            b'i' => ClientMessage::Identify(string(0)?.0),
+            b'p' => {
+                let (name, rest) = string(0)?;
+                let colour = *expect(rest.first(), compressed.len(), "expected a colour")?;
+                ClientMessage::SetProfile { name, colour }
+            }
+            b't' => {
+                let channel = expect(body.first().and_then(|&byte| ChatChannel::from_byte(byte)), 1, "expected a chat channel")?;
+                ClientMessage::Chat { channel, text: string(1)?.0 }
+            }
+            b'g' => ClientMessage::Ping(position(0)?),
+            b'n' => {
+                let position = position(0)?;
+                ClientMessage::AddMarker { position, name: string(8)?.0 }
+            }
+            b'r' => ClientMessage::RemoveMarker(u32::from_be_bytes(expect(body.first_chunk().copied(), 1, "expected 4 bytes of marker ID")?)),
+            b'h' => {
+                let version = u16_at_start()?;
+                let (features, _) = expect(read_strings(&body[2..]), 3, "expected a list of features")?;
+                ClientMessage::Hello { version, features }
+            }
+            b'o' => {
+                let (MessageLength(count), length_slice) = MessageLength::read_from_bytes(body)
+                    .map_err(|()| ClientMessageError { offset: 1, reason: "expected a number of chunks" })?;
+                let start = 1 + length_slice.len();
+                let entries = body[length_slice.len()..].chunks_exact(11);
+                if entries.len() != count || !entries.remainder().is_empty() {
+                    return Err(ClientMessageError { offset: start, reason: "expected 11 bytes for each chunk" });
+                }
+                let versions = entries.enumerate()
+                    .map(|(i, entry)| Ok((
+                        expect(ChunkPosition::from_bytes(entry[..7].to_vec()), start + i * 11, "expected a chunk position")?,
+                        u32::from_be_bytes(*entry[7..].first_chunk().expect("Entries are 11 bytes")),
+                    )))
+                    .collect::<Result<_, _>>()?;
+                ClientMessage::Holding(versions)
+            }
+            _ => return Err(ClientMessageError { offset: 0, reason: "unknown header" }),
+        };
+        Ok(message)
+    }
+}
+
+impl Arbitrary for ClientMessage {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let rect = |g: &mut Gen| Rect {
+            left: i32::arbitrary(g),
+            top: i32::arbitrary(g),
+            right: i32::arbitrary(g),
+            bottom: i32::arbitrary(g),
+        };
+        match u8::arbitrary(g) % 17 {
+            0 => ClientMessage::Connected,
+            1 => ClientMessage::Click(Position::arbitrary(g)),
+            2 => ClientMessage::Flag(Position::arbitrary(g)),
+            3 => ClientMessage::DoubleClick(Position::arbitrary(g)),
+            4 => ClientMessage::Query(rect(g)),
+            5 => ClientMessage::Viewport(rect(g)),
+            6 => ClientMessage::Ack(u16::arbitrary(g)),
+            7 => ClientMessage::Resume { key: String::arbitrary(g), last: u16::arbitrary(g) },
+            8 => ClientMessage::Holding(Vec::<(Position, u32)>::arbitrary(g).into_iter()
+                .map(|(position, version)| (position.chunk_position(), version))
+                .collect()),
+            9 => ClientMessage::CursorMoved(Position::arbitrary(g)),
+            10 => ClientMessage::Identify(String::arbitrary(g)),
+            11 => ClientMessage::SetProfile { name: String::arbitrary(g), colour: u8::arbitrary(g) },
+            12 => ClientMessage::Chat { channel: ChatChannel::arbitrary(g), text: String::arbitrary(g) },
+            13 => ClientMessage::Ping(Position::arbitrary(g)),
+            14 => ClientMessage::AddMarker { position: Position::arbitrary(g), name: String::arbitrary(g) },
+            15 => ClientMessage::RemoveMarker(u32::arbitrary(g)),
+            _ => ClientMessage::Hello { version: u16::arbitrary(g), features: Vec::arbitrary(g) },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck_macros::quickcheck;
+    use crate::{ClientMessage, ClientMessageError, Position};
+
+    #[quickcheck]
+    fn compression_then_decompression(message: ClientMessage) {
+        let compressed: Vec<u8> = (&message).into();
+        assert_eq!(ClientMessage::from_compressed(&compressed), Ok(message));
+    }
+
+    #[quickcheck]
+    fn binary_and_json_agree(message: ClientMessage) {
+        let json = serde_json::to_value(&message).unwrap();
+        let compressed: Vec<u8> = (&message).into();
+        assert_eq!(ClientMessage::decode(json), ClientMessage::from_compressed(&compressed).ok());
+    }
+
+    #[quickcheck]
+    fn truncated_messages_are_rejected(message: ClientMessage) {
+        let compressed: Vec<u8> = (&message).into();
+        for end in 0..compressed.len() {
+            let error = ClientMessage::from_compressed(&compressed[..end]).unwrap_err();
+            assert!(error.offset <= end);
+        }
+    }
+
+    #[quickcheck]
+    fn decoding_damaged_messages(message: ClientMessage, damage: Vec<(usize, u8)>, cut: usize) {
+        let mut compressed: Vec<u8> = (&message).into();
+        for (index, byte) in damage {
+            let length = compressed.len();
+            compressed[index % length] ^= byte;
+        }
+        compressed.truncate(cut % (compressed.len() + 1));
+        if let Err(error) = ClientMessage::from_compressed(&compressed) {
+            assert!(error.offset <= compressed.len());
+        }
+    }
+
+    #[quickcheck]
+    fn decoding_arbitrary_messages(bytes: Vec<u8>) {
+        if let Err(error) = ClientMessage::from_compressed(&bytes) {
+            assert!(error.offset <= bytes.len());
+        }
+    }
+
+    #[test]
+    fn errors_say_where_the_problem_is() {
+        let mut compressed: Vec<u8> = (&ClientMessage::AddMarker { position: Position(1, 2), name: "x".to_string() }).into();
+        compressed.truncate(5);
+        assert_eq!(ClientMessage::from_compressed(&compressed), Err(ClientMessageError {
+            offset: 1,
+            reason: "expected 8 bytes of position",
+        }));
+        compressed[0] = b'?';
+        assert_eq!(ClientMessage::from_compressed(&compressed).unwrap_err().reason, "unknown header");
+    }
+}
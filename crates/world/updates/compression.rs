@@ -84,8 +84,8 @@ impl From<&Tile> for PublicTile {
                     5 => Adjacent5,
                     6 => Adjacent6,
                     7 => Adjacent7,
-                    8 => Adjacent8,
-                    _ => panic!("Uh oh what have we got here...")
+                    // adjacent() never goes above 8
+                    _ => Adjacent8,
                 }
             }
         } else {
@@ -1,6 +1,6 @@
-use crate::{Position, Tile, UpdatedRect, UpdatedTile};
+use crate::{Position, Rect, ServerMessageError, Tile, UpdatedRect, UpdatedTile};
+use crate::ServerMessageErrorKind::BadEvent;
 use serde::{Deserialize, Serialize};
-use std::i32;
 use quickcheck::{Arbitrary, Gen};
 use crate::player::Player;
 
@@ -57,13 +57,36 @@ impl Event {
             Event::Flag { player_id, at, .. } |
             Event::Unflag { player_id, at, .. } => {
                 Player {
-                    player_id: player_id.clone(),
-                    position: at.clone()
+                    position: at.clone(),
+                    ..Player::new(player_id.clone())
                 }
             }
         }
     }
     
+    /// The part of the world that the event changed, including where it happened
+    pub fn area(&self) -> Rect {
+        let Player { position, .. } = self.player();
+        let mut area = Rect::from_center_and_size(position, 1, 1);
+        match self {
+            Event::Clicked { updated, .. } |
+            Event::DoubleClicked { updated, .. } if !updated.is_empty() => {
+                area.expand_to_contain(updated.rect());
+            }
+            _ => {}
+        }
+        area
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Clicked { .. } => "Clicked",
+            Event::DoubleClicked { .. } => "DoubleClicked",
+            Event::Flag { .. } => "Flag",
+            Event::Unflag { .. } => "Unflag",
+        }
+    }
+
     pub fn should_send(&self) -> bool {
         true
     }
@@ -98,40 +121,30 @@ impl Event {
         binary
     }
     
-    pub fn from_compressed(compressed: &[u8]) -> Option<Event> {
-        let header = String::from_utf8_lossy(&compressed[0..=0]);
-        let mut index = 1;
-        loop {
-            let char = compressed.get(index)?;
-            index += 1;
-            if *char == 0 {
-                break
-            }
-        }
-        let player_id = String::from_utf8_lossy(&compressed[1..index-1]).to_string();
-        let at = {
-            let x = i32::from_be_bytes(*compressed[index..].first_chunk()?);
-            let y = i32::from_be_bytes(*compressed[index+4..].first_chunk()?);
-            index += 8;
-            Position(x, y)
-        };
+    pub fn from_compressed(compressed: &[u8]) -> Result<Event, ServerMessageError> {
+        let bad = |offset, reason| ServerMessageError::new(BadEvent, offset, reason);
+        let &header = compressed.first().ok_or(bad(0, "empty event"))?;
+        let id_length = compressed[1..].iter().position(|&byte| byte == 0)
+            .ok_or(bad(1, "player ID isn't terminated"))?;
+        let player_id = String::from_utf8_lossy(&compressed[1..1 + id_length]).to_string();
+        let mut index = 2 + id_length;
+        let at = Position::from_compressed(&compressed[index..])
+            .ok_or(bad(index, "expected 8 bytes of position"))?;
+        index += 8;
 
-        if header == "C" {
-            UpdatedRect::from_compressed(&compressed[index..])
-                .map(|updated| {
-                    Event::Clicked { player_id, at, updated }
-                })
-        } else if header == "D" {
-            UpdatedRect::from_compressed(&compressed[index..])
-                .map(|updated| {
-                    Event::DoubleClicked { player_id, at, updated }
-                })
-        } else if header == "F" {
-            Some(Event::Flag { player_id, at })
-        } else if header == "U" {
-            Some(Event::Unflag { player_id, at })
-        } else {
-            None
+        match header {
+            b'C' | b'D' => {
+                let updated = UpdatedRect::from_compressed(&compressed[index..])
+                    .map_err(|error| ServerMessageError { kind: BadEvent, ..error.after(index) })?;
+                if header == b'C' {
+                    Ok(Event::Clicked { player_id, at, updated })
+                } else {
+                    Ok(Event::DoubleClicked { player_id, at, updated })
+                }
+            }
+            b'F' => Ok(Event::Flag { player_id, at }),
+            b'U' => Ok(Event::Unflag { player_id, at }),
+            _ => Err(bad(0, "unknown header")),
         }
     }
 }
@@ -160,7 +173,38 @@ impl Arbitrary for Event {
 #[cfg(test)]
 mod tests {
     use quickcheck_macros::quickcheck;
-    use crate::Event;
+    use crate::{Event, Position, Tile, World};
+
+    /// Damaged events give an error rather than panicking
+    #[quickcheck]
+    fn decoding_damaged_events(event: Event, damage: Vec<(usize, u8)>, cut: usize) {
+        let mut compressed = event.compress();
+        for (index, byte) in damage {
+            let length = compressed.len();
+            compressed[index % length] ^= byte;
+        }
+        compressed.truncate(cut % (compressed.len() + 1));
+        if let Err(error) = Event::from_compressed(&compressed) {
+            assert!(error.offset <= compressed.len());
+        }
+    }
+
+    #[quickcheck]
+    fn decoding_arbitrary_events(bytes: Vec<u8>) {
+        let _ = Event::from_compressed(&bytes);
+    }
+
+    #[test]
+    fn applying_flags_and_unflags_like_a_client() {
+        let mut world = World::new();
+        let at = Position(3, 4);
+        let flag = Event::Flag { player_id: "alice".to_string(), at };
+        world.apply_updated_rect(flag.updated_rect());
+        assert_eq!(world.get_tile(&at), Tile::empty().with_flag());
+        let unflag = Event::Unflag { player_id: "alice".to_string(), at };
+        world.apply_updated_rect(unflag.updated_rect());
+        assert_eq!(world.get_tile(&at), Tile::empty());
+    }
 
     #[quickcheck]
     fn event_compression_then_decompression(event: Event) -> bool {
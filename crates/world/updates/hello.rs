@@ -0,0 +1,77 @@
+/// The version of the protocol that this build speaks. It goes up whenever a change would stop
+/// older clients from understanding the server, or the other way round.
+pub const PROTOCOL_VERSION: u16 = 5;
+/// The oldest client version the server still understands. Version 2 added chunk versions to
+/// chunk messages, version 3 added the token and stats to `Welcome`, version 4 added names and
+/// colours to players, and version 5 added the epoch to `Welcome`.
+pub const MIN_PROTOCOL_VERSION: u16 = 5;
+/// The version spoken by clients from before there was a hello
+pub const UNVERSIONED_PROTOCOL_VERSION: u16 = 1;
+
+/// The client can send messages as binary instead of JSON
+pub const BINARY_CLIENT_MESSAGES: &str = "binary-client-messages";
+/// The client can decompress tiles using the byte pair encoding table in `compression.rs`. A
+/// different table would need a different name.
+pub const TILES_BPE_1: &str = "tiles-bpe-1";
+/// The client can read `Cursors` messages, so it can be told where other players' mice are
+pub const CURSORS: &str = "cursors";
+/// The client can read `Chat` messages
+pub const CHAT: &str = "chat";
+/// The client can read `Ping`, `Marker` and `MarkerRemoved` messages
+pub const MARKERS: &str = "markers";
+
+/// Everything the server can do
+pub const SUPPORTED_FEATURES: [&str; 5] = [BINARY_CLIENT_MESSAGES, TILES_BPE_1, CURSORS, CHAT, MARKERS];
+/// The server can't talk to clients that can't do these
+pub const REQUIRED_FEATURES: [&str; 1] = [TILES_BPE_1];
+
+/// Works out which features to use with a client, or why the server can't talk to it
+pub fn negotiate(version: u16, features: &[String]) -> Result<Vec<String>, String> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        let needed = if MIN_PROTOCOL_VERSION == PROTOCOL_VERSION {
+            format!("version {PROTOCOL_VERSION}")
+        } else {
+            format!("a version from {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}")
+        };
+        return Err(format!(
+            "This client uses protocol version {version}, but the server needs {needed}. Try reloading the page."
+        ));
+    }
+    let missing: Vec<_> = REQUIRED_FEATURES.iter()
+        .filter(|required| !features.iter().any(|feature| feature == *required))
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "This client doesn't support {}, which the server needs. Try reloading the page.",
+            missing.join(", ")
+        ));
+    }
+    Ok(SUPPORTED_FEATURES.iter()
+        .filter(|supported| features.iter().any(|feature| feature == *supported))
+        .map(|feature| feature.to_string())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::updates::hello::{negotiate, BINARY_CLIENT_MESSAGES, PROTOCOL_VERSION, TILES_BPE_1};
+
+    #[test]
+    fn negotiating_features() {
+        let features = vec![TILES_BPE_1.to_string(), "from-the-future".to_string(), BINARY_CLIENT_MESSAGES.to_string()];
+        assert_eq!(
+            negotiate(PROTOCOL_VERSION, &features),
+            Ok(vec![BINARY_CLIENT_MESSAGES.to_string(), TILES_BPE_1.to_string()])
+        );
+        assert_eq!(negotiate(PROTOCOL_VERSION, &[TILES_BPE_1.to_string()]), Ok(vec![TILES_BPE_1.to_string()]));
+    }
+
+    #[test]
+    fn incompatible_clients_are_told_why() {
+        let features = vec![TILES_BPE_1.to_string()];
+        assert!(negotiate(PROTOCOL_VERSION + 1, &features).unwrap_err().contains("version"));
+        assert!(negotiate(0, &features).unwrap_err().contains("version"));
+        assert!(negotiate(PROTOCOL_VERSION, &[]).unwrap_err().contains(TILES_BPE_1));
+    }
+}
@@ -0,0 +1,87 @@
+use quickcheck::{Arbitrary, Gen};
+use serde::{Deserialize, Serialize};
+use crate::{Position, ServerMessageError};
+use crate::ServerMessageErrorKind::BadMarker;
+use crate::updates::{compress_string, read_string};
+
+/// The longest name a marker can have, in characters
+pub const MAX_MARKER_NAME_LENGTH: usize = 32;
+/// How long a ping stays on the map for
+pub const PING_DURATION_MS: u64 = 10_000;
+
+/// A named place on the map, which stays there until the player who put it there takes it away
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Marker {
+    pub id: u32,
+    pub player_id: String,
+    pub position: Position,
+    pub name: String,
+}
+
+impl Marker {
+    /// The name with its spaces tidied up, or why it can't be used
+    pub fn clean_name(name: &str) -> Result<String, String> {
+        let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+        if name.is_empty() {
+            return Err("Markers need a name".to_string());
+        }
+        if name.chars().count() > MAX_MARKER_NAME_LENGTH {
+            return Err(format!("Marker names can't be longer than {MAX_MARKER_NAME_LENGTH} characters"));
+        }
+        if name.chars().any(char::is_control) {
+            return Err("Marker names can't have control characters in them".to_string());
+        }
+        Ok(name)
+    }
+
+    pub fn compress(&self, header: u8) -> Vec<u8> {
+        let Position(x, y) = self.position;
+        let mut result = vec![header];
+        result.extend_from_slice(&self.id.to_be_bytes());
+        result.extend_from_slice(&x.to_be_bytes());
+        result.extend_from_slice(&y.to_be_bytes());
+        compress_string(&self.player_id, &mut result);
+        compress_string(&self.name, &mut result);
+        result
+    }
+
+    /// Reads a marker compressed with [Marker::compress], header and all
+    pub fn from_compressed(compressed: &[u8]) -> Result<Self, ServerMessageError> {
+        let id = compressed.get(1..).and_then(|bytes| bytes.first_chunk())
+            .ok_or(ServerMessageError::new(BadMarker, 1, "expected 4 bytes of ID"))?;
+        let position = compressed.get(5..).and_then(Position::from_compressed)
+            .ok_or(ServerMessageError::new(BadMarker, 5, "expected 8 bytes of position"))?;
+        let (player_id, rest) = read_string(&compressed[13..])
+            .ok_or(ServerMessageError::new(BadMarker, 13, "player ID cut short or not UTF-8"))?;
+        let offset = compressed.len() - rest.len();
+        let (name, _) = read_string(rest)
+            .ok_or(ServerMessageError::new(BadMarker, offset, "name cut short or not UTF-8"))?;
+        Ok(Self { id: u32::from_be_bytes(*id), player_id, position, name })
+    }
+}
+
+impl Arbitrary for Marker {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Self {
+            id: u32::arbitrary(g),
+            player_id: String::arbitrary(g),
+            position: Position::arbitrary(g),
+            name: String::arbitrary(g),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Marker, MAX_MARKER_NAME_LENGTH};
+
+    #[test]
+    fn marker_names_are_tidied_and_checked() {
+        assert_eq!(Marker::clean_name("  Base   camp! "), Ok("Base camp!".to_string()));
+        assert!(Marker::clean_name(" ").is_err());
+        assert!(Marker::clean_name("a\u{7}b").is_err());
+        assert!(Marker::clean_name(&"é".repeat(MAX_MARKER_NAME_LENGTH)).is_ok());
+        assert!(Marker::clean_name(&"é".repeat(MAX_MARKER_NAME_LENGTH + 1)).is_err());
+    }
+}
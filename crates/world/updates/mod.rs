@@ -4,6 +4,9 @@ mod client_messages;
 mod server_messages;
 mod compression;
 mod server_message_bundle;
+mod hello;
+mod chat;
+mod markers;
 
 pub use updated_rect::*;
 pub use events::*;
@@ -11,3 +14,6 @@ pub use client_messages::*;
 pub use server_messages::*;
 pub use compression::*;
 pub use server_message_bundle::*;
+pub use hello::*;
+pub use chat::*;
+pub use markers::*;
@@ -1,12 +1,14 @@
-use crate::ServerMessage;
+use crate::{ServerMessage, ServerMessageError};
+use crate::ServerMessageErrorKind::BadBundle;
 use std::usize;
 
+#[derive(Debug)]
 pub struct ServerMessageBundle(pub Vec<ServerMessage>);
 
-struct MessageLength(usize);
+pub(crate) struct MessageLength(pub usize);
 
 impl MessageLength {
-    fn to_bytes(&self) -> Vec<u8> {
+    pub fn to_bytes(&self) -> Vec<u8> {
         if self.0 == 0 {
             return vec![0];
         }
@@ -28,17 +30,53 @@ impl MessageLength {
 impl MessageLength {
     pub fn read_from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), ()> {
         let mut length: usize = 0;
-        let mut slice_end = 0;
-        for &byte in bytes {
-            slice_end += 1;
-            length *= 128;
-            length += (byte % 128) as usize;
+        for (i, &byte) in bytes.iter().enumerate() {
+            length = length.checked_mul(128).ok_or(())? + (byte % 128) as usize;
             if byte < 128 {
-                break
+                return Ok((Self(length), &bytes[0..=i]));
             }
         }
-        Ok((Self(length), &bytes[0..slice_end]))
+        // Ran out of bytes before the end of the length
+        Err(())
+    }
+}
+
+/// Writes a string with its length in front
+pub(crate) fn compress_string(string: &str, binary: &mut Vec<u8>) {
+    binary.append(&mut MessageLength(string.len()).to_bytes());
+    binary.extend_from_slice(string.as_bytes());
+}
+
+/// Reads a string written by [compress_string], and returns the bytes after it
+pub(crate) fn read_string(bytes: &[u8]) -> Option<(String, &[u8])> {
+    let (MessageLength(length), length_slice) = MessageLength::read_from_bytes(bytes).ok()?;
+    let rest = &bytes[length_slice.len()..];
+    if rest.len() < length {
+        return None;
     }
+    let string = String::from_utf8(rest[..length].to_vec()).ok()?;
+    Some((string, &rest[length..]))
+}
+
+/// Writes a list of strings with how many there are in front
+pub(crate) fn compress_strings(strings: &[String], binary: &mut Vec<u8>) {
+    binary.append(&mut MessageLength(strings.len()).to_bytes());
+    for string in strings {
+        compress_string(string, binary);
+    }
+}
+
+pub(crate) fn read_strings(bytes: &[u8]) -> Option<(Vec<String>, &[u8])> {
+    let (MessageLength(count), length_slice) = MessageLength::read_from_bytes(bytes).ok()?;
+    let mut rest = &bytes[length_slice.len()..];
+    // Every string takes at least a byte, which stops a huge count from allocating lots
+    let mut strings = Vec::with_capacity(count.min(rest.len()));
+    for _ in 0..count {
+        let (string, after) = read_string(rest)?;
+        strings.push(string);
+        rest = after;
+    }
+    Some((strings, rest))
 }
 
 impl From<Vec<ServerMessage>> for ServerMessageBundle {
@@ -49,49 +87,75 @@ impl From<Vec<ServerMessage>> for ServerMessageBundle {
 
 impl ServerMessageBundle {
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut result = vec![b'b'];
-        for message in &self.0 {
-            let mut serialized: Vec<u8> = message.into();
-
-            let length = MessageLength(serialized.len());
-            result.append(&mut length.to_bytes());
+        Self::from_message_bytes(self.0.iter().map(Vec::<u8>::from))
+    }
 
-            result.append(&mut serialized);
+    /// Bundles up messages that have already been turned into bytes, such as chunks that were
+    /// compressed once to be sent to lots of clients.
+    pub fn from_message_bytes<T: AsRef<[u8]>>(messages: impl IntoIterator<Item = T>) -> Vec<u8> {
+        let mut result = vec![b'b'];
+        for message in messages {
+            let message = message.as_ref();
+            result.append(&mut MessageLength(message.len()).to_bytes());
+            result.extend_from_slice(message);
         }
         result
     }
+
+    /// Gives a bundle that's already been turned into bytes a number, so that the client can
+    /// acknowledge it. Numbered bundles start with `n` and the number, instead of `b`.
+    pub fn number_bytes(bundle: &[u8], number: u16) -> Vec<u8> {
+        let mut result = Vec::with_capacity(bundle.len() + 2);
+        result.push(b'n');
+        result.extend_from_slice(&number.to_be_bytes());
+        result.extend_from_slice(bundle.get(1..).unwrap_or_default());
+        result
+    }
 }
 
 impl ServerMessageBundle {
-    pub fn from_compressed(value: &[u8]) -> Result<Self, ()> {
-        if let Some(header) = value.get(0) {
-            if *header != b'b' { return Err(()); }
-            let mut result = vec![];
-            let mut read_position = 1;
-            while read_position < value.len() {
-                let (MessageLength(length), length_slice) = MessageLength::read_from_bytes(&value[read_position..])?;
-                read_position += length_slice.len();
-                let end = read_position + length;
-                if end > value.len() {
-                    return Err(());
-                }
-                let message_bytes = &value[read_position..end];
-                read_position = end;
-                let message = ServerMessage::from_compressed(message_bytes).map_err(|_| ())?;
-                result.push(message);
+    /// Reads a bundle, whether or not it's numbered
+    pub fn from_compressed(value: &[u8]) -> Result<Self, ServerMessageError> {
+        Self::from_compressed_with_number(value).map(|(_, bundle)| bundle)
+    }
+
+    /// Reads a bundle, and its number if it has one
+    pub fn from_compressed_with_number(value: &[u8]) -> Result<(Option<u16>, Self), ServerMessageError> {
+        match value.first() {
+            Some(b'b') => Ok((None, Self::read_messages(value, 1)?)),
+            Some(b'n') if value.len() >= 3 => {
+                let number = u16::from_be_bytes([value[1], value[2]]);
+                Ok((Some(number), Self::read_messages(value, 3)?))
             }
-            Ok(Self(result))
-        } else {
-            Err(())
+            Some(b'n') => Err(ServerMessageError::new(BadBundle, 1, "expected 2 bytes of bundle number")),
+            _ => Err(ServerMessageError::new(BadBundle, 0, "expected a bundle header")),
         }
     }
+
+    fn read_messages(value: &[u8], mut read_position: usize) -> Result<Self, ServerMessageError> {
+        let mut result = vec![];
+        while read_position < value.len() {
+            let (MessageLength(length), length_slice) = MessageLength::read_from_bytes(&value[read_position..])
+                .map_err(|_| ServerMessageError::new(BadBundle, read_position, "message length is cut short"))?;
+            read_position += length_slice.len();
+            let end = read_position.checked_add(length)
+                .filter(|&end| end <= value.len())
+                .ok_or(ServerMessageError::new(BadBundle, read_position, "message is longer than the bundle"))?;
+            let message_bytes = &value[read_position..end];
+            let message = ServerMessage::from_compressed(message_bytes)
+                .map_err(|error| error.after(read_position))?;
+            read_position = end;
+            result.push(message);
+        }
+        Ok(Self(result))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use quickcheck_macros::quickcheck;
     use crate::updates::server_message_bundle::{MessageLength, ServerMessageBundle};
-    use crate::{Chunk, ChunkPosition, ChunkTiles, ServerMessage};
+    use crate::{Chunk, ChunkPosition, ChunkTiles, ServerMessage, ServerMessageErrorKind};
 
     #[quickcheck]
     fn encode_decode_message_length(length: usize) {
@@ -119,4 +183,54 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+
+    /// Damaged bundles give an error rather than panicking
+    #[quickcheck]
+    fn decoding_damaged_bundles(messages: Vec<ServerMessage>, damage: Vec<(usize, u8)>, cut: usize) {
+        let mut compressed = ServerMessageBundle(messages).to_bytes();
+        for (index, byte) in damage {
+            let length = compressed.len();
+            compressed[index % length] ^= byte;
+        }
+        compressed.truncate(cut % (compressed.len() + 1));
+        if let Err(error) = ServerMessageBundle::from_compressed(&compressed) {
+            assert!(error.offset <= compressed.len());
+        }
+    }
+
+    #[quickcheck]
+    fn decoding_arbitrary_bundles(bytes: Vec<u8>) {
+        let _ = ServerMessageBundle::from_compressed(&bytes);
+        let mut bundle = vec![b'b'];
+        bundle.extend(bytes);
+        let _ = ServerMessageBundle::from_compressed(&bundle);
+    }
+
+    #[test]
+    fn errors_say_where_the_problem_is() {
+        let mut compressed = ServerMessageBundle(vec![ServerMessage::Stale, ServerMessage::Throttled(5)]).to_bytes();
+        compressed.pop();
+        // b, length, S, length, T, then only one of the two bytes of milliseconds
+        let error = ServerMessageBundle::from_compressed(&compressed).unwrap_err();
+        assert_eq!(error.kind, ServerMessageErrorKind::BadBundle);
+        assert_eq!(error.offset, 4);
+        // With the length fixed, it's the message that's wrong
+        compressed[3] = 2;
+        let error = ServerMessageBundle::from_compressed(&compressed).unwrap_err();
+        assert_eq!(error.kind, ServerMessageErrorKind::BadThrottled);
+        assert_eq!(error.offset, 5);
+    }
+
+    #[quickcheck]
+    fn numbered_bundles(messages: Vec<ServerMessage>, number: u16) {
+        let bundle = ServerMessageBundle(messages.clone()).to_bytes();
+        let numbered = ServerMessageBundle::number_bytes(&bundle, number);
+        if let Ok((read_number, ServerMessageBundle(decompressed))) = ServerMessageBundle::from_compressed_with_number(&numbered) {
+            assert_eq!(read_number, Some(number));
+            assert_eq!(decompressed, messages);
+        }
+        if let Ok((read_number, _)) = ServerMessageBundle::from_compressed_with_number(&bundle) {
+            assert_eq!(read_number, None);
+        }
+    }
+}
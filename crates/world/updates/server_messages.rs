@@ -1,9 +1,13 @@
-use crate::player::Player;
+use crate::player::{Player, PlayerStats};
 use crate::PublicTile;
+use crate::{ChatMessage, Marker};
 use crate::{Chunk, ChunkPosition, ChunkTiles, Event, Position, Tile, UpdatedRect};
+use crate::updates::server_message_bundle::{compress_string, compress_strings, read_string, read_strings, MessageLength};
 // use huffman::HuffmanCode;
 use quickcheck::{Arbitrary, Gen};
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use ServerMessageErrorKind::*;
 
 // ServerMessage is anything the server sends that gets compressed to bytes
 #[repr(u8)]
@@ -15,8 +19,42 @@ pub enum ServerMessage {
     Chunk(Chunk) = b'h',
     Rect(UpdatedRect) = b'r',
     Player(Player) = b'p',
-    Welcome(Player) = b'w',
+    /// The player that the client is playing as. The token is a secret that the client can
+    /// send back with `Identify` to be the same player again, with the same stats. Chunk
+    /// versions only mean something within one epoch, so a client that's told about a different
+    /// epoch than last time forgets the versions it has.
+    Welcome { player: Player, token: String, stats: PlayerStats, epoch: u64 } = b'w',
     Disconnected(String) = b'x',
+    /// The server is shutting down, and will be back after this many seconds
+    Restarting(u16) = b'R',
+    /// The client is sending messages too quickly, and its messages are being ignored. It can
+    /// send more after this many milliseconds.
+    Throttled(u16) = b'T',
+    /// Something the client sent was rejected, and why, in words
+    Error(String) = b'!',
+    /// The client fell behind and missed some messages. The server forgets which players the
+    /// client knows about, and sends everything in its viewport again.
+    Stale = b'S',
+    /// A secret that lets the client pick up where it left off if its connection drops
+    ResumeKey(String) = b'k',
+    /// The answer to the client's `Hello`: the server's protocol version, and the features that
+    /// both ends support
+    Hello { version: u16, features: Vec<String> } = b'H',
+    /// Where other players' cursors have moved to since the last of these. Only sent to clients
+    /// that support the `cursors` feature.
+    Cursors(Vec<Player>) = b'm',
+    /// Something a player said, sent to everyone for the global channel, and to the players
+    /// looking at where the sender is for the nearby one. Only sent to clients that support
+    /// the `chat` feature.
+    Chat(ChatMessage) = b't',
+    /// A player pointed at a place, for the players looking near it. It goes away after
+    /// [PING_DURATION_MS](crate::PING_DURATION_MS). Only sent to clients that support the
+    /// `markers` feature, like the two below.
+    Ping { player_id: String, position: Position } = b'g',
+    /// A named marker that has been put on the map. Every marker is sent after `Welcome`.
+    Marker(Marker) = b'M',
+    /// The marker with this ID has been taken off the map
+    MarkerRemoved(u32) = b'X',
     Connected = b'+',
 }
 
@@ -42,89 +80,291 @@ impl From<&ServerMessage> for Vec<u8> {
                 result.append(&mut rect.into());
                 result
             }
-            ServerMessage::Player(player) |
-            ServerMessage::Welcome(player) => {
+            ServerMessage::Player(player) => {
                 player.compress(header)
             }
+            ServerMessage::Welcome { player, token, stats, epoch } => {
+                let mut result = player.compress(header);
+                compress_string(token, &mut result);
+                result.append(&mut stats.to_bytes());
+                result.extend_from_slice(&epoch.to_be_bytes());
+                result
+            }
             ServerMessage::Disconnected(player_id) => {
                 let mut result = vec![];
                 result.append(&mut "x".as_bytes().to_vec());
                 result.append(&mut player_id.as_bytes().to_vec());
                 result
             },
+            ServerMessage::Restarting(seconds) => {
+                let mut result = vec![header];
+                result.extend_from_slice(&seconds.to_be_bytes());
+                result
+            }
+            ServerMessage::Throttled(ms) => {
+                let mut result = vec![header];
+                result.extend_from_slice(&ms.to_be_bytes());
+                result
+            }
+            ServerMessage::Error(text) |
+            ServerMessage::ResumeKey(text) => {
+                let mut result = vec![header];
+                result.extend_from_slice(text.as_bytes());
+                result
+            }
+            ServerMessage::Hello { version, features } => {
+                let mut result = vec![header];
+                result.extend_from_slice(&version.to_be_bytes());
+                compress_strings(features, &mut result);
+                result
+            }
+            ServerMessage::Cursors(players) => {
+                let mut result = vec![header];
+                result.append(&mut MessageLength(players.len()).to_bytes());
+                for Player { player_id, position: Position(x, y), .. } in players {
+                    result.extend_from_slice(&x.to_be_bytes());
+                    result.extend_from_slice(&y.to_be_bytes());
+                    compress_string(player_id, &mut result);
+                }
+                result
+            }
+            ServerMessage::Chat(message) => {
+                message.compress(header)
+            }
+            ServerMessage::Ping { player_id, position: Position(x, y) } => {
+                let mut result = vec![header];
+                result.extend_from_slice(&x.to_be_bytes());
+                result.extend_from_slice(&y.to_be_bytes());
+                compress_string(player_id, &mut result);
+                result
+            }
+            ServerMessage::Marker(marker) => {
+                marker.compress(header)
+            }
+            ServerMessage::MarkerRemoved(id) => {
+                let mut result = vec![header];
+                result.extend_from_slice(&id.to_be_bytes());
+                result
+            }
+            ServerMessage::Stale => vec![header],
             ServerMessage::Connected => vec![],
         }
     }
 }
 
-#[derive(Debug)]
-pub enum ServerMessageError {
+/// What kind of message couldn't be read
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ServerMessageErrorKind {
+    BadBundle,
     BadChunk,
     BadEvent,
     BadPlayer,
     BadTile,
     BadRect,
+    BadRestarting,
+    BadThrottled,
+    BadHello,
+    BadCursors,
+    BadChat,
+    BadMarker,
 }
 
+/// Why some bytes from the server couldn't be read, and where
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ServerMessageError {
+    pub kind: ServerMessageErrorKind,
+    /// How far into the bytes the problem is
+    pub offset: usize,
+    pub reason: &'static str,
+}
+
+impl ServerMessageError {
+    pub fn new(kind: ServerMessageErrorKind, offset: usize, reason: &'static str) -> Self {
+        Self { kind, offset, reason }
+    }
+
+    /// For errors from reading part of something, so that the offset counts from the start
+    /// of the whole thing
+    pub fn after(mut self, offset: usize) -> Self {
+        self.offset += offset;
+        self
+    }
+}
+
+impl Display for ServerMessageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} at byte {}: {}", self.kind, self.offset, self.reason)
+    }
+}
+
+impl std::error::Error for ServerMessageError {}
+
 impl ServerMessage {
     pub fn from_compressed(compressed: &[u8]) -> Result<ServerMessage, ServerMessageError> {
-        if compressed.is_empty() {
-            return Err(ServerMessageError::BadEvent)
+        let Some(&header) = compressed.first() else {
+            return Err(ServerMessageError::new(BadEvent, 0, "empty message"));
+        };
+        let body = &compressed[1..];
+        if header == b'h' {
+            Ok(ServerMessage::Chunk(Chunk::from_compressed(compressed)?))
         }
-        let header = String::from_utf8_lossy(&compressed[0..=0]);
-        if header == "h" {
-            match Chunk::from_compressed(compressed) {
-                Some(chunk) => Ok(ServerMessage::Chunk(chunk)),
-                None => Err(ServerMessageError::BadChunk)
-            }
+        else if header == b'r' {
+            UpdatedRect::from_compressed(body)
+                .map(ServerMessage::Rect)
+                .map_err(|error| ServerMessageError { kind: BadRect, ..error.after(1) })
         }
-        else if header == "r" {
-            match UpdatedRect::from_compressed(&compressed[1..]) {
-                Some(rect) => Ok(ServerMessage::Rect(rect)),
-                None => Err(ServerMessageError::BadRect)
+        else if header == b'p' {
+            Ok(ServerMessage::Player(Player::from_compressed(compressed)?))
+        }
+        else if header == b'w' {
+            read_welcome(compressed)
+        }
+        else if header == b'x' {
+            let player_id = String::from_utf8_lossy(body);
+            Ok(ServerMessage::Disconnected(player_id.into()))
+        }
+        else if header == b'R' {
+            match body.try_into() {
+                Ok(seconds) => Ok(ServerMessage::Restarting(u16::from_be_bytes(seconds))),
+                Err(_) => Err(ServerMessageError::new(BadRestarting, 1, "expected 2 bytes of seconds"))
             }
         }
-        else if header == "p" {
-            match Player::from_compressed(compressed) {
-                Some(player) => Ok(ServerMessage::Player(player)),
-                None => Err(ServerMessageError::BadPlayer)
+        else if header == b'T' {
+            match body.try_into() {
+                Ok(ms) => Ok(ServerMessage::Throttled(u16::from_be_bytes(ms))),
+                Err(_) => Err(ServerMessageError::new(BadThrottled, 1, "expected 2 bytes of milliseconds"))
             }
         }
-        else if header == "w" {
-            match Player::from_compressed(compressed) {
-                Some(player) => Ok(ServerMessage::Welcome(player)),
-                None => Err(ServerMessageError::BadPlayer)
+        else if header == b'k' {
+            Ok(ServerMessage::ResumeKey(String::from_utf8_lossy(body).into()))
+        }
+        else if header == b'H' {
+            let Some(version) = body.first_chunk() else {
+                return Err(ServerMessageError::new(BadHello, 1, "expected 2 bytes of version"));
+            };
+            let Some((features, _)) = read_strings(&body[2..]) else {
+                return Err(ServerMessageError::new(BadHello, 3, "features cut short or not UTF-8"));
+            };
+            Ok(ServerMessage::Hello { version: u16::from_be_bytes(*version), features })
+        }
+        else if header == b'm' {
+            Ok(ServerMessage::Cursors(read_cursors(compressed)?))
+        }
+        else if header == b't' {
+            Ok(ServerMessage::Chat(ChatMessage::from_compressed(compressed)?))
+        }
+        else if header == b'g' {
+            let position = Position::from_compressed(body)
+                .ok_or(ServerMessageError::new(BadMarker, 1, "expected 8 bytes of position"))?;
+            let (player_id, _) = read_string(&body[8..])
+                .ok_or(ServerMessageError::new(BadMarker, 9, "player ID cut short or not UTF-8"))?;
+            Ok(ServerMessage::Ping { player_id, position })
+        }
+        else if header == b'M' {
+            Ok(ServerMessage::Marker(Marker::from_compressed(compressed)?))
+        }
+        else if header == b'X' {
+            match body.try_into() {
+                Ok(id) => Ok(ServerMessage::MarkerRemoved(u32::from_be_bytes(id))),
+                Err(_) => Err(ServerMessageError::new(BadMarker, 1, "expected 4 bytes of ID"))
             }
         }
-        else if header == "x" { 
-            let player_id = String::from_utf8_lossy(&compressed[1..]);
-            Ok(ServerMessage::Disconnected(player_id.into()))
+        else if header == b'S' {
+            Ok(ServerMessage::Stale)
+        }
+        else if header == b'!' {
+            Ok(ServerMessage::Error(String::from_utf8_lossy(body).into()))
         }
         else {
-            match Event::from_compressed(&compressed) {
-                Some(event) => Ok(ServerMessage::Event(event)),
-                None => Err(ServerMessageError::BadEvent)
-            }
+            Ok(ServerMessage::Event(Event::from_compressed(compressed)?))
         }
     }
 }
 
+fn read_welcome(compressed: &[u8]) -> Result<ServerMessage, ServerMessageError> {
+    let (player, offset) = Player::read(compressed, 1)?;
+    let (token, rest) = read_string(&compressed[offset..])
+        .ok_or(ServerMessageError::new(BadPlayer, offset, "token cut short or not UTF-8"))?;
+    let offset = compressed.len() - rest.len();
+    let stats = rest.get(..16).and_then(PlayerStats::from_bytes)
+        .ok_or(ServerMessageError::new(BadPlayer, offset, "expected 16 bytes of stats"))?;
+    let epoch = rest.get(16..).and_then(|epoch| epoch.try_into().ok())
+        .ok_or(ServerMessageError::new(BadPlayer, offset + 16, "expected 8 bytes of epoch"))?;
+    Ok(ServerMessage::Welcome { player, token, stats, epoch: u64::from_be_bytes(epoch) })
+}
+
+fn read_cursors(compressed: &[u8]) -> Result<Vec<Player>, ServerMessageError> {
+    let Ok((MessageLength(count), length_slice)) = MessageLength::read_from_bytes(&compressed[1..]) else {
+        return Err(ServerMessageError::new(BadCursors, 1, "expected the number of cursors"));
+    };
+    let mut rest = &compressed[1 + length_slice.len()..];
+    // Every cursor takes at least 9 bytes, which stops a huge count from allocating lots
+    let mut players = Vec::with_capacity(count.min(rest.len() / 9));
+    for _ in 0..count {
+        let offset = compressed.len() - rest.len();
+        let position = Position::from_compressed(rest)
+            .ok_or(ServerMessageError::new(BadCursors, offset, "expected 8 bytes of position"))?;
+        let (player_id, after) = read_string(&rest[8..])
+            .ok_or(ServerMessageError::new(BadCursors, offset + 8, "player ID cut short or not UTF-8"))?;
+        players.push(Player { position, ..Player::new(player_id) });
+        rest = after;
+    }
+    Ok(players)
+}
+
 impl Arbitrary for ServerMessage {
     fn arbitrary(g: &mut Gen) -> Self {
-        match u32::arbitrary(g)%5 {
+        match u32::arbitrary(g)%18 {
             1 => Self::Disconnected(String::arbitrary(g)),
             2 => Self::Rect(UpdatedRect::arbitrary(g)),
             3 => Self::Connected,
+            4 => Self::Restarting(u16::arbitrary(g)),
+            5 => Self::Throttled(u16::arbitrary(g)),
+            6 => Self::Error(String::arbitrary(g)),
+            7 => Self::Stale,
+            8 => Self::ResumeKey(String::arbitrary(g)),
+            9 => Self::Hello { version: u16::arbitrary(g), features: Vec::arbitrary(g) },
+            10 => Self::Cursors(Vec::<(String, Position)>::arbitrary(g).into_iter()
+                .map(|(player_id, position)| Player { position, ..Player::new(player_id) })
+                .collect()),
+            11 => Self::Welcome {
+                player: Player::arbitrary(g),
+                token: String::arbitrary(g),
+                stats: PlayerStats {
+                    clicks: u32::arbitrary(g),
+                    flags: u32::arbitrary(g),
+                    tiles_revealed: u32::arbitrary(g),
+                    mines_revealed: u32::arbitrary(g),
+                },
+                epoch: u64::arbitrary(g),
+            },
+            12 => Self::Player(Player::arbitrary(g)),
+            13 => Self::Chat(ChatMessage::arbitrary(g)),
+            14 => Self::Ping { player_id: String::arbitrary(g), position: Position::arbitrary(g) },
+            15 => Self::Marker(Marker::arbitrary(g)),
+            16 => Self::MarkerRemoved(u32::arbitrary(g)),
             _ => Self::Chunk(Chunk::arbitrary(g))
         }
     }
 }
 
+impl Arbitrary for Player {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Self {
+            player_id: String::arbitrary(g),
+            position: Position::arbitrary(g),
+            name: String::arbitrary(g),
+            colour: u8::arbitrary(g),
+        }
+    }
+}
+
 impl Chunk {
     pub fn compress(&self) -> Vec<u8> {
         let mut result = vec![];
         result.append(&mut "h".as_bytes().to_vec());
         result.append(&mut self.position.to_bytes());
+        result.extend_from_slice(&self.version.to_be_bytes());
         result.append(&mut PublicTile::compress_tiles(&self.public_tiles()));
         result
     }
@@ -150,10 +390,19 @@ impl Chunk {
     ///     assert_eq!(decompressed_tile.clone(), tile);
     /// }
     /// ```
-    pub fn from_compressed(compressed: &[u8]) -> Option<Self> {
-        let position = ChunkPosition::from_bytes(compressed[1..8].to_vec())?;
-        let tiles = PublicTile::from_compressed_bytes(compressed[8..].to_vec());
-        Some(Chunk::from_position_and_tiles(position, ChunkTiles::from(*bytemuck::cast_slice(&tiles).first_chunk::<256>()?)))
+    pub fn from_compressed(compressed: &[u8]) -> Result<Self, ServerMessageError> {
+        let position = compressed.get(1..8)
+            .and_then(|bytes| ChunkPosition::from_bytes(bytes.to_vec()))
+            .ok_or(ServerMessageError::new(BadChunk, 1, "expected 7 bytes of chunk position"))?;
+        let version = compressed.get(8..).and_then(|bytes| bytes.first_chunk())
+            .ok_or(ServerMessageError::new(BadChunk, 8, "expected 4 bytes of version"))?;
+        let tiles = PublicTile::from_compressed_bytes(compressed[12..].to_vec());
+        let Ok(&tiles) = bytemuck::cast_slice(&tiles).try_into() else {
+            return Err(ServerMessageError::new(BadChunk, 12, "chunks need exactly 256 tiles"));
+        };
+        let mut chunk = Chunk::from_position_and_tiles(position, ChunkTiles::from(tiles));
+        chunk.version = u32::from_be_bytes(*version);
+        Ok(chunk)
     }
 }
 
@@ -213,8 +462,9 @@ impl ChunkPosition {
 }
 
 impl UpdatedRect {
-    pub fn from_compressed(compressed: &[u8]) -> Option<Self> {
-        let top_left = Position::from_compressed(compressed)?;
+    pub fn from_compressed(compressed: &[u8]) -> Result<Self, ServerMessageError> {
+        let top_left = Position::from_compressed(compressed)
+            .ok_or(ServerMessageError::new(BadRect, 0, "expected 8 bytes of position"))?;
         let mut updated = UpdatedRect::empty_at(top_left);
 
         let index = 8;
@@ -223,17 +473,21 @@ impl UpdatedRect {
         for tile in tiles {
             match tile {
                 PublicTile::Newline => updated.push_newline(),
-                tile => updated.push(tile.into()),
+                // The tiles in the rect that didn't change
+                PublicTile::Hidden => updated.push(None),
+                tile => updated.push(Some(tile.into())),
             }
         }
         
-        Some(updated)
+        Ok(updated)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{Chunk, ServerMessage, UpdatedRect};
+    use crate::player::{Player, PlayerStats};
+    use crate::{ChatChannel, ChatMessage, Chunk, Marker, Position, ServerMessage, UpdatedRect};
+    use crate::ServerMessageErrorKind::{BadChat, BadCursors, BadMarker, BadPlayer};
     use quickcheck_macros::quickcheck;
     
     #[quickcheck]
@@ -266,6 +520,80 @@ mod tests {
         }
     }
 
+    #[quickcheck]
+    fn restarting_compression(seconds: u16) {
+        let compressed: Vec<u8> = (&ServerMessage::Restarting(seconds)).into();
+        assert_eq!(ServerMessage::from_compressed(&compressed).unwrap(), ServerMessage::Restarting(seconds));
+    }
+
+    #[quickcheck]
+    fn throttled_compression(ms: u16) {
+        let compressed: Vec<u8> = (&ServerMessage::Throttled(ms)).into();
+        assert_eq!(ServerMessage::from_compressed(&compressed).unwrap(), ServerMessage::Throttled(ms));
+    }
+
+    #[test]
+    fn cursors_compression() {
+        let cursors = ServerMessage::Cursors(vec![
+            Player { position: Position(-5, 7), ..Player::new("abc".to_string()) },
+            Player { position: Position(i32::MAX, i32::MIN), ..Player::new("".to_string()) },
+        ]);
+        let compressed: Vec<u8> = (&cursors).into();
+        assert_eq!(ServerMessage::from_compressed(&compressed).unwrap(), cursors);
+        // The second cursor starts 14 bytes in, and its position is cut short
+        let error = ServerMessage::from_compressed(&compressed[..20]).unwrap_err();
+        assert_eq!((error.kind, error.offset), (BadCursors, 14));
+    }
+
+    #[test]
+    fn welcome_compression() {
+        let stats = PlayerStats { clicks: 3, flags: 1, tiles_revealed: 40, mines_revealed: 0 };
+        let welcome = ServerMessage::Welcome {
+            player: Player { position: Position(-5, 7), name: "Bob".to_string(), colour: 200, ..Player::new("abc".to_string()) },
+            token: "secret".to_string(),
+            stats,
+            epoch: 12345,
+        };
+        let compressed: Vec<u8> = (&welcome).into();
+        assert_eq!(ServerMessage::from_compressed(&compressed).unwrap(), welcome);
+        // The stats start after the header, position, the ID and name, the colour, and the token
+        let error = ServerMessage::from_compressed(&compressed[..compressed.len() - 9]).unwrap_err();
+        assert_eq!((error.kind, error.offset), (BadPlayer, 25));
+        let error = ServerMessage::from_compressed(&compressed[..compressed.len() - 1]).unwrap_err();
+        assert_eq!((error.kind, error.offset), (BadPlayer, 41));
+    }
+
+    #[test]
+    fn chat_compression() {
+        let chat = ServerMessage::Chat(ChatMessage {
+            channel: ChatChannel::Nearby,
+            player_id: "abc".to_string(),
+            name: "Bob".to_string(),
+            position: Position(-5, 7),
+            time: 1_700_000_000_000,
+            text: "Over here!".to_string(),
+        });
+        let compressed: Vec<u8> = (&chat).into();
+        assert_eq!(ServerMessage::from_compressed(&compressed).unwrap(), chat);
+        // The text starts after the header, channel, position, time, ID and name
+        let error = ServerMessage::from_compressed(&compressed[..compressed.len() - 1]).unwrap_err();
+        assert_eq!((error.kind, error.offset), (BadChat, 26));
+    }
+
+    #[test]
+    fn marker_compression() {
+        let marker = ServerMessage::Marker(Marker {
+            id: 7,
+            player_id: "abc".to_string(),
+            position: Position(-5, 7),
+            name: "Base camp".to_string(),
+        });
+        let compressed: Vec<u8> = (&marker).into();
+        assert_eq!(ServerMessage::from_compressed(&compressed).unwrap(), marker);
+        // The name starts after the header, ID, position and player ID
+        let error = ServerMessage::from_compressed(&compressed[..compressed.len() - 1]).unwrap_err();
+        assert_eq!((error.kind, error.offset), (BadMarker, 17));
+    }
 
     #[quickcheck]
     fn compression_then_decompression(message: ServerMessage) {
@@ -1,5 +1,5 @@
 use crate::PublicTile;
-use crate::{Position, Tile};
+use crate::{Position, Rect, Tile};
 use quickcheck::{Arbitrary, Gen};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
@@ -15,7 +15,9 @@ pub struct UpdatedTile {
 #[derive(Serialize, Deserialize)]
 pub struct UpdatedRect {
     pub top_left: Position,
-    updated: Vec<Vec<Tile>>,
+    /// `None` for the tiles in the rect that didn't change, so that a tile going back to
+    /// [Tile::empty], like when it's unflagged, still counts
+    updated: Vec<Vec<Option<Tile>>>,
 }
 
 impl Debug for UpdatedRect {
@@ -24,8 +26,10 @@ impl Debug for UpdatedRect {
         for y in 0..self.height() {
             for x in 0..self.width() {
                 if let Some(col) = self.updated.get(x) {
-                    if let Some(tile) = col.get(y) {
-                        write!(f, "{}", tile)?;
+                    match col.get(y) {
+                        Some(Some(tile)) => write!(f, "{}", tile)?,
+                        Some(None) => write!(f, " ")?,
+                        None => {}
                     }
                 }
             }
@@ -76,7 +80,7 @@ impl UpdatedRect {
         for i in 0..n_cols {
             updated.push(vec![]);
             for _j in 0..n_rows {
-                updated[i as usize].push(Tile::empty())
+                updated[i as usize].push(None)
             }
         }
 
@@ -88,7 +92,7 @@ impl UpdatedRect {
             if x > 1000 || y > 1000 || x < 0 || y < 0 {
                 return Self::empty()
             }
-            updated[x as usize][y as usize] = updated_tile.tile;
+            updated[x as usize][y as usize] = Some(updated_tile.tile);
         }
 
         Self {
@@ -97,7 +101,7 @@ impl UpdatedRect {
         }
     }
 
-    pub fn push(&mut self, tile: Tile) {
+    pub fn push(&mut self, tile: Option<Tile>) {
         if let Some(last) = self.updated.last_mut() {
             last.push(tile);
         } else {
@@ -113,7 +117,7 @@ impl UpdatedRect {
         let mut result = vec![];
         for row in &self.updated {
             for tile in row {
-                result.push(tile.into())
+                result.push(tile.map_or(PublicTile::Hidden, |tile| tile.into()))
             }
             result.push(PublicTile::Newline)
         }
@@ -124,13 +128,13 @@ impl UpdatedRect {
         let mut result = vec![];
         for (x, col) in self.updated.iter().enumerate() {
             for (y, tile) in col.iter().enumerate() {
-                if *tile == Tile::empty() {
+                let Some(tile) = *tile else {
                     continue
-                }
+                };
                 let position = self.top_left + Position(x as i32, y as i32);
                 result.push(UpdatedTile {
                     position,
-                    tile: *tile
+                    tile
                 });
             }
         }
@@ -148,6 +152,10 @@ impl UpdatedRect {
     pub fn width(&self) -> usize {
         self.updated.len()
     }
+
+    pub fn rect(&self) -> Rect {
+        Rect::from_top_left_and_size(self.top_left, self.width() as i32, self.height() as i32)
+    }
 }
 
 impl From<&UpdatedRect> for Vec<u8> {
@@ -163,10 +171,17 @@ impl From<&UpdatedRect> for Vec<u8> {
 
 impl Arbitrary for UpdatedRect {
     fn arbitrary(g: &mut Gen) -> Self {
+        // Hidden tiles are sent the same as ones that didn't change, so there aren't any here
+        let changed_tile = |g: &mut Gen| loop {
+            let tile = PublicTile::arbitrary(g);
+            if tile != PublicTile::Hidden {
+                return tile;
+            }
+        };
         let mut queue = VecDeque::from([Position::arbitrary(g)]);
         let mut updated = HashMap::<Position, PublicTile>::new();
         while let Some(position) = queue.pop_front() {
-            if updated.insert(position, PublicTile::arbitrary(g)).is_none() {
+            if updated.insert(position, changed_tile(g)).is_none() {
                 for neighbor in position.neighbors() {
                     if !updated.contains_key(&neighbor) && bool::arbitrary(g) {
                         queue.push_back(neighbor);
@@ -191,7 +206,7 @@ impl Arbitrary for UpdatedRect {
         let new_width = self.width() - 1;
         shrunk.updated.resize(new_width, vec![]);
         for col in &mut shrunk.updated {
-            col.resize(new_height, Tile::empty())
+            col.resize(new_height, None)
         }
         Box::from([shrunk].into_iter())
     }
@@ -0,0 +1,40 @@
+//! What the fuzz targets check, kept out of the targets so that the corpus can be replayed with
+//! `cargo test` as well as fuzzed with `cargo fuzz`.
+
+use world::{ClientMessage, Event, ServerMessageBundle};
+
+/// Bundles from the server either decode or say where they went wrong, rather than panicking
+pub fn server_bundle(data: &[u8]) {
+    if let Err(error) = ServerMessageBundle::from_compressed_with_number(data) {
+        assert!(error.offset <= data.len(), "{error} is past the end of {} bytes", data.len());
+    }
+}
+
+/// Events from the event log either decode or say where they went wrong, rather than panicking
+pub fn event(data: &[u8]) {
+    if let Err(error) = Event::from_compressed(data) {
+        assert!(error.offset <= data.len(), "{error} is past the end of {} bytes", data.len());
+    }
+}
+
+/// Binary messages from clients either decode or say where they went wrong, and anything that
+/// decodes comes out the same when it's sent again
+pub fn client_message(data: &[u8]) {
+    match ClientMessage::from_compressed(data) {
+        Ok(message) => {
+            let compressed: Vec<u8> = (&message).into();
+            assert_eq!(ClientMessage::from_compressed(&compressed), Ok(message));
+        }
+        Err(error) => assert!(error.offset <= data.len(), "{error} is past the end of {} bytes", data.len()),
+    }
+}
+
+/// Checks one input, panicking if something's wrong
+pub type Target = fn(&[u8]);
+
+/// The fuzz targets, by name
+pub const TARGETS: [(&str, Target); 3] = [
+    ("server_bundle", server_bundle),
+    ("event", event),
+    ("client_message", client_message),
+];
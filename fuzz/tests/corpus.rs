@@ -0,0 +1,26 @@
+//! Replays the seeds and everything `cargo fuzz` has saved for each target, so that inputs that
+//! once found a bug keep being checked without libFuzzer. The seeds are a minimised corpus from
+//! fuzzing each target, and inputs that once crashed one.
+
+use std::path::Path;
+use world_fuzz::TARGETS;
+
+#[test]
+fn replay_corpus() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    for (name, target) in TARGETS {
+        let mut replayed = 0;
+        for dir in ["seeds", "corpus", "artifacts"] {
+            let Ok(entries) = std::fs::read_dir(root.join(dir).join(name)) else { continue };
+            for entry in entries {
+                let path = entry.unwrap().path();
+                let data = std::fs::read(&path).unwrap();
+                if std::panic::catch_unwind(|| target(&data)).is_err() {
+                    panic!("{name} failed on {}", path.display());
+                }
+                replayed += 1;
+            }
+        }
+        println!("{name}: replayed {replayed} inputs");
+    }
+}